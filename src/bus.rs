@@ -1,13 +1,17 @@
-use crate::mappers::{Map, MappedRead};
+use crate::mappers::{Map, MappedRead, MappedWrite};
 use crate::{mem::Mem, ppu::PPU};
 use crate::rom::ROM;
+use crate::cpu::ResetKind;
 use crate::gamepad::Gamepad;
+use crate::gamepad::gamepad_register::JoypadButton;
+use crate::power_on::{PowerOnState, Region};
+use crate::savestate::{StateReader, StateWriter};
 use log::debug;
 
 const RAM_START: u16 =                0x0000;
 const RAM_MIRROR_END: u16 =           0x1FFF;
 const PPU_REGISTER_MIRROR_END: u16 =  0x3FFF;
-const ROM_SPACE_START: u16 =          0x8000;
+const CARTRIDGE_SPACE_START: u16 =    0x4020;
 const ROM_SPACE_END: u16 =            0xFFFF;
 
 /// Write-only registers
@@ -29,8 +33,24 @@ const PPU_DATA_REGISTER: u16 =        0x2007;
 /// Direct-Memory Access, for quickly writing 256 bytes
 /// from RAM to OAM
 const PPU_DMA_ADDRESS: u16 =          0x4014;
+/// CPU cycles an OAM DMA halts the CPU for, one more when it starts on an odd cycle
+const OAM_DMA_CYCLES: usize =         513;
 
 const GAMEPAD_ADDRESS: u16 =          0x4016;
+const GAMEPAD_2_ADDRESS: u16 =        0x4017;
+
+/// Reading the APU status doesn't drive the data bus, so bit 5 is open bus
+const APU_STATUS_REGISTER: u16 =      0x4015;
+
+/// Controller reads only drive the low bits, the rest are open bus
+const GAMEPAD_OPEN_BUS_MASK: u8 =     0xE0;
+
+/// NTSC CPU clock, in Hz
+const CPU_CLOCK_RATE: usize =         1_789_773;
+/// Rate the cartridge's sound is handed to the frontend at, in Hz
+pub const AUDIO_SAMPLE_RATE: usize =  44_100;
+/// Samples kept while nobody takes them, a second's worth
+const MAX_AUDIO_SAMPLES: usize =      AUDIO_SAMPLE_RATE;
 
 #[allow(clippy::type_complexity)]
 pub struct Bus<'call> {
@@ -39,7 +59,27 @@ pub struct Bus<'call> {
   prg_ram: Vec<u8>,
   pub ppu: PPU,
   gamepad: Gamepad,
+  /// The last value on the CPU data bus, which reads of unmapped addresses return
+  open_bus: u8,
+  power_on_state: PowerOnState,
   cycles: usize,
+  /// Cycles of the current instruction the PPU hasn't been run for yet
+  pending_cycles: u8,
+  /// An NMI that arrived on the last cycle of an instruction, which the CPU only sees one instruction later
+  nmi_delayed: bool,
+  /// A $4014 write halts the CPU once the instruction that made it ends
+  oam_dma_pending: bool,
+  frames: usize,
+  /// Whether a battery keeps PRG-RAM and the mapper's RAM between runs
+  battery: bool,
+  /// Sound since the last `take_audio_samples`
+  audio_samples: Vec<f32>,
+  /// Sum and count of the per-cycle levels making up the next sample
+  audio_sum: f32,
+  audio_cycles: usize,
+  /// CPU cycles into the next sample, scaled by the sample rate
+  audio_clock: usize,
+  callback_enabled: bool,
   callback: Box<dyn FnMut(&mut PPU, &mut Gamepad) + 'call>,
 }
 
@@ -49,6 +89,7 @@ impl Bus<'_> {
   where 
       F: FnMut(&mut PPU, &mut Gamepad) + 'call {
     
+    let battery = rom.header.has_battery_backed_ram;
     let mut ppu = PPU::new();
     ppu.load_mapper(rom.mapper);
     ppu.load_chr_ram(rom.chr_ram);
@@ -61,7 +102,19 @@ impl Bus<'_> {
       prg_ram: rom.prg_ram,
       ppu,
       gamepad: Gamepad::new(),
+      open_bus: 0,
+      power_on_state: PowerOnState::Zero,
       cycles: 0,
+      pending_cycles: 0,
+      nmi_delayed: false,
+      oam_dma_pending: false,
+      frames: 0,
+      battery,
+      audio_samples: Vec::new(),
+      audio_sum: 0.0,
+      audio_cycles: 0,
+      audio_clock: 0,
+      callback_enabled: true,
       callback: Box::from(callback)
     }
   }
@@ -73,62 +126,268 @@ impl Bus<'_> {
   pub fn tick_cycles(&mut self, cycles: u8) {
 
     self.cycles += cycles as usize;
+    for _ in 0..cycles {
+      self.ppu.mapper.cpu_cycle();
+      self.sample_audio();
+    }
 
     let frame = self.ppu.tick(cycles * 3);
     if frame {
-      (self.callback)(&mut self.ppu, &mut self.gamepad);
+      self.frames += 1;
+      if self.callback_enabled {
+        (self.callback)(&mut self.ppu, &mut self.gamepad);
+      }
+    }
+
+  }
+
+  /// Averages the cartridge's sound over each sample period, which keeps channels
+  /// faster than the sample rate from aliasing
+  fn sample_audio(&mut self) {
+    self.audio_sum += self.ppu.mapper.audio_sample();
+    self.audio_cycles += 1;
+    self.audio_clock += AUDIO_SAMPLE_RATE;
+
+    if self.audio_clock >= CPU_CLOCK_RATE {
+      self.audio_clock -= CPU_CLOCK_RATE;
+      if self.audio_samples.len() < MAX_AUDIO_SAMPLES {
+        self.audio_samples.push(self.audio_sum / self.audio_cycles as f32);
+      }
+      self.audio_sum = 0.0;
+      self.audio_cycles = 0;
+    }
+  }
+
+  /// Sound made since the last call, mono at `AUDIO_SAMPLE_RATE` between 0 and 1.
+  /// There's no APU yet, so this is only the cartridge's expansion sound.
+  pub fn take_audio_samples(&mut self) -> Vec<f32> {
+    std::mem::take(&mut self.audio_samples)
+  }
+
+  /// Whether the cart's RAM should be kept between runs with `battery_ram`
+  pub fn has_battery(&self) -> bool {
+    self.battery
+  }
+
+  /// The cart's battery-backed memory: PRG-RAM, then whatever the mapper keeps itself
+  pub fn battery_ram(&self) -> Vec<u8> {
+    [self.prg_ram.as_slice(), self.ppu.mapper.battery_ram()].concat()
+  }
+
+  /// Restores memory from `battery_ram`. Call it after `power_on`, which would overwrite it.
+  pub fn load_battery_ram(&mut self, data: &[u8]) -> Result<(), String> {
+    let mapper_len = self.ppu.mapper.battery_ram().len();
+    if data.len() != self.prg_ram.len() + mapper_len {
+      return Err(format!("Battery save is {} bytes, expected {}", data.len(), self.prg_ram.len() + mapper_len));
+    }
+    let (prg_ram, mapper_ram) = data.split_at(self.prg_ram.len());
+    self.prg_ram.copy_from_slice(prg_ram);
+    self.ppu.mapper.load_battery_ram(mapper_ram);
+    Ok(())
+  }
+
+  /// Fills CPU RAM, PRG-RAM and the PPU's memories the way `state` says a console powers up.
+  /// `Bus::new` leaves everything zeroed, call this before the CPU starts running.
+  pub fn power_on(&mut self, state: PowerOnState) {
+    self.power_on_state = state;
+    state.fill(Region::CpuRam, &mut self.cpu_vram);
+    state.fill(Region::PrgRam, &mut self.prg_ram);
+    self.ppu.power_on(state);
+  }
+
+  /// Resets everything on the bus. A power cycle also refills RAM using the last `power_on` state.
+  /// There's no APU yet, so there is no sound state to reset.
+  pub fn reset(&mut self, kind: ResetKind) {
+    if kind == ResetKind::Hard {
+      self.power_on(self.power_on_state);
+      self.open_bus = 0;
+    }
+    self.ppu.reset(kind);
+    self.ppu.mapper.reset(kind);
+    self.gamepad.reset();
+  }
+
+  /// Starts an instruction that takes `cycles`. The PPU is run for them when the instruction
+  /// ends, or earlier when the instruction touches a PPU register, so the access lands on the right dot.
+  pub fn begin_instruction(&mut self, cycles: u8) {
+    self.pending_cycles = cycles;
+  }
+
+  /// Adds cycles to the current instruction, for page crossings and taken branches.
+  /// Add them before the access they delay so that it still lands on the right dot.
+  pub fn add_cycles(&mut self, cycles: u8) {
+    self.pending_cycles += cycles;
+  }
+
+  /// Runs the PPU up to the access an instruction is about to make. Reads and writes
+  /// happen on an instruction's last cycle (read-modify-write instructions write on
+  /// their last two), so everything before that one is run first.
+  fn catch_up(&mut self) {
+    if self.pending_cycles > 1 {
+      let cycles = self.pending_cycles - 1;
+      self.pending_cycles = 1;
+      self.tick_cycles(cycles);
     }
+  }
 
+  /// Runs the PPU for whatever is left of the instruction
+  pub fn end_instruction(&mut self) {
+    if self.pending_cycles == 0 {
+      return;
+    }
+    self.catch_up();
+
+    // The CPU polls for interrupts before an instruction's last cycle,
+    // so an NMI that starts on that cycle waits for the next instruction
+    let nmi_before = self.ppu.nmi_pending();
+    self.pending_cycles = 0;
+    self.tick_cycles(1);
+    if !nmi_before && self.ppu.nmi_pending() {
+      self.nmi_delayed = true;
+    }
+
+    if self.oam_dma_pending {
+      self.oam_dma_pending = false;
+      // An extra cycle lines the copy up with the CPU's read cycles when it starts on an odd one
+      let stall = OAM_DMA_CYCLES + self.cycles % 2;
+      for _ in 0..stall {
+        self.tick();
+      }
+    }
   }
 
   pub fn get_cycles(&self) -> usize {
     self.cycles
   }
 
+  /// Number of frames the PPU has completed since power on
+  pub fn frame_count(&self) -> usize {
+    self.frames
+  }
+
+  /// Frames still run while the callback is disabled, they just aren't handed
+  /// to the frontend. Used when replaying frames that should not be shown.
+  pub fn set_callback_enabled(&mut self, enabled: bool) {
+    self.callback_enabled = enabled;
+  }
+
+  pub fn gamepad(&mut self) -> &mut Gamepad {
+    &mut self.gamepad
+  }
+
+  pub fn gamepad_state(&self) -> JoypadButton {
+    self.gamepad.button_status()
+  }
+
   pub fn poll_nmi(&mut self) -> Option<u8> {
+    if self.nmi_delayed {
+      self.nmi_delayed = false;
+      return None;
+    }
     self.ppu.poll_nmi()
   }
 
+  /// Whether the cartridge is asserting IRQ
+  pub fn irq_pending(&self) -> bool {
+    self.ppu.mapper.irq_pending()
+  }
+
+  pub fn save_state(&self, state: &mut StateWriter) {
+    state.write_bytes(&self.cpu_vram);
+    state.write_vec(&self.prg_ram);
+    state.write_u8(self.open_bus);
+    state.write_usize(self.cycles);
+    state.write_bool(self.nmi_delayed);
+    state.write_usize(self.frames);
+    self.gamepad.save_state(state);
+    self.ppu.save_state(state);
+  }
+
+  pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    state.read_bytes(&mut self.cpu_vram)?;
+    state.read_vec(&mut self.prg_ram)?;
+    self.open_bus = state.read_u8()?;
+    self.cycles = state.read_usize()?;
+    self.nmi_delayed = state.read_bool()?;
+    self.frames = state.read_usize()?;
+    self.gamepad.load_state(state)?;
+    self.ppu.load_state(state)
+  }
+
 }
 
 impl Mem for Bus<'_> {
 
   fn mem_read_u8(&mut self, addr: u16) -> u8 {
-    match addr {
+    if (PPU_CONTROL_BYTE..=PPU_REGISTER_MIRROR_END).contains(&addr) {
+      self.catch_up();
+    }
+    let data = match addr {
       RAM_START..=RAM_MIRROR_END => {
         let mirrored_addr = addr & 0x7FF;
         self.cpu_vram[mirrored_addr as usize]
       },
       PPU_CONTROL_BYTE | PPU_MASK_REGISTER | PPU_OAM_ADDRESS_REGISTER | PPU_SCROLL_BYTE | PPU_ADDRESS_REGISTER => {
-        // error!("Attempted to read from write-only PPU address 0x{:0X}", addr);
-        // panic!("Attempted to read from write-only PPU address 0x{:0X}", addr);
-        self.ppu.internal_data_buffer
+        self.ppu.read_io_latch()
       },
       PPU_STATUS_REGISTER => self.ppu.read_status(),
       PPU_OAM_DATA_REGISTER => self.ppu.read_oam_data(),
       PPU_DATA_REGISTER => self.ppu.read_data(),
-      0x4000..=0x4015 => 0,
+      // There is no APU yet, so its status reads as all clear
+      APU_STATUS_REGISTER => return self.open_bus & 0x20,
       0x2008..=PPU_REGISTER_MIRROR_END => {
         let mirrored_addr = addr & 0x2007;
         self.mem_read_u8(mirrored_addr)
       },
-      0x4020..=ROM_SPACE_END => {
+      CARTRIDGE_SPACE_START..=ROM_SPACE_END => {
         match self.ppu.mapper.map_read(addr) {
           MappedRead::Data(data) => data,
-          MappedRead::PrgRAM(addr) => self.prg_ram[addr],
+          MappedRead::PrgRAM(addr) => self.prg_ram.get(addr).copied().unwrap_or(self.open_bus),
           MappedRead::PrgROM(addr) => self.prg_rom[addr],
-          _ => self.ppu.internal_data_buffer,
+          _ => self.open_bus,
         }
       },
-      GAMEPAD_ADDRESS => self.gamepad.read(),
+      GAMEPAD_ADDRESS => self.open_bus & GAMEPAD_OPEN_BUS_MASK | self.gamepad.read(),
+      GAMEPAD_2_ADDRESS => self.open_bus & GAMEPAD_OPEN_BUS_MASK,
       _ => {
-        debug!("Ignoring memory read at 0x{:0X}", addr);
-        0
+        debug!("Open bus read at 0x{:0X}", addr);
+        self.open_bus
       }
+    };
+    self.open_bus = data;
+    data
+  }
+
+  fn mem_peek_u8(&self, addr: u16) -> u8 {
+    match addr {
+      RAM_START..=RAM_MIRROR_END => self.cpu_vram[(addr & 0x7FF) as usize],
+      PPU_CONTROL_BYTE | PPU_MASK_REGISTER | PPU_OAM_ADDRESS_REGISTER | PPU_SCROLL_BYTE | PPU_ADDRESS_REGISTER => {
+        self.ppu.read_io_latch()
+      },
+      PPU_STATUS_REGISTER => self.ppu.peek_status(),
+      PPU_OAM_DATA_REGISTER => self.ppu.peek_oam_data(),
+      PPU_DATA_REGISTER => self.ppu.peek_data(),
+      APU_STATUS_REGISTER => self.open_bus & 0x20,
+      0x2008..=PPU_REGISTER_MIRROR_END => self.mem_peek_u8(addr & 0x2007),
+      CARTRIDGE_SPACE_START..=ROM_SPACE_END => {
+        match self.ppu.mapper.map_peak(addr) {
+          MappedRead::Data(data) => data,
+          MappedRead::PrgRAM(addr) => self.prg_ram.get(addr).copied().unwrap_or(self.open_bus),
+          MappedRead::PrgROM(addr) => self.prg_rom[addr],
+          _ => self.open_bus,
+        }
+      },
+      GAMEPAD_ADDRESS => self.open_bus & GAMEPAD_OPEN_BUS_MASK | self.gamepad.peek(),
+      GAMEPAD_2_ADDRESS => self.open_bus & GAMEPAD_OPEN_BUS_MASK,
+      _ => self.open_bus
     }
   }
 
   fn mem_write_u8(&mut self, addr: u16, data: u8) {
+    if (PPU_CONTROL_BYTE..=PPU_REGISTER_MIRROR_END).contains(&addr) {
+      self.catch_up();
+    }
+    self.open_bus = data;
     match addr {
       RAM_START..=RAM_MIRROR_END => {
         let mirrored_addr = addr & 0x7FF;
@@ -140,16 +399,18 @@ impl Mem for Bus<'_> {
       PPU_ADDRESS_REGISTER => self.ppu.write_to_ppu_address(data),
       PPU_DATA_REGISTER => self.ppu.write_to_data_register(data),
       PPU_MASK_REGISTER => self.ppu.write_to_mask_register(data),
-      PPU_STATUS_REGISTER => self.ppu.internal_data_buffer = data,
+      PPU_SCROLL_BYTE => self.ppu.write_to_scroll_register(data),
+      PPU_STATUS_REGISTER => self.ppu.write_to_status_register(data),
       0x2008..=PPU_REGISTER_MIRROR_END => {
         let mirrored_addr = addr & 0x2007;
         self.mem_write_u8(mirrored_addr, data);
       },
-      ROM_SPACE_START..=ROM_SPACE_END => {
-        // let msg = "Attempted to write to ROM address space!";
-        // error!("{msg}");
-        // panic!("{msg}");
-        // self.prg_rom[addr as usize -0x8000] = data;
+      CARTRIDGE_SPACE_START..=ROM_SPACE_END => {
+        if let MappedWrite::PrgRAM(addr, data) = self.ppu.mapper.map_write(addr, data) {
+          if let Some(byte) = self.prg_ram.get_mut(addr) {
+            *byte = data;
+          }
+        }
       },
       GAMEPAD_ADDRESS => self.gamepad.write(data),
       PPU_DMA_ADDRESS => {
@@ -162,6 +423,7 @@ impl Mem for Bus<'_> {
         }
 
         self.ppu.write_oam_dma(&buffer);
+        self.oam_dma_pending = true;
 
       }
       _ => {
@@ -169,4 +431,138 @@ impl Mem for Bus<'_> {
       }
     }
   }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::mappers::tests::banked_rom;
+  use crate::rom::tests::test_rom;
+
+  #[test]
+  fn test_unmapped_reads_return_open_bus() {
+    let mut bus = Bus::new(test_rom(), |_, _| {});
+
+    bus.mem_write_u8(0x0010, 0x5A);
+    assert_eq!(bus.mem_read_u8(0x0010), 0x5A);
+    assert_eq!(bus.mem_peek_u8(0x4018), 0x5A);
+    assert_eq!(bus.mem_read_u8(0x401F), 0x5A);
+    // No PRG-RAM or registers there on NROM
+    assert_eq!(bus.mem_read_u8(0x5000), 0x5A);
+
+    bus.mem_write_u8(0x0010, 0x41);
+    bus.mem_read_u8(0x0010);
+    assert_eq!(bus.mem_read_u8(GAMEPAD_2_ADDRESS), 0x40);
+    assert_eq!(bus.mem_read_u8(APU_STATUS_REGISTER), 0x00);
+  }
+
+  #[test]
+  fn test_ppu_registers_use_io_latch() {
+    let mut bus = Bus::new(test_rom(), |_, _| {});
+
+    bus.mem_write_u8(PPU_SCROLL_BYTE, 0x3C);
+    bus.mem_write_u8(0x0010, 0x00);
+    assert_eq!(bus.mem_read_u8(PPU_CONTROL_BYTE), 0x3C);
+    assert_eq!(bus.mem_read_u8(0x2008 + PPU_MASK_REGISTER - 0x2000), 0x3C);
+    // Only the top three bits are status, the rest are what was last on the PPU's bus
+    assert_eq!(bus.mem_read_u8(PPU_STATUS_REGISTER), 0x1C);
+
+    bus.mem_write_u8(PPU_STATUS_REGISTER, 0xA5);
+    assert_eq!(bus.mem_read_u8(PPU_ADDRESS_REGISTER), 0xA5);
+  }
+
+  #[test]
+  fn test_nmi_on_last_cycle_is_delayed() {
+    let mut bus = Bus::new(test_rom(), |_, _| {});
+    bus.ppu.update_ctrl_register(0b1000_0000);
+
+    // Vblank starts during the final cycle of a 2 cycle instruction
+    bus.ppu.scanline = 240;
+    bus.ppu.cycles = 338;
+    bus.begin_instruction(2);
+    bus.end_instruction();
+    assert!(bus.poll_nmi().is_none());
+    assert!(bus.poll_nmi().is_some());
+
+    // Starting during the first cycle, it is in time for the CPU to see it
+    bus.ppu.scanline = 240;
+    bus.ppu.cycles = 339;
+    bus.begin_instruction(2);
+    bus.end_instruction();
+    assert!(bus.poll_nmi().is_some());
+  }
+
+  #[test]
+  fn test_audio_samples() {
+    let mut bus = Bus::new(banked_rom(5), |_, _| {});
+    bus.mem_write_u8(0x5011, 0xFF);
+
+    let cycles = CPU_CLOCK_RATE / 60;
+    for _ in 0..cycles {
+      bus.tick();
+    }
+    let samples = bus.take_audio_samples();
+    assert_eq!(samples.len(), cycles * AUDIO_SAMPLE_RATE / CPU_CLOCK_RATE);
+    assert!(samples.iter().all(|sample| *sample == 0.5));
+    assert!(bus.take_audio_samples().is_empty());
+
+    // Nobody taking them doesn't grow the buffer without bound
+    for _ in 0..CPU_CLOCK_RATE * 2 {
+      bus.tick();
+    }
+    assert_eq!(bus.take_audio_samples().len(), MAX_AUDIO_SAMPLES);
+  }
+
+  #[test]
+  fn test_battery_ram() {
+    let mut bus = Bus::new(banked_rom(19), |_, _| {});
+    assert!(!bus.has_battery());
+    // Unprotect PRG-RAM and point the sound RAM port at $40
+    bus.mem_write_u8(0xF800, 0x40);
+    bus.mem_write_u8(0x6000, 0x12);
+    bus.mem_write_u8(0x4800, 0x34);
+
+    let saved = bus.battery_ram();
+    assert_eq!(saved.len(), 0x2000 + 0x80);
+    assert_eq!((saved[0], saved[0x2040]), (0x12, 0x34));
+
+    let mut restored = Bus::new(banked_rom(19), |_, _| {});
+    restored.load_battery_ram(&saved).unwrap();
+    restored.mem_write_u8(0xF800, 0x40);
+    assert_eq!(restored.mem_read_u8(0x6000), 0x12);
+    assert_eq!(restored.mem_read_u8(0x4800), 0x34);
+
+    assert!(restored.load_battery_ram(&saved[1..]).is_err());
+  }
+
+  #[test]
+  fn test_oam_dma_stall() {
+    let mut bus = Bus::new(test_rom(), |_, _| {});
+
+    bus.begin_instruction(4);
+    bus.mem_write_u8(PPU_DMA_ADDRESS, 0x02);
+    bus.end_instruction();
+    assert_eq!(bus.get_cycles(), 4 + 513);
+
+    bus.begin_instruction(4);
+    bus.mem_write_u8(PPU_DMA_ADDRESS, 0x02);
+    bus.end_instruction();
+    assert_eq!(bus.get_cycles(), 4 + 513 + 4 + 514);
+  }
+
+  #[test]
+  fn test_power_on_state() {
+    let mut first = Bus::new(test_rom(), |_, _| {});
+    let mut second = Bus::new(test_rom(), |_, _| {});
+    first.power_on(PowerOnState::Random(99));
+    second.power_on(PowerOnState::Random(99));
+
+    assert_eq!(first.cpu_vram, second.cpu_vram);
+    assert!(first.cpu_vram.iter().any(|byte| *byte != 0));
+
+    first.power_on(PowerOnState::Ones);
+    assert_eq!(first.mem_read_u8(0x07FF), 0xFF);
+    assert!(first.prg_ram.iter().all(|byte| *byte == 0xFF));
+  }
+
+}
@@ -0,0 +1,139 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::ppu::frame::{Frame, PixelFormat};
+use crate::ppu::palette::Palette;
+
+/// NTSC runs at 39375000 / 655171 ≈ 60.0988 frames per second
+const FRAME_RATE: &str = "39375000:655171";
+
+/// NES pixels are slightly wider than they are tall on an NTSC TV
+const PIXEL_ASPECT: &str = "8:7";
+
+/// Writes RGB24 pixels to a PNG file, creating its directory if needed
+pub fn write_png<P: AsRef<Path>>(path: P, width: usize, height: usize, rgb: &[u8]) -> Result<(), String> {
+
+  let path = path.as_ref();
+  if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+    fs::create_dir_all(dir).map_err(|e| format!("Unable to create \"{}\": {}", dir.to_string_lossy(), e))?;
+  }
+
+  let file = File::create(path).map_err(|e| format!("Unable to create \"{}\": {}", path.to_string_lossy(), e))?;
+  encode_png(BufWriter::new(file), width, height, rgb)
+}
+
+fn encode_png<W: Write>(writer: W, width: usize, height: usize, rgb: &[u8]) -> Result<(), String> {
+
+  let mut encoder = png::Encoder::new(writer, width as u32, height as u32);
+  encoder.set_color(png::ColorType::Rgb);
+  encoder.set_depth(png::BitDepth::Eight);
+
+  encoder
+    .write_header()
+    .and_then(|mut png| png.write_image_data(rgb))
+    .map_err(|e| format!("Unable to write PNG: {}", e))
+}
+
+/// Saves `frame` as a PNG, colored with `palette`
+pub fn save_screenshot<P: AsRef<Path>>(path: P, frame: &Frame, palette: &Palette) -> Result<(), String> {
+  write_png(path, Frame::FRAME_WIDTH, Frame::FRAME_HEIGHT, &frame.to_bytes(palette, PixelFormat::RGB24))
+}
+
+/// Records frames as uncompressed YUV4MPEG2 video, which ffmpeg and most players read directly.
+/// There is no APU yet, so recordings have no audio.
+pub struct Y4mRecorder<W: Write> {
+  writer: W,
+  width: usize,
+  height: usize,
+  planes: Vec<u8>,
+}
+
+impl Y4mRecorder<BufWriter<File>> {
+
+  pub fn create<P: AsRef<Path>>(path: P, width: usize, height: usize) -> Result<Self, String> {
+    let path = path.as_ref();
+    let file = File::create(path).map_err(|e| format!("Unable to create \"{}\": {}", path.to_string_lossy(), e))?;
+    Y4mRecorder::new(BufWriter::new(file), width, height)
+  }
+
+}
+
+impl<W: Write> Y4mRecorder<W> {
+
+  pub fn new(mut writer: W, width: usize, height: usize) -> Result<Self, String> {
+    // 4:4:4 so the NES's sharp color edges survive
+    writeln!(writer, "YUV4MPEG2 W{} H{} F{} Ip A{} C444", width, height, FRAME_RATE, PIXEL_ASPECT)
+      .map_err(|e| format!("Unable to write video header: {}", e))?;
+
+    Ok(Y4mRecorder { writer, width, height, planes: vec![0; width * height * 3] })
+  }
+
+  /// Appends one frame of RGB24 pixels
+  pub fn write_frame(&mut self, rgb: &[u8]) -> Result<(), String> {
+
+    let size = self.width * self.height;
+    let (luma, chroma) = self.planes.split_at_mut(size);
+    let (cb, cr) = chroma.split_at_mut(size);
+
+    for (i, pixel) in rgb.chunks_exact(3).take(size).enumerate() {
+      let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+      // BT.601, limited range
+      luma[i] = (16.0 + 0.257 * r + 0.504 * g + 0.098 * b).round() as u8;
+      cb[i] = (128.0 - 0.148 * r - 0.291 * g + 0.439 * b).round() as u8;
+      cr[i] = (128.0 + 0.439 * r - 0.368 * g - 0.071 * b).round() as u8;
+    }
+
+    self.writer
+      .write_all(b"FRAME\n")
+      .and_then(|_| self.writer.write_all(&self.planes))
+      .map_err(|e| format!("Unable to write video frame: {}", e))
+  }
+
+  pub fn finish(mut self) -> Result<W, String> {
+    self.writer.flush().map_err(|e| format!("Unable to write video: {}", e))?;
+    Ok(self.writer)
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_png_round_trip() {
+
+    let rgb: Vec<u8> = (0..4 * 2 * 3).map(|i| i as u8 * 10).collect();
+    let mut bytes = vec![];
+    encode_png(&mut bytes, 4, 2, &rgb).unwrap();
+
+    let decoder = png::Decoder::new(bytes.as_slice());
+    let mut reader = decoder.read_info().unwrap();
+    let mut decoded = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut decoded).unwrap();
+
+    assert_eq!((info.width, info.height), (4, 2));
+    assert_eq!(&decoded[..info.buffer_size()], rgb.as_slice());
+
+  }
+
+  #[test]
+  fn test_y4m_frames() {
+
+    let mut recorder = Y4mRecorder::new(vec![], 2, 1).unwrap();
+    recorder.write_frame(&[0, 0, 0, 255, 255, 255]).unwrap();
+    recorder.write_frame(&[0, 0, 0, 0, 0, 0]).unwrap();
+    let bytes = recorder.finish().unwrap();
+
+    let header = b"YUV4MPEG2 W2 H1 F39375000:655171 Ip A8:7 C444\n";
+    assert_eq!(&bytes[..header.len()], header);
+
+    let frame = &bytes[header.len()..];
+    assert_eq!(frame.len(), 2 * (6 + 2 * 3));
+    assert_eq!(&frame[..12], b"FRAME\n\x10\xEB\x80\x80\x80\x80");
+
+  }
+
+}
@@ -1,14 +1,131 @@
+use std::str::FromStr;
+
 use log::error;
 
 use crate::cpu::{CPU, Mem, AddressingMode};
+use crate::cpu::cpu_status_flags::CPUFlags;
 use crate::instructions::CPU_INSTRUCTION_SET;
 
-pub fn trace(cpu: &mut CPU) -> String {
+/// Layout of a trace line
+#[derive(Debug, Clone, PartialEq)]
+pub enum TraceFormat {
+  /// Matches `nestest.log`:
+  /// `C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7`
+  Nestest,
+  /// Modeled after Mesen's default trace, with the flags spelled out and the frame count:
+  /// `C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 S:FD P:nvUbdIzc V:0   H:21  Fr:0 Cyc:7`
+  Mesen,
+  /// A template where `{PC}`, `{BYTES}`, `{OP}`, `{OPERAND}`, `{DISASM}`, `{A}`, `{X}`, `{Y}`,
+  /// `{P}`, `{FLAGS}`, `{SP}`, `{SL}`, `{DOT}`, `{FRAME}` and `{CYC}` are substituted
+  Custom(String),
+}
+
+impl FromStr for TraceFormat {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_ascii_lowercase().as_str() {
+      "nestest" => Ok(TraceFormat::Nestest),
+      "mesen" => Ok(TraceFormat::Mesen),
+      _ if s.contains('{') => Ok(TraceFormat::Custom(s.to_string())),
+      _ => Err(format!("Unknown trace format \"{}\", expected nestest, mesen or a template containing {{PC}}-style fields", s)),
+    }
+  }
+}
+
+/// Everything a trace line shows about the instruction that is about to run
+pub struct TraceLine {
+  pub pc: u16,
+  pub bytes: Vec<u8>,
+  pub mnemonic: &'static str,
+  pub operand: String,
+  pub acc: u8,
+  pub x: u8,
+  pub y: u8,
+  pub status: CPUFlags,
+  pub sp: u8,
+  pub scanline: u16,
+  pub dot: usize,
+  pub frame: usize,
+  pub cycles: usize,
+}
+
+impl TraceLine {
+
+  pub fn hex_dump(&self) -> String {
+    self.bytes
+      .iter()
+      .map(|z| format!("{:02X}", z))
+      .collect::<Vec<String>>()
+      .join(" ")
+  }
+
+  pub fn disassembly(&self) -> String {
+    format!("{} {}", self.mnemonic.trim(), self.operand).trim().to_string()
+  }
+
+  /// Flags as `NVUBDIZC`, upper case when set and lower case when clear
+  pub fn flags(&self) -> String {
+    "nvubdizc"
+      .chars()
+      .enumerate()
+      .map(|(i, c)| if self.status.bits() & (0x80 >> i) != 0 { c.to_ascii_uppercase() } else { c })
+      .collect()
+  }
+
+  pub fn format(&self, format: &TraceFormat) -> String {
+
+    let asm_str = format!("{:04X}  {:8} {: >4} {}", self.pc, self.hex_dump(), self.mnemonic, self.operand)
+      .trim()
+      .to_string();
+
+    match format {
+      TraceFormat::Nestest => format!(
+        "{:47} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:3},{:3} CYC:{}",
+        asm_str, self.acc, self.x, self.y, self.status, self.sp, self.scanline, self.dot, self.cycles
+      ),
+      TraceFormat::Mesen => format!(
+        "{:47} A:{:02X} X:{:02X} Y:{:02X} S:{:02X} P:{} V:{:<3} H:{:<3} Fr:{} Cyc:{}",
+        asm_str, self.acc, self.x, self.y, self.sp, self.flags(), self.scanline, self.dot, self.frame, self.cycles
+      ),
+      TraceFormat::Custom(template) => template
+        .replace("{PC}", &format!("{:04X}", self.pc))
+        .replace("{BYTES}", &self.hex_dump())
+        .replace("{OP}", self.mnemonic.trim())
+        .replace("{OPERAND}", &self.operand)
+        .replace("{DISASM}", &self.disassembly())
+        .replace("{A}", &format!("{:02X}", self.acc))
+        .replace("{X}", &format!("{:02X}", self.x))
+        .replace("{Y}", &format!("{:02X}", self.y))
+        .replace("{P}", &format!("{:02X}", self.status))
+        .replace("{FLAGS}", &self.flags())
+        .replace("{SP}", &format!("{:02X}", self.sp))
+        .replace("{SL}", &self.scanline.to_string())
+        .replace("{DOT}", &self.dot.to_string())
+        .replace("{FRAME}", &self.frame.to_string())
+        .replace("{CYC}", &self.cycles.to_string()),
+    }
+  }
+
+}
+
+/// Traces the next instruction in the `nestest.log` format
+pub fn trace(cpu: &CPU) -> String {
+  trace_line(cpu).format(&TraceFormat::Nestest)
+}
+
+pub fn trace_with_format(cpu: &CPU, format: &TraceFormat) -> String {
+  trace_line(cpu).format(format)
+}
+
+/// Disassembles the instruction at the program counter. Memory is only peeked at,
+/// so tracing doesn't disturb PPU registers, the controllers or mapper state.
+pub fn trace_line(cpu: &CPU) -> TraceLine {
         
   let opcodes = &CPU_INSTRUCTION_SET;
 
   // TODO: Remove the match statement once all 256 opcodes are implemented
-  let code = cpu.mem_read_u8(cpu.pc);
+  let code = cpu.mem_peek_u8(cpu.pc);
   let opcode = match opcodes.get(&code) {
     Some(ins) => *ins,
     None => {
@@ -24,8 +141,8 @@ pub fn trace(cpu: &mut CPU) -> String {
   let (mem_addr, stored_value) = match opcode.addressing_mode {
       AddressingMode::Immediate | AddressingMode::None | AddressingMode::Implied | AddressingMode::Relative => (0, 0),
       _ => {
-          let (addr, _) = cpu.get_absolute_address(&opcode.addressing_mode, begin+1);
-          (addr, cpu.mem_read_u8(addr))
+          let (addr, _) = cpu.peek_absolute_address(&opcode.addressing_mode, begin+1);
+          (addr, cpu.mem_peek_u8(addr))
       }
   };
 
@@ -35,7 +152,7 @@ pub fn trace(cpu: &mut CPU) -> String {
           _ => String::from(""),
       },
       2 => {
-          let address: u8 = cpu.mem_read_u8(begin + 1);
+          let address: u8 = cpu.mem_peek_u8(begin + 1);
           hex_dump.push(address);
 
           match opcode.addressing_mode {
@@ -77,26 +194,26 @@ pub fn trace(cpu: &mut CPU) -> String {
           }
       }
       3 => {
-          let address_lo = cpu.mem_read_u8(begin + 1);
-          let address_hi = cpu.mem_read_u8(begin + 2);
+          let address_lo = cpu.mem_peek_u8(begin + 1);
+          let address_hi = cpu.mem_peek_u8(begin + 2);
           hex_dump.push(address_lo);
           hex_dump.push(address_hi);
 
-          let address = cpu.mem_read_u16(begin + 1);
+          let address = cpu.mem_peek_u16(begin + 1);
 
           match opcode.addressing_mode {
               AddressingMode::None | AddressingMode::Implied | AddressingMode::Relative | AddressingMode::Indirect => {
                   if opcode.opcode == 0x6c {
                       //jmp indirect
                       let jmp_addr = if address & 0x00FF == 0x00FF {
-                          let lo = cpu.mem_read_u8(address);
-                          let hi = cpu.mem_read_u8(address & 0xFF00);
+                          let lo = cpu.mem_peek_u8(address);
+                          let hi = cpu.mem_peek_u8(address & 0xFF00);
                           (hi as u16) << 8 | (lo as u16)
                       } else {
-                          cpu.mem_read_u16(address)
+                          cpu.mem_peek_u16(address)
                       };
 
-                      // let jmp_addr = cpu.mem_read_u16(address);
+                      // let jmp_addr = cpu.mem_peek_u16(address);
                       format!("(${:04x}) = {:04x}", address, jmp_addr)
                   } else {
                       format!("${:04x}", address)
@@ -127,26 +244,28 @@ pub fn trace(cpu: &mut CPU) -> String {
       _ => String::from(""),
   };
 
-  let hex_str = hex_dump
-      .iter()
-      .map(|z| format!("{:02x}", z))
-      .collect::<Vec<String>>()
-      .join(" ");
-  let asm_str = format!("{:04x}  {:8} {: >4} {}", begin, hex_str, opcode.ins, tmp)
-      .trim()
-      .to_string();
-
-  format!(
-      "{:47} A:{:02x} X:{:02x} Y:{:02x} P:{:02x} SP:{:02x} PPU:{:3},{:3} CYC:{}",
-      asm_str, cpu.acc, cpu.x, cpu.y, cpu.status, cpu.sp, cpu.bus.ppu.scanline, cpu.bus.ppu.cycles, cpu.bus.get_cycles()
-  )
-  .to_ascii_uppercase()
+  TraceLine {
+    pc: begin,
+    bytes: hex_dump,
+    mnemonic: opcode.ins,
+    operand: tmp.to_ascii_uppercase(),
+    acc: cpu.acc,
+    x: cpu.x,
+    y: cpu.y,
+    status: cpu.status,
+    sp: cpu.sp,
+    scanline: cpu.bus.ppu.scanline,
+    dot: cpu.bus.ppu.cycles,
+    frame: cpu.bus.frame_count(),
+    cycles: cpu.bus.get_cycles(),
+  }
 }
 
 #[cfg(test)]
 mod test {
    use super::*;
    use crate::bus::Bus;
+   use crate::gamepad::gamepad_register::JoypadButton;
    use crate::rom::tests::test_rom;
 
    #[test]
@@ -208,4 +327,63 @@ mod test {
            result[0]
        );
    }
-}
\ No newline at end of file
+
+   #[test]
+   fn test_selectable_formats() {
+       let mut bus = Bus::new(test_rom(), |_, _| {});
+       bus.mem_write_u8(100, 0xa2);
+       bus.mem_write_u8(101, 0x01);
+
+       let mut cpu = CPU::new(bus);
+       cpu.pc = 0x64;
+       cpu.acc = 1;
+
+       assert_eq!(
+           "0064  A2 01     LDX #$01                        A:01 X:00 Y:00 S:FD P:nvUbdIzc V:0   H:0   Fr:0 Cyc:0",
+           trace_with_format(&cpu, &TraceFormat::Mesen)
+       );
+
+       let custom = "{PC} {OP} {OPERAND} | {A}{X}{Y} {P} {SL}/{DOT} @{CYC}".parse::<TraceFormat>().unwrap();
+       assert_eq!("0064 LDX #$01 | 010000 24 0/0 @0", trace_with_format(&cpu, &custom));
+   }
+
+   #[test]
+   fn test_trace_has_no_side_effects() {
+       let mut bus = Bus::new(test_rom(), |_, _| {});
+       // LDA $2002
+       bus.mem_write_u8(100, 0xad);
+       bus.mem_write_u8(101, 0x02);
+       bus.mem_write_u8(102, 0x20);
+       // LDA $4016
+       bus.mem_write_u8(103, 0xad);
+       bus.mem_write_u8(104, 0x16);
+       bus.mem_write_u8(105, 0x40);
+
+       // Run the PPU into vblank
+       while bus.mem_peek_u8(0x2002) & 0x80 == 0 {
+           bus.tick_cycles(50);
+       }
+       bus.gamepad().set_button_status(JoypadButton::BUTTON_B);
+
+       let mut cpu = CPU::new(bus);
+       cpu.pc = 0x64;
+
+       let line = trace(&cpu);
+       assert!(line.starts_with("0064  AD 02 20  LDA $2002 = 80"), "{}", line);
+       assert_eq!(cpu.mem_peek_u8(0x2002) & 0x80, 0x80);
+
+       cpu.pc = 0x67;
+       trace(&cpu);
+       trace(&cpu);
+       // Button A is still the next one the controller reports
+       assert_eq!(cpu.mem_read_u8(0x4016), 0);
+       assert_eq!(cpu.mem_read_u8(0x4016), 1);
+   }
+
+   #[test]
+   fn test_parse_format() {
+       assert_eq!("nestest".parse::<TraceFormat>().unwrap(), TraceFormat::Nestest);
+       assert_eq!("Mesen".parse::<TraceFormat>().unwrap(), TraceFormat::Mesen);
+       assert!("fceux".parse::<TraceFormat>().is_err());
+   }
+}
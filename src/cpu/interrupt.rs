@@ -1,5 +1,6 @@
 pub enum InterruptType {
   NMI,
+  IRQ,
 }
 
 pub(super) struct Interrupt {
@@ -12,4 +13,10 @@ pub(super) const NMI: Interrupt = Interrupt {
   vector_address: 0xFFFA,
   interrupt_flag_mask: 0b0010_0000,
   cycles: 2
-};
\ No newline at end of file
+};
+
+pub(super) const IRQ: Interrupt = Interrupt {
+  vector_address: 0xFFFE,
+  interrupt_flag_mask: 0b0010_0000,
+  cycles: 2
+};
@@ -7,8 +7,9 @@ use crate::cpu::cpu_status_flags::CPUFlags;
 use crate::cpu::interrupt::Interrupt;
 use crate::bus::Bus;
 use crate::mem::Mem;
+use crate::savestate::{StateReader, StateWriter};
 
-use self::interrupt::NMI;
+use self::interrupt::{IRQ, NMI};
 
 /// For instructions that perform the same operation
 /// but on different registers (Ex: `CMP`, `CPX`, `CPY`)
@@ -23,6 +24,8 @@ pub enum RegisterID {
     SP
 }
 
+/// `Soft` is the console's reset button, `Hard` switches it off and on again
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ResetKind {
     Soft,
     Hard,
@@ -78,6 +81,10 @@ impl Mem for CPU<'_> {
         self.bus.mem_write_u16(addr, data);
     }
 
+    fn mem_peek_u8(&self, addr: u16) -> u8 {
+        self.bus.mem_peek_u8(addr)
+    }
+
 }
 
 impl CPU<'_> {
@@ -117,6 +124,14 @@ impl CPU<'_> {
         }
     }
 
+    /// Resets the whole console: the bus resets the PPU, mapper and controllers
+    /// (and on power cycle refills RAM) before the CPU reads its reset vector.
+    /// `reset` on its own only touches the CPU registers.
+    pub fn reset_system(&mut self, kind: ResetKind) {
+        self.bus.reset(kind);
+        self.reset(kind);
+    }
+
     /// DEPRECATED?? Maybe only useful for testing??
     /// Loads the program into memory, starting at address 0x8000.
     /// Calling this method WILL reset the CPU state. If you want to test the CPU
@@ -153,113 +168,154 @@ impl CPU<'_> {
     /// as you can inject methods that are run each time the CPU fetches an instruction.
     /// `callback` is executed before the program counter is incremented and the next instruction is executed.
     pub fn run_with_callback<F>(&mut self, mut callback: F) where F: FnMut(&mut CPU), {
+        loop {
+            self.service_interrupts();
+            callback(self);
+            if !self.step() {
+                return;
+            }
+        }
+    }
 
-        let ins_set = &(*instructions::CPU_INSTRUCTION_SET);
+    /// Handles a pending reset request, NMI or IRQ. `run_with_callback` does this
+    /// before handing control to its callback, so anyone driving the CPU with
+    /// `step` directly needs to call this between instructions as well.
+    pub fn service_interrupts(&mut self) {
 
-        loop {
+        if let Some(kind) = self.bus.ppu.take_reset_request() {
+            self.reset_system(kind);
+        }
 
-            if self.bus.ppu.should_reset() {
-                self.bus.ppu.set_should_reset(false);
-                self.reset(ResetKind::Soft);
-            }
+        if let Some(_nmi) = self.bus.poll_nmi() {
+            self.interrupt(NMI);
+        }
 
-            if let Some(_nmi) = self.bus.poll_nmi() {
-                self.interrupt(NMI);
-            }
+        // IRQ is level triggered, it is taken for as long as the line is low and interrupts are enabled
+        if self.bus.irq_pending() && !self.status.contains(CPUFlags::INTERRUPT_DISABLE) {
+            self.interrupt(IRQ);
+        }
 
-            callback(self);
+    }
 
-            let opcode = self.mem_read_u8(self.pc);
-            let ins = *ins_set.get(&opcode).unwrap_or_else(|| panic!("Instruction {} is invalid or unimplemented", opcode));
-
-            self.pc += 1;
-            let current_pc = self.pc;
-
-            match opcode {
-
-                0x00 => return,
-                0xEA => (),
-                0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => (),
-                0x80 => (),
-                0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => self.nop_read(&ins.addressing_mode),
-                0x04 | 0x44 | 0x64 | 0x0C | 0x14 | 0x34 | 0x54 | 0x74 | 0xD4 | 0xF4 => self.nop_read(&ins.addressing_mode),
-                0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => self.add_with_carry(&ins.addressing_mode),
-                0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => self.subtract_with_carry(&ins.addressing_mode),
-                0xEB => self.subtract_with_carry(&ins.addressing_mode),
-                0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => self.and(&ins.addressing_mode),
-                0x24 | 0x2C => self.bit(&ins.addressing_mode),
-                0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => self.compare_register(&ins.addressing_mode, &RegisterID::ACC),
-                0xE0 | 0xE4 | 0xEC => self.compare_register(&ins.addressing_mode, &RegisterID::X),
-                0xC0 | 0xC4 | 0xCC => self.compare_register(&ins.addressing_mode, &RegisterID::Y),
-                0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => self.load_register(&ins.addressing_mode, &RegisterID::ACC),
-                0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => self.load_register(&ins.addressing_mode, &RegisterID::X),
-                0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => self.load_register(&ins.addressing_mode, &RegisterID::Y),
-                0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => self.store_register(&ins.addressing_mode, &RegisterID::ACC),
-                0x86 | 0x96 | 0x8E => self.store_register(&ins.addressing_mode, &RegisterID::X),
-                0x84 | 0x94 | 0x8C => self.store_register(&ins.addressing_mode, &RegisterID::Y),
-                0xA3 | 0xA7 | 0xAF | 0xB3 | 0xB7 | 0xBF => self.load_acc_and_x(&ins.addressing_mode),
-                0x83 | 0x87 | 0x8F | 0x97 => self.store_registers(&ins.addressing_mode, &RegisterID::ACC, &RegisterID::X),
-                0xAA => self.transfer_register(&RegisterID::ACC, &RegisterID::X),
-                0xA8 => self.transfer_register(&RegisterID::ACC, &RegisterID::Y),
-                0xBA => self.transfer_register(&RegisterID::SP, &RegisterID::X),
-                0x8A => self.transfer_register(&RegisterID::X, &RegisterID::ACC),
-                0x9A => self.transfer_register(&RegisterID::X, &RegisterID::SP),
-                0x98 => self.transfer_register(&RegisterID::Y, &RegisterID::ACC),
-                0x18 => self.status.remove(CPUFlags::CARRY),
-                0xD8 => self.status.remove(CPUFlags::DECIMAL_MODE),
-                0x58 => self.status.remove(CPUFlags::INTERRUPT_DISABLE),
-                0xB8 => self.status.remove(CPUFlags::OVERFLOW),
-                0x38 => self.status.insert(CPUFlags::CARRY),
-                0xF8 => self.status.insert(CPUFlags::DECIMAL_MODE),
-                0x78 => self.status.insert(CPUFlags::INTERRUPT_DISABLE),
-                0xCA => self.decrement_register(&RegisterID::X),
-                0x88 => self.decrement_register(&RegisterID::Y),
-                0xE8 => self.increment_register(&RegisterID::X),
-                0xC8 => self.increment_register(&RegisterID::Y),
-                0xE6 | 0xF6 | 0xEE | 0xFE => self.increment_memory(&ins.addressing_mode),
-                0xC6 | 0xD6 | 0xCE | 0xDE => self.decrement_memory(&ins.addressing_mode),
-                0xC3 | 0xC7 | 0xCF | 0xD3 | 0xD7 | 0xDB | 0xDF => self.decrement_memory_unofficial(&ins.addressing_mode),
-                0xE3 | 0xE7 | 0xEF | 0xF3 | 0xF7 | 0xFB | 0xFF  => self.increment_mem_and_subtract_from_acc(&ins.addressing_mode),
-                0x0A => self.acc_shift_left(),
-                0x4A => self.acc_shift_right(),
-                0x2A => self.rotate_acc_left(),
-                0x6A => self.rotate_acc_right(),
-                0x03 | 0x07 | 0x0F | 0x13 | 0x17 | 0x1B | 0x1F => self.arithmetic_shift_left_and_or_with_acc(&ins.addressing_mode),
-                0x43 | 0x47 | 0x4F | 0x53 | 0x57 | 0x5B | 0x5F => self.logical_shift_right_and_xor_with_acc(&ins.addressing_mode),
-                0x23 | 0x27 | 0x2F | 0x33 | 0x37 | 0x3B | 0x3F => self.rotate_left_and_and_with_acc(&ins.addressing_mode),
-                0x63 | 0x67 | 0x6F | 0x73 | 0x77 | 0x7B | 0x7F => self.rotate_right_and_add_to_acc(&ins.addressing_mode),
-                0x06 | 0x16 | 0x0E | 0x1E => self.mem_shift_left(&ins.addressing_mode),
-                0x46 | 0x56 | 0x4E | 0x5E => self.mem_shift_right(&ins.addressing_mode),
-                0x26 | 0x36 | 0x2E | 0x3E => self.rotate_mem_left(&ins.addressing_mode),
-                0x66 | 0x76 | 0x6E | 0x7E => self.rotate_mem_right(&ins.addressing_mode),
-                0xB0 => self.branch_if(self.status.contains(CPUFlags::CARRY)),
-                0xF0 => self.branch_if(self.status.contains(CPUFlags::ZERO)),
-                0x30 => self.branch_if(self.status.contains(CPUFlags::NEGATIVE)),
-                0x70 => self.branch_if(self.status.contains(CPUFlags::OVERFLOW)),
-                0x90 => self.branch_if(!self.status.contains(CPUFlags::CARRY)),
-                0xD0 => self.branch_if(!self.status.contains(CPUFlags::ZERO)),
-                0x10 => self.branch_if(!self.status.contains(CPUFlags::NEGATIVE)),
-                0x50 => self.branch_if(!self.status.contains(CPUFlags::OVERFLOW)),
-                0x4C | 0x6C => self.jump(&ins.addressing_mode),
-                0x20 => self.jump_to_subroutine(&ins.addressing_mode),
-                0x60 => self.return_from_subroutine(),
-                0x40 => self.return_from_interrupt(),
-                0x48 => self.stack_push_u8(self.acc),
-                0x08 => self.stack_push_status(),
-                0x68 => self.stack_pop_acc(),
-                0x28 => self.stack_pop_status(),
-                0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => self.inclusive_or(&ins.addressing_mode),
-                0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => self.exclusive_or(&ins.addressing_mode),
-                _ => todo!("Opcode [0x{:0X}] is invalid or unimplemented", opcode)
+    /// Executes the instruction at the program counter and ticks the bus for its cycles.
+    /// Returns `false` when the instruction was a `BRK`, which is treated as the end of the program.
+    pub fn step(&mut self) -> bool {
 
-            }
+        let ins_set = &(*instructions::CPU_INSTRUCTION_SET);
 
-            self.bus.tick_cycles(ins.cycles);
+        let opcode = self.mem_read_u8(self.pc);
+        let ins = *ins_set.get(&opcode).unwrap_or_else(|| panic!("Instruction {} is invalid or unimplemented", opcode));
+
+        self.pc += 1;
+        let current_pc = self.pc;
+        self.bus.begin_instruction(ins.cycles);
+
+        match opcode {
+
+            0x00 => return false,
+            0xEA => (),
+            0x1A | 0x3A | 0x5A | 0x7A | 0xDA | 0xFA => (),
+            0x80 => (),
+            0x1C | 0x3C | 0x5C | 0x7C | 0xDC | 0xFC => self.nop_read(&ins.addressing_mode),
+            0x04 | 0x44 | 0x64 | 0x0C | 0x14 | 0x34 | 0x54 | 0x74 | 0xD4 | 0xF4 => self.nop_read(&ins.addressing_mode),
+            0x69 | 0x65 | 0x75 | 0x6D | 0x7D | 0x79 | 0x61 | 0x71 => self.add_with_carry(&ins.addressing_mode),
+            0xE9 | 0xE5 | 0xF5 | 0xED | 0xFD | 0xF9 | 0xE1 | 0xF1 => self.subtract_with_carry(&ins.addressing_mode),
+            0xEB => self.subtract_with_carry(&ins.addressing_mode),
+            0x29 | 0x25 | 0x35 | 0x2D | 0x3D | 0x39 | 0x21 | 0x31 => self.and(&ins.addressing_mode),
+            0x24 | 0x2C => self.bit(&ins.addressing_mode),
+            0xC9 | 0xC5 | 0xD5 | 0xCD | 0xDD | 0xD9 | 0xC1 | 0xD1 => self.compare_register(&ins.addressing_mode, &RegisterID::ACC),
+            0xE0 | 0xE4 | 0xEC => self.compare_register(&ins.addressing_mode, &RegisterID::X),
+            0xC0 | 0xC4 | 0xCC => self.compare_register(&ins.addressing_mode, &RegisterID::Y),
+            0xA9 | 0xA5 | 0xB5 | 0xAD | 0xBD | 0xB9 | 0xA1 | 0xB1 => self.load_register(&ins.addressing_mode, &RegisterID::ACC),
+            0xA2 | 0xA6 | 0xB6 | 0xAE | 0xBE => self.load_register(&ins.addressing_mode, &RegisterID::X),
+            0xA0 | 0xA4 | 0xB4 | 0xAC | 0xBC => self.load_register(&ins.addressing_mode, &RegisterID::Y),
+            0x85 | 0x95 | 0x8D | 0x9D | 0x99 | 0x81 | 0x91 => self.store_register(&ins.addressing_mode, &RegisterID::ACC),
+            0x86 | 0x96 | 0x8E => self.store_register(&ins.addressing_mode, &RegisterID::X),
+            0x84 | 0x94 | 0x8C => self.store_register(&ins.addressing_mode, &RegisterID::Y),
+            0xA3 | 0xA7 | 0xAF | 0xB3 | 0xB7 | 0xBF => self.load_acc_and_x(&ins.addressing_mode),
+            0x83 | 0x87 | 0x8F | 0x97 => self.store_registers(&ins.addressing_mode, &RegisterID::ACC, &RegisterID::X),
+            0xAA => self.transfer_register(&RegisterID::ACC, &RegisterID::X),
+            0xA8 => self.transfer_register(&RegisterID::ACC, &RegisterID::Y),
+            0xBA => self.transfer_register(&RegisterID::SP, &RegisterID::X),
+            0x8A => self.transfer_register(&RegisterID::X, &RegisterID::ACC),
+            0x9A => self.transfer_register(&RegisterID::X, &RegisterID::SP),
+            0x98 => self.transfer_register(&RegisterID::Y, &RegisterID::ACC),
+            0x18 => self.status.remove(CPUFlags::CARRY),
+            0xD8 => self.status.remove(CPUFlags::DECIMAL_MODE),
+            0x58 => self.status.remove(CPUFlags::INTERRUPT_DISABLE),
+            0xB8 => self.status.remove(CPUFlags::OVERFLOW),
+            0x38 => self.status.insert(CPUFlags::CARRY),
+            0xF8 => self.status.insert(CPUFlags::DECIMAL_MODE),
+            0x78 => self.status.insert(CPUFlags::INTERRUPT_DISABLE),
+            0xCA => self.decrement_register(&RegisterID::X),
+            0x88 => self.decrement_register(&RegisterID::Y),
+            0xE8 => self.increment_register(&RegisterID::X),
+            0xC8 => self.increment_register(&RegisterID::Y),
+            0xE6 | 0xF6 | 0xEE | 0xFE => self.increment_memory(&ins.addressing_mode),
+            0xC6 | 0xD6 | 0xCE | 0xDE => self.decrement_memory(&ins.addressing_mode),
+            0xC3 | 0xC7 | 0xCF | 0xD3 | 0xD7 | 0xDB | 0xDF => self.decrement_memory_unofficial(&ins.addressing_mode),
+            0xE3 | 0xE7 | 0xEF | 0xF3 | 0xF7 | 0xFB | 0xFF  => self.increment_mem_and_subtract_from_acc(&ins.addressing_mode),
+            0x0A => self.acc_shift_left(),
+            0x4A => self.acc_shift_right(),
+            0x2A => self.rotate_acc_left(),
+            0x6A => self.rotate_acc_right(),
+            0x03 | 0x07 | 0x0F | 0x13 | 0x17 | 0x1B | 0x1F => self.arithmetic_shift_left_and_or_with_acc(&ins.addressing_mode),
+            0x43 | 0x47 | 0x4F | 0x53 | 0x57 | 0x5B | 0x5F => self.logical_shift_right_and_xor_with_acc(&ins.addressing_mode),
+            0x23 | 0x27 | 0x2F | 0x33 | 0x37 | 0x3B | 0x3F => self.rotate_left_and_and_with_acc(&ins.addressing_mode),
+            0x63 | 0x67 | 0x6F | 0x73 | 0x77 | 0x7B | 0x7F => self.rotate_right_and_add_to_acc(&ins.addressing_mode),
+            0x06 | 0x16 | 0x0E | 0x1E => self.mem_shift_left(&ins.addressing_mode),
+            0x46 | 0x56 | 0x4E | 0x5E => self.mem_shift_right(&ins.addressing_mode),
+            0x26 | 0x36 | 0x2E | 0x3E => self.rotate_mem_left(&ins.addressing_mode),
+            0x66 | 0x76 | 0x6E | 0x7E => self.rotate_mem_right(&ins.addressing_mode),
+            0xB0 => self.branch_if(self.status.contains(CPUFlags::CARRY)),
+            0xF0 => self.branch_if(self.status.contains(CPUFlags::ZERO)),
+            0x30 => self.branch_if(self.status.contains(CPUFlags::NEGATIVE)),
+            0x70 => self.branch_if(self.status.contains(CPUFlags::OVERFLOW)),
+            0x90 => self.branch_if(!self.status.contains(CPUFlags::CARRY)),
+            0xD0 => self.branch_if(!self.status.contains(CPUFlags::ZERO)),
+            0x10 => self.branch_if(!self.status.contains(CPUFlags::NEGATIVE)),
+            0x50 => self.branch_if(!self.status.contains(CPUFlags::OVERFLOW)),
+            0x4C | 0x6C => self.jump(&ins.addressing_mode),
+            0x20 => self.jump_to_subroutine(&ins.addressing_mode),
+            0x60 => self.return_from_subroutine(),
+            0x40 => self.return_from_interrupt(),
+            0x48 => self.stack_push_u8(self.acc),
+            0x08 => self.stack_push_status(),
+            0x68 => self.stack_pop_acc(),
+            0x28 => self.stack_pop_status(),
+            0x09 | 0x05 | 0x15 | 0x0D | 0x1D | 0x19 | 0x01 | 0x11 => self.inclusive_or(&ins.addressing_mode),
+            0x49 | 0x45 | 0x55 | 0x4D | 0x5D | 0x59 | 0x41 | 0x51 => self.exclusive_or(&ins.addressing_mode),
+            _ => todo!("Opcode [0x{:0X}] is invalid or unimplemented", opcode)
 
-            if current_pc == self.pc {
-                self.pc += (ins.bytes-1) as u16;
-            }
         }
+
+        self.bus.end_instruction();
+
+        if current_pc == self.pc {
+            self.pc += (ins.bytes-1) as u16;
+        }
+
+        true
+    }
+
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.pc);
+        state.write_u8(self.sp);
+        state.write_u8(self.acc);
+        state.write_u8(self.x);
+        state.write_u8(self.y);
+        state.write_u8(self.status.bits());
+        self.bus.save_state(state);
+    }
+
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+        self.pc = state.read_u16()?;
+        self.sp = state.read_u8()?;
+        self.acc = state.read_u8()?;
+        self.x = state.read_u8()?;
+        self.y = state.read_u8()?;
+        self.status = CPUFlags::from_bits_truncate(state.read_u8()?);
+        self.bus.load_state(state)
     }
 
     fn get_operand_address(&mut self, addressing_mode: &AddressingMode) -> (u16, bool) {
@@ -267,64 +323,81 @@ impl CPU<'_> {
     }
 
     pub fn get_absolute_address(&mut self, addressing_mode: &AddressingMode, addr: u16) -> (u16, bool) {
-        
+        let (x, y, pc) = (self.x, self.y, self.pc);
+        CPU::resolve_address(addressing_mode, addr, x, y, pc, &mut |addr| self.bus.mem_read_u8(addr))
+    }
+
+    /// Same as `get_absolute_address`, but peeks at memory so that nothing is disturbed.
+    /// Used by the tracer to show where an instruction is about to read or write.
+    pub fn peek_absolute_address(&self, addressing_mode: &AddressingMode, addr: u16) -> (u16, bool) {
+        CPU::resolve_address(addressing_mode, addr, self.x, self.y, self.pc, &mut |addr| self.bus.mem_peek_u8(addr))
+    }
+
+    fn resolve_address(addressing_mode: &AddressingMode, addr: u16, x: u8, y: u8, pc: u16, read: &mut dyn FnMut(u16) -> u8) -> (u16, bool) {
+
+        let mut read_u16 = |addr: u16| {
+            let lsb = read(addr) as u16;
+            let msb = read(addr.wrapping_add(1)) as u16;
+            (msb << 8) | lsb
+        };
+
         match addressing_mode {
 
             AddressingMode::Immediate => (addr, false),
-            AddressingMode::Absolute => (self.mem_read_u16(addr), false),
+            AddressingMode::Absolute => (read_u16(addr), false),
             AddressingMode::AbsoluteX => {
-                let base_addr = self.mem_read_u16(addr);
-                let target_addr = base_addr.wrapping_add(self.x as u16);
-                (target_addr, self.page_crossed(base_addr, target_addr))
+                let base_addr = read_u16(addr);
+                let target_addr = base_addr.wrapping_add(x as u16);
+                (target_addr, CPU::page_crossed(base_addr, target_addr))
             },
             AddressingMode::AbsoluteY => {
-                let base_addr = self.mem_read_u16(addr);
-                let target_addr = base_addr.wrapping_add(self.y as u16);
-                (target_addr, self.page_crossed(base_addr, target_addr))
+                let base_addr = read_u16(addr);
+                let target_addr = base_addr.wrapping_add(y as u16);
+                (target_addr, CPU::page_crossed(base_addr, target_addr))
             },
-            AddressingMode::ZeroPage => (self.mem_read_u8(addr) as u16, false),
-            AddressingMode::ZeroPageX => (self.mem_read_u8(addr).wrapping_add(self.x) as u16, false),
-            AddressingMode::ZeroPageY => (self.mem_read_u8(addr).wrapping_add(self.y) as u16, false),
+            AddressingMode::ZeroPage => (read(addr) as u16, false),
+            AddressingMode::ZeroPageX => (read(addr).wrapping_add(x) as u16, false),
+            AddressingMode::ZeroPageY => (read(addr).wrapping_add(y) as u16, false),
             AddressingMode::Indirect => {
 
-                let target_addr = self.mem_read_u16(addr);
+                let target_addr = read_u16(addr);
 
                 if target_addr & 0xFF == 0xFF {
-                    let lsb = self.mem_read_u8(target_addr);
-                    let msb = self.mem_read_u8(target_addr & 0xFF00);
+                    let lsb = read(target_addr);
+                    let msb = read(target_addr & 0xFF00);
                     ((msb as u16) << 8 | lsb as u16, false)
                 } else {
-                    (self.mem_read_u16(target_addr), false)
+                    (read_u16(target_addr), false)
                 }
 
             },
             AddressingMode::IndirectX => {
 
-                let initial_read_addr = self.mem_read_u8(addr);
-                let offset_addr = initial_read_addr.wrapping_add(self.x);
+                let initial_read_addr = read(addr);
+                let offset_addr = initial_read_addr.wrapping_add(x);
 
-                let lsb = self.mem_read_u8(offset_addr as u16);
-                let msb = self.mem_read_u8(offset_addr.wrapping_add(1) as u16);
+                let lsb = read(offset_addr as u16);
+                let msb = read(offset_addr.wrapping_add(1) as u16);
 
                 ((msb as u16) << 8 | lsb as u16, false)
 
             },
             AddressingMode::IndirectY => {
 
-                let initial_read_addr = self.mem_read_u8(addr);
+                let initial_read_addr = read(addr);
 
-                let lsb = self.mem_read_u8(initial_read_addr as u16);
-                let msb = self.mem_read_u8(initial_read_addr.wrapping_add(1) as u16);
+                let lsb = read(initial_read_addr as u16);
+                let msb = read(initial_read_addr.wrapping_add(1) as u16);
                 let target_addr_base = (msb as u16) << 8 | lsb as u16;
-                let target_addr = target_addr_base.wrapping_add(self.y as u16);
+                let target_addr = target_addr_base.wrapping_add(y as u16);
 
-                (target_addr, self.page_crossed(target_addr_base, target_addr))
+                (target_addr, CPU::page_crossed(target_addr_base, target_addr))
 
             },
             AddressingMode::Relative => {
-                let offset = self.mem_read_u8(addr) as i8;
+                let offset = read(addr) as i8;
                 let relative_addr = addr.wrapping_add_signed(offset as i16).wrapping_add(1);
-                (relative_addr, self.page_crossed(self.pc.wrapping_add(1), relative_addr))
+                (relative_addr, CPU::page_crossed(pc.wrapping_add(1), relative_addr))
             }
             _ => panic!("Addressing mode {:?} instruction should not be reading an address", addressing_mode)
         }
@@ -418,22 +491,22 @@ impl CPU<'_> {
     fn inclusive_or(&mut self, addressing_mode: &AddressingMode) {
 
         let (target_addr, page_crossed) = self.get_operand_address(addressing_mode);
+        self.add_cycle_if_page_crossed(page_crossed);
         let data = self.mem_read_u8(target_addr);
 
         self.acc |= data;
         self.set_negative_and_zero_flags(self.acc);
-        self.tick_if_page_crossed(page_crossed);
 
     }
 
     fn exclusive_or(&mut self, addressing_mode: &AddressingMode) {
 
         let (target_addr, page_crossed) = self.get_operand_address(addressing_mode);
+        self.add_cycle_if_page_crossed(page_crossed);
         let data = self.mem_read_u8(target_addr);
 
         self.acc ^= data;
         self.set_negative_and_zero_flags(self.acc);
-        self.tick_if_page_crossed(page_crossed);
 
     }
 
@@ -530,8 +603,8 @@ impl CPU<'_> {
         if condition {
             let (jump_addr, page_crossed) = self.get_operand_address(&AddressingMode::Relative);
             self.pc = jump_addr;
-            self.bus.tick();
-            self.tick_if_page_crossed(page_crossed);
+            // A taken branch takes a cycle, and another when it lands on a different page
+            self.bus.add_cycles(1 + page_crossed as u8);
         }
     }
 
@@ -554,16 +627,16 @@ impl CPU<'_> {
 
     fn add_with_carry(&mut self, addressing_mode: &AddressingMode) {
         let (target_addr, page_crossed) = self.get_operand_address(addressing_mode);
+        self.add_cycle_if_page_crossed(page_crossed);
         let data = self.mem_read_u8(target_addr);
         self.add_to_acc(data);
-        self.tick_if_page_crossed(page_crossed);
     }
 
     fn subtract_with_carry(&mut self, addressing_mode: &AddressingMode) {
         let (target_addr, page_crossed) = self.get_operand_address(addressing_mode);
+        self.add_cycle_if_page_crossed(page_crossed);
         let data = self.mem_read_u8(target_addr) as i8;
         self.add_to_acc(data.wrapping_neg().wrapping_sub(1) as u8);
-        self.tick_if_page_crossed(page_crossed);
     }
 
     fn acc_shift_left(&mut self) {
@@ -697,19 +770,21 @@ impl CPU<'_> {
         self.status.set(CPUFlags::NEGATIVE, value & CPUFlags::NEGATIVE.bits() > 0);
     }
 
-    fn page_crossed(&self, base: u16, target: u16) -> bool {
+    fn page_crossed(base: u16, target: u16) -> bool {
         (base & 0xFF00) != (target & 0xFF00)
     }
 
-    fn tick_if_page_crossed(&mut self, page_crossed: bool) {
+    /// Indexed reads that cross a page take a cycle to fix the address up, before the read itself
+    fn add_cycle_if_page_crossed(&mut self, page_crossed: bool) {
         if page_crossed {
-            self.bus.tick();
+            self.bus.add_cycles(1);
         }
     }
 
     fn load_register(&mut self, addressing_mode: &AddressingMode, target_register: &RegisterID) {
 
         let (target_addr, page_crossed) = self.get_operand_address(addressing_mode);
+        self.add_cycle_if_page_crossed(page_crossed);
         let data = self.mem_read_u8(target_addr);
         let register_ref = match target_register {
             RegisterID::ACC => &mut self.acc,
@@ -720,7 +795,6 @@ impl CPU<'_> {
         
         *register_ref = data;
         self.set_negative_and_zero_flags(data);
-        self.tick_if_page_crossed(page_crossed);
 
     }
 
@@ -741,12 +815,12 @@ impl CPU<'_> {
     fn load_acc_and_x(&mut self, addressing_mode: &AddressingMode) {
 
         let (target_addr, page_crossed) = self.get_operand_address(addressing_mode);
+        self.add_cycle_if_page_crossed(page_crossed);
         let data = self.mem_read_u8(target_addr);
         self.acc = data;
         self.x = data;
 
         self.set_negative_and_zero_flags(data);
-        self.tick_if_page_crossed(page_crossed);
         
     }
 
@@ -798,6 +872,7 @@ impl CPU<'_> {
     fn compare_register(&mut self, addressing_mode: &AddressingMode, target_register: &RegisterID) {
 
         let (target_addr, page_crossed) = self.get_operand_address(addressing_mode);
+        self.add_cycle_if_page_crossed(page_crossed);
         let data = self.mem_read_u8(target_addr);
         let register_value = match target_register {
             RegisterID::ACC => self.acc,
@@ -809,15 +884,14 @@ impl CPU<'_> {
         let result = register_value.wrapping_sub(data);
         self.set_negative_and_zero_flags(result);
         self.status.set(CPUFlags::CARRY, register_value >= data);
-        self.tick_if_page_crossed(page_crossed);
     }
 
     fn and(&mut self, addressing_mode: &AddressingMode) {
         let (target_addr, page_crossed) = self.get_operand_address(addressing_mode);
+        self.add_cycle_if_page_crossed(page_crossed);
         let data = self.mem_read_u8(target_addr);
         self.acc &= data;
         self.set_negative_and_zero_flags(self.acc);
-        self.tick_if_page_crossed(page_crossed);
     }
 
     fn bit(&mut self, addressing_mode: &AddressingMode) {
@@ -834,8 +908,8 @@ impl CPU<'_> {
 
     fn nop_read(&mut self, addressing_mode: &AddressingMode) {
         let (addr, page_crossed) = self.get_operand_address(addressing_mode);
+        self.add_cycle_if_page_crossed(page_crossed);
         self.mem_read_u8(addr);
-        self.tick_if_page_crossed(page_crossed);
     }
 
 }
@@ -846,6 +920,9 @@ mod tests {
     use std::vec;
 
     use crate::cpu::*;
+    use crate::power_on::PowerOnState;
+    use crate::mappers::{Map, RenderEvent};
+    use crate::mappers::tests::banked_rom;
     use crate::rom::tests::test_rom;
 
     fn init_test_cpu<'a>() -> CPU<'a> {
@@ -866,6 +943,66 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_reset_system() {
+
+        let mut cpu = init_test_cpu();
+        cpu.bus.power_on(PowerOnState::Ones);
+        cpu.mem_write_u8(0x0010, 0x42);
+        cpu.acc = 52;
+
+        cpu.bus.ppu.request_reset(ResetKind::Soft);
+        cpu.service_interrupts();
+        assert_eq!(cpu.mem_read_u8(0x0010), 0x42);
+        assert_eq!(cpu.acc, 52);
+
+        cpu.bus.ppu.request_reset(ResetKind::Hard);
+        cpu.service_interrupts();
+        assert_eq!(cpu.mem_read_u8(0x0010), 0xFF);
+        assert_eq!(cpu.acc, 0);
+        assert_eq!(cpu.pc, 0x101);
+    }
+
+    #[test]
+    fn test_mapper_irq() {
+
+        // MMC5 with its scanline IRQ set to fire on the first line after the start of the frame
+        let mut cpu = CPU::new(Bus::new(banked_rom(5), |_, _| {}));
+        cpu.mem_write_u8(0x5203, 1);
+        cpu.mem_write_u8(0x5204, 0x80);
+        cpu.bus.ppu.mapper.render_event(RenderEvent::Scanline(0));
+        cpu.bus.ppu.mapper.render_event(RenderEvent::Scanline(1));
+        cpu.pc = 0x0200;
+
+        cpu.status.insert(CPUFlags::INTERRUPT_DISABLE);
+        cpu.service_interrupts();
+        assert_eq!(cpu.pc, 0x0200);
+
+        // The IRQ vector is in the last bank, which is filled with its bank number
+        cpu.status.remove(CPUFlags::INTERRUPT_DISABLE);
+        cpu.service_interrupts();
+        assert_eq!(cpu.pc, 0x0F0F);
+        assert!(cpu.status.contains(CPUFlags::INTERRUPT_DISABLE));
+    }
+
+    #[test]
+    fn test_page_cross_cycle_comes_before_the_read() {
+
+        // LDA $20FF,X reads PPUSTATUS at $2102, one cycle later for crossing into page $21
+        let mut cpu = init_test_cpu();
+        cpu.load(vec![0xBD, 0xFF, 0x20]);
+        cpu.x = 3;
+
+        // Vblank starts 11 dots in, so only the fourth cycle running first makes the read see it
+        cpu.bus.ppu.scanline = 240;
+        cpu.bus.ppu.cycles = 331;
+        cpu.step();
+
+        assert_eq!(cpu.acc & 0x80, 0x80);
+        assert_eq!(cpu.bus.get_cycles(), 5);
+
+    }
+
     #[test]
     fn test_cpu_reset() {
 
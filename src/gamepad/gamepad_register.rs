@@ -2,7 +2,7 @@ use bitflags::bitflags;
 
 bitflags! {
   // https://wiki.nesdev.com/w/index.php/Controller_reading_code
-  #[derive(Clone, Copy, Default)]
+  #[derive(Clone, Copy, Default, PartialEq, Debug)]
   pub struct JoypadButton: u8 {
       const RIGHT             = 0b1000_0000;
       const LEFT              = 0b0100_0000;
@@ -2,6 +2,8 @@ pub mod gamepad_register;
 
 use gamepad_register::JoypadButton;
 
+use crate::savestate::{StateReader, StateWriter};
+
 #[derive(Default)]
 pub struct Gamepad {
   strobe: bool,
@@ -37,8 +39,39 @@ impl Gamepad {
       response
   }
 
+  /// The bit `read` would return, without shifting to the next button
+  pub fn peek(&self) -> u8 {
+    if self.button_index > 7 {
+      return 1;
+    }
+    (self.button_status.bits() >> self.button_index) & 1
+  }
+
+  /// Clears the strobe and shift position. Which buttons are held is up to the player.
+  pub fn reset(&mut self) {
+    self.strobe = false;
+    self.button_index = 0;
+  }
+
   pub fn set_button_pressed_status(&mut self, button: JoypadButton, pressed: bool) {
     self.button_status.set(button, pressed);
 }
 
+  pub fn button_status(&self) -> JoypadButton { self.button_status }
+
+  pub fn set_button_status(&mut self, status: JoypadButton) { self.button_status = status; }
+
+  pub fn save_state(&self, state: &mut StateWriter) {
+    state.write_bool(self.strobe);
+    state.write_u8(self.button_index);
+    state.write_u8(self.button_status.bits());
+  }
+
+  pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.strobe = state.read_bool()?;
+    self.button_index = state.read_u8()?;
+    self.button_status = JoypadButton::from_bits_truncate(state.read_u8()?);
+    Ok(())
+  }
+
 }
\ No newline at end of file
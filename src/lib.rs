@@ -0,0 +1,16 @@
+pub mod bus;
+pub mod capture;
+pub mod cpu;
+pub mod gamepad;
+pub mod instructions;
+pub mod mappers;
+pub mod mem;
+pub mod power_on;
+pub mod ppu;
+pub mod rewind;
+pub mod rom;
+pub mod savestate;
+pub mod test_rom;
+
+extern crate bitflags;
+extern crate lazy_static;
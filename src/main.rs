@@ -1,47 +1,164 @@
-pub mod bus;
-pub mod cpu;
-pub mod gamepad;
-pub mod instructions;
-pub mod mappers;
-pub mod mem;
-pub mod ppu;
-pub mod rom;
-
-extern crate bitflags;
-extern crate lazy_static;
-
-use bus::Bus;
-use cpu::cpu_status_flags::CPUFlags;
-use cpu::cpu_trace::trace;
-use cpu::CPU;
-use gamepad::gamepad_register::JoypadButton;
-use gamepad::Gamepad;
-use ppu::frame::Frame;
-use ppu::{palette, render, PPU};
-use rom::ROM;
-
-use clap::Parser;
+use ferricom::bus::{Bus, AUDIO_SAMPLE_RATE};
+use ferricom::capture::{self, Y4mRecorder};
+use ferricom::cpu::cpu_status_flags::CPUFlags;
+use ferricom::cpu::cpu_trace::{trace_with_format, TraceFormat};
+use ferricom::cpu::{ResetKind, CPU};
+use ferricom::gamepad::gamepad_register::JoypadButton;
+use ferricom::gamepad::Gamepad;
+use ferricom::ppu::ntsc::{NtscFilter, NtscPreset};
+use ferricom::ppu::frame::PixelFormat;
+use ferricom::ppu::palette::{NtscParams, Palette};
+use ferricom::ppu::viewer::{self, View};
+use ferricom::power_on::PowerOnState;
+use ferricom::ppu::PPU;
+use ferricom::rewind::RewindBuffer;
+use ferricom::rom::ROM;
+use ferricom::test_rom::{self, TestOutcome};
+
+use clap::{Parser, Subcommand};
 use log::{error, info, trace, warn, LevelFilter};
-use sdl2::{event::Event, keyboard::Keycode, pixels::PixelFormatEnum};
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::{event::Event, keyboard::{Keycode, Mod}, pixels::PixelFormatEnum, rect::Rect};
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::path::PathBuf;
+use std::rc::Rc;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about=None)]
+#[command(author, version, about, long_about=None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Arguments {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Path to the ROM file to load, ending in `.nes`
-    rom_file: PathBuf,
+    #[arg(required = true)]
+    rom_file: Option<PathBuf>,
 
     /// Enable generating a tracelog of the CPU.
     /// Will be found in `./logs/cpu_trace.log`
     #[arg(short, long, default_value_t = false)]
     cpu_tracelog: bool,
 
+    /// Layout of the CPU tracelog: `nestest`, `mesen`, or a template
+    /// such as "{PC} {DISASM} A:{A} SL:{SL} CYC:{CYC}"
+    #[arg(long, default_value = "nestest")]
+    trace_format: TraceFormat,
+
     /// Enable if running the `nestest.nes` ROM without a PPU.
     /// This sets the CPU program counter to 0xC000,
     /// which will skip the graphical output
     #[arg(short, long, default_value_t = false)]
     disable_nestest_ppu_output: bool,
+
+    /// A `.pal` file of 64 or 512 colors, e.g. one exported from FCEUX or Nestopia,
+    /// or `ntsc` to generate the palette from the PPU's measured composite signal
+    #[arg(long)]
+    palette: Option<String>,
+
+    /// Hue adjustment in degrees for `--palette ntsc` and `--ntsc-filter`
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    hue: f32,
+
+    /// Saturation multiplier for `--palette ntsc` and `--ntsc-filter`
+    #[arg(long, default_value_t = 1.0)]
+    saturation: f32,
+
+    /// Contrast multiplier for `--palette ntsc` and `--ntsc-filter`
+    #[arg(long, default_value_t = 1.0)]
+    contrast: f32,
+
+    /// Brightness offset for `--palette ntsc` and `--ntsc-filter`, from -1.0 to 1.0
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    brightness: f32,
+
+    /// Simulate how a TV decodes the video signal: `composite` or `svideo`
+    #[arg(long)]
+    ntsc_filter: Option<NtscPreset>,
+
+    /// Draw every sprite on a scanline instead of only the first 8.
+    /// Removes flicker, but isn't accurate
+    #[arg(long, default_value_t = false)]
+    no_sprite_flicker: bool,
+
+    /// Where screenshots are saved. Press F12 to take one
+    #[arg(long, default_value = "screenshots")]
+    screenshot_dir: PathBuf,
+
+    /// Save a screenshot automatically once this frame has been drawn
+    #[arg(long)]
+    screenshot_at: Option<usize>,
+
+    /// Record every frame to a `.y4m` video, e.g. for sharing a bug reproduction.
+    /// Video only until there is an APU to record audio from
+    #[arg(long)]
+    record: Option<PathBuf>,
+
+    /// Open a second window showing the pattern tables, nametables, sprites and palette RAM.
+    /// Tab switches between them and P cycles the pattern table palette.
+    /// F11 saves all of them to the screenshot directory, with or without the window
+    #[arg(long, default_value_t = false)]
+    ppu_viewer: bool,
+
+    /// What RAM holds at power on: `zero`, `ones`, `hardware` (a pattern real consoles show),
+    /// `random`, or `random:<seed>` to reproduce a run. The seed `random` picks is logged
+    #[arg(long, default_value = "zero")]
+    power_on: PowerOnState,
+
+    /// How many frames apart rewind snapshots are taken.
+    /// Hold backspace to rewind
+    #[arg(long, default_value_t = 10)]
+    rewind_interval: usize,
+
+    /// Memory budget for rewind snapshots, in MiB. 0 disables rewinding
+    #[arg(long, default_value_t = 64)]
+    rewind_budget: usize,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run test ROMs that report through 0x6000 (blargg's protocol) without a window.
+    /// Exits with a non-zero status if any of them fail
+    TestRom {
+        /// A test ROM, or a directory to search for test ROMs
+        path: PathBuf,
+
+        /// Give up on a ROM after this many frames
+        #[arg(short, long, default_value_t = 3600)]
+        frames: usize,
+    },
+}
+
+#[cfg(not(tarpaulin_include))]
+fn run_test_roms(path: &PathBuf, frames: usize) -> ! {
+
+    let roms = if path.is_dir() { test_rom::find_test_roms(path) } else { vec![path.clone()] };
+    let mut failed = 0;
+
+    for rom in roms.iter() {
+        let outcome = test_rom::run_path(rom, frames).unwrap_or_else(TestOutcome::Crashed);
+        if !outcome.passed() {
+            failed += 1;
+        }
+        println!("{}: {}", rom.display(), outcome);
+    }
+
+    println!("{} passed, {} failed", roms.len() - failed, failed);
+    std::process::exit(if failed == 0 && !roms.is_empty() { 0 } else { 1 });
+}
+
+/// Number of pages `ppu_view` can show
+const PPU_VIEWER_PAGES: usize = 4;
+
+/// Bytes of sound allowed to queue up before more is dropped, a tenth of a second
+const MAX_QUEUED_AUDIO: u32 = (AUDIO_SAMPLE_RATE / 10 * std::mem::size_of::<f32>()) as u32;
+
+fn ppu_view(ppu: &PPU, palette: &Palette, page: usize, pattern_palette: u8) -> View {
+    match page {
+        0 => viewer::pattern_tables(ppu, palette, pattern_palette),
+        1 => viewer::nametables(ppu, palette),
+        2 => viewer::sprites(ppu, palette),
+        _ => viewer::palette_ram(ppu, palette),
+    }
 }
 
 #[cfg(not(tarpaulin_include))]
@@ -50,10 +167,15 @@ fn main() {
 
     let args = Arguments::parse();
 
-    let file_path = &args.rom_file;
+    if let Some(Command::TestRom { path, frames }) = &args.command {
+        run_test_roms(path, *frames);
+    }
+
+    let file_path = args.rom_file.as_ref().expect("ROM file is required without a subcommand");
     info!("Target ROM: {}", file_path.to_string_lossy());
 
     let cpu_tracing_enabled = args.cpu_tracelog;
+    let trace_format = args.trace_format.clone();
     let nestest_ppu_disabled = args.disable_nestest_ppu_output;
 
     // FIX: Move this log to the ROM module so that we can just early return with `?`
@@ -73,6 +195,7 @@ fn main() {
 
     // NOTE: Maybe add some more info in here if the user wants?
     let window_title = format!("ferricom v0.1.0 EXPERIMENTAL | {}", rom.name);
+    let rom_name = rom.name.clone();
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
@@ -86,13 +209,44 @@ fn main() {
     let mut event_pump = sdl_context.event_pump().unwrap();
     canvas.set_scale(3.0, 3.0).unwrap();
 
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let audio_spec = AudioSpecDesired {
+        freq: Some(AUDIO_SAMPLE_RATE as i32),
+        channels: Some(1),
+        samples: None,
+    };
+    let audio_queue: AudioQueue<f32> = audio_subsystem.open_queue(None, &audio_spec).unwrap();
+    audio_queue.resume();
+
+    let ntsc_params = NtscParams {
+        hue: args.hue,
+        saturation: args.saturation,
+        contrast: args.contrast,
+        brightness: args.brightness,
+    };
+    let palette = match args.palette.as_deref() {
+        None => Ok(Palette::default()),
+        Some("ntsc") => Ok(Palette::ntsc(&ntsc_params)),
+        Some(path) => Palette::from_path(path),
+    };
+    let palette = match palette {
+        Ok(palette) => palette,
+        Err(msg) => {
+            error!("{msg}");
+            panic!("{msg}");
+        }
+    };
+    let mut converted = vec![0; 256 * 240 * PixelFormat::RGB24.bytes_per_pixel()];
+
+    let mut ntsc_filter = args.ntsc_filter.map(|preset| NtscFilter::new(preset, ntsc_params));
+    let mut filtered = vec![0; NtscFilter::WIDTH * NtscFilter::HEIGHT * 3];
+    let texture_width = if ntsc_filter.is_some() { NtscFilter::WIDTH } else { 256 };
+
     let creator = canvas.texture_creator();
     let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 256, 240)
+        .create_texture_target(PixelFormatEnum::RGB24, texture_width as u32, 240)
         .unwrap();
 
-    let mut frame = Frame::new();
-
     // TODO: Make keys remappable
     let mut key_map = HashMap::new();
     key_map.insert(Keycode::Down, JoypadButton::DOWN);
@@ -104,41 +258,174 @@ fn main() {
     key_map.insert(Keycode::A, JoypadButton::BUTTON_A);
     key_map.insert(Keycode::S, JoypadButton::BUTTON_B);
 
-    let bus = Bus::new(rom, move |ppu: &mut PPU, gamepad: &mut Gamepad| {
-        render::render(ppu, &mut frame);
-        texture.update(None, &frame.data, 256 * 3).unwrap();
+    let mut viewer_canvas = args.ppu_viewer.then(|| {
+        let window = video_subsystem
+            .window("ferricom PPU viewer", 512 * 2, 480 * 2)
+            .build()
+            .unwrap();
+        window.into_canvas().present_vsync().build().unwrap()
+    });
+    let viewer_creator = viewer_canvas.as_ref().map(|canvas| canvas.texture_creator());
+    let mut viewer_page = 0;
+    let mut pattern_palette = 0;
+
+    let rewind_held = Rc::new(Cell::new(false));
+    let rewind_key = Rc::clone(&rewind_held);
+    // Quitting waits for the CPU callback, which can reach the battery-backed RAM
+    let quit_requested = Rc::new(Cell::new(false));
+    let quit_key = Rc::clone(&quit_requested);
+
+    let mut recorder = match args.record.as_ref().map(|path| Y4mRecorder::create(path, 256, 240)).transpose() {
+        Ok(recorder) => recorder,
+        Err(msg) => {
+            error!("{msg}");
+            panic!("{msg}");
+        }
+    };
+    let screenshot_dir = args.screenshot_dir.clone();
+    let screenshot_at = args.screenshot_at;
+    let mut frames_drawn = 0;
+
+    let mut bus = Bus::new(rom, move |ppu: &mut PPU, gamepad: &mut Gamepad| {
+        frames_drawn += 1;
+
+        if let Some(recorder) = recorder.as_mut() {
+            ppu.frame.convert(&palette, PixelFormat::RGB24, &mut converted);
+            if let Err(msg) = recorder.write_frame(&converted) {
+                error!("{msg}");
+            }
+        }
+
+        let mut take_screenshot = screenshot_at == Some(frames_drawn);
+        let mut save_ppu_views = false;
+
+        match ntsc_filter.as_mut() {
+            Some(filter) => {
+                filter.apply(&ppu.frame, &mut filtered);
+                texture.update(None, &filtered, NtscFilter::WIDTH * 3).unwrap();
+            }
+            None => {
+                ppu.frame.convert(&palette, PixelFormat::RGB24, &mut converted);
+                texture.update(None, &converted, 256 * 3).unwrap();
+            }
+        }
 
         canvas.copy(&texture, None, None).unwrap();
 
         canvas.present();
+
+        if let (Some(viewer_canvas), Some(creator)) = (viewer_canvas.as_mut(), viewer_creator.as_ref()) {
+            let view = ppu_view(ppu, &palette, viewer_page, pattern_palette);
+            let mut viewer_texture = creator
+                .create_texture_streaming(PixelFormatEnum::RGB24, view.width as u32, view.height as u32)
+                .unwrap();
+            viewer_texture.update(None, &view.rgb, view.width * 3).unwrap();
+
+            // Largest whole-number scale that fits the window
+            let (window_width, window_height) = viewer_canvas.output_size().unwrap();
+            let scale = (window_width / view.width as u32).min(window_height / view.height as u32).max(1);
+            viewer_canvas.clear();
+            viewer_canvas
+                .copy(&viewer_texture, None, Rect::new(0, 0, view.width as u32 * scale, view.height as u32 * scale))
+                .unwrap();
+            viewer_canvas.present();
+        }
+
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
-                } => std::process::exit(0),
+                } => {
+                    // `exit` skips destructors, so the recording has to be flushed here
+                    if let Some(Err(msg)) = recorder.take().map(Y4mRecorder::finish) {
+                        error!("{msg}");
+                    }
+                    quit_key.set(true);
+                }
 
-                Event::KeyDown { keycode, .. } => {
+                Event::KeyDown { keycode, keymod, .. } => {
                     if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
                         gamepad.set_button_pressed_status(*key, true);
                     }
 
-                    if keycode.unwrap() == Keycode::R {
-                        ppu.set_should_reset(true);
+                    // R presses the reset button, Shift+R power cycles
+                    if keycode == Some(Keycode::R) {
+                        if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                            ppu.request_reset(ResetKind::Hard);
+                        } else {
+                            ppu.request_reset(ResetKind::Soft);
+                        }
+                    }
+
+                    if keycode == Some(Keycode::Backspace) {
+                        rewind_key.set(true);
+                    }
+
+                    if keycode == Some(Keycode::F12) {
+                        take_screenshot = true;
+                    }
+
+                    if keycode == Some(Keycode::F11) {
+                        save_ppu_views = true;
+                    }
+
+                    if keycode == Some(Keycode::Tab) {
+                        viewer_page = (viewer_page + 1) % PPU_VIEWER_PAGES;
+                    }
+
+                    if keycode == Some(Keycode::P) {
+                        pattern_palette = (pattern_palette + 1) % 8;
                     }
                 }
                 Event::KeyUp { keycode, .. } => {
                     if let Some(key) = key_map.get(&keycode.unwrap_or(Keycode::Ampersand)) {
                         gamepad.set_button_pressed_status(*key, false);
                     }
+
+                    if keycode == Some(Keycode::Backspace) {
+                        rewind_key.set(false);
+                    }
                 }
 
                 _ => { /* do nothing */ }
             }
         }
+
+        if take_screenshot {
+            let path = screenshot_dir.join(format!("{}-{:06}.png", rom_name, frames_drawn));
+            match capture::save_screenshot(&path, &ppu.frame, &palette) {
+                Ok(()) => info!("Saved screenshot to {}", path.to_string_lossy()),
+                Err(msg) => error!("{msg}"),
+            }
+        }
+
+        if save_ppu_views {
+            let prefix = format!("{}-{:06}", rom_name, frames_drawn);
+            match viewer::save_views(ppu, &palette, pattern_palette, &screenshot_dir, &prefix) {
+                Ok(()) => info!("Saved PPU views to {}", screenshot_dir.join(prefix).to_string_lossy()),
+                Err(msg) => error!("{msg}"),
+            }
+        }
     });
 
+    bus.ppu.set_sprite_limit(!args.no_sprite_flicker);
+    info!("Power on state: {}", args.power_on);
+    bus.power_on(args.power_on);
+
+    // Battery saves live next to the ROM
+    let save_path = file_path.with_extension("sav");
+    if bus.has_battery() && save_path.exists() {
+        let loaded = std::fs::read(&save_path)
+            .map_err(|err| format!("Couldn't read {}: {err}", save_path.to_string_lossy()))
+            .and_then(|data| bus.load_battery_ram(&data));
+        match loaded {
+            Ok(()) => info!("Loaded battery save from {}", save_path.to_string_lossy()),
+            Err(msg) => error!("{msg}"),
+        }
+    }
+
     let mut cpu = CPU::new(bus);
 
     if nestest_ppu_disabled {
@@ -147,11 +434,46 @@ fn main() {
         cpu.status = CPUFlags::from_bits_truncate(0x24);
     }
 
+    let mut rewind = (args.rewind_budget > 0)
+        .then(|| RewindBuffer::new(args.rewind_interval, args.rewind_budget * 1024 * 1024));
+    let mut last_frame = 0;
+
     let _ = simple_logging::log_to_file("logs/cpu_trace.log", LevelFilter::Trace);
 
     cpu.run_with_callback(move |cpu| {
         if cpu_tracing_enabled {
-            trace!("{}", trace(cpu));
+            trace!("{}", trace_with_format(cpu, &trace_format));
+        }
+
+        if quit_requested.get() {
+            if cpu.bus.has_battery() {
+                if let Err(err) = std::fs::write(&save_path, cpu.bus.battery_ram()) {
+                    error!("Couldn't write {}: {err}", save_path.to_string_lossy());
+                }
+            }
+            std::process::exit(0)
+        }
+
+        if cpu.bus.frame_count() != last_frame {
+            // A frame was just shown. Rewinding is silent, and sound that's fallen
+            // behind is dropped rather than played ever later
+            let samples = cpu.bus.take_audio_samples();
+            if !rewind_held.get() && audio_queue.size() < MAX_QUEUED_AUDIO {
+                if let Err(msg) = audio_queue.queue_audio(&samples) {
+                    error!("{msg}");
+                }
+            }
+
+            // While rewinding, step back two frames so that emulating
+            // the next one lands on the frame before the one on screen
+            if let Some(rewind) = rewind.as_mut() {
+                if rewind_held.get() {
+                    rewind.rewind(cpu, 2);
+                } else {
+                    rewind.record(cpu);
+                }
+            }
+            last_frame = cpu.bus.frame_count();
         }
     });
 }
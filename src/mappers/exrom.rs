@@ -0,0 +1,833 @@
+use crate::cpu::ResetKind;
+use crate::rom::{ROM, ScreenMirroring};
+use crate::savestate::{StateReader, StateWriter};
+
+use super::{Map, Mapper, MappedRead, MappedWrite, RenderEvent};
+
+/// MMC5's internal RAM at $5C00-$5FFF
+const EX_RAM_SIZE: usize = 0x400;
+
+const CHR_START: usize = 0x0000;
+const CHR_END: usize = 0x1FFF;
+const PULSE_START: usize = 0x5000;
+const PULSE_END: usize = 0x5007;
+const EX_RAM_START: usize = 0x5C00;
+const EX_RAM_END: usize = 0x5FFF;
+const PRG_START: usize = 0x6000;
+const PRG_END: usize = 0xFFFF;
+
+/// Nametable rows before the attribute table
+const ATTRIBUTE_OFFSET: usize = 0x3C0;
+
+/// CPU cycles between envelope and length counter clocks. MMC5 doesn't have the APU's
+/// frame counter, it clocks both at a fixed 240 Hz.
+const FRAME_PERIOD: u16 = 7457;
+
+/// Lengths the top 5 bits of a pulse's fourth register load, the same as the APU's
+const LENGTH_TABLE: [u8; 32] = [
+  10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+  12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// The 8 steps of each duty cycle, 12.5%, 25%, 50% and 75%
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+  [0, 1, 0, 0, 0, 0, 0, 0],
+  [0, 1, 1, 0, 0, 0, 0, 0],
+  [0, 1, 1, 1, 1, 0, 0, 0],
+  [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+/// Loudest the two 4-bit pulses get together
+const MAX_PULSE_OUTPUT: f32 = (15 + 15) as f32;
+
+/// Where an 8 KB slot of 0x6000-0xFFFF points
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PrgBank {
+  Rom(usize),
+  Ram(usize),
+}
+
+/// Which part of the scanline the PPU is fetching
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Fetching {
+  Idle,
+  Background,
+  Sprites,
+}
+
+/// Attribute byte with all four quadrants set to `palette`
+fn fill_attribute(palette: u8) -> u8 {
+  (palette & 0b11) * 0b0101_0101
+}
+
+/// One of MMC5's two square channels, an APU pulse without the sweep unit
+#[derive(Debug, Clone, Default)]
+struct Pulse {
+  duty: u8,
+  /// Stops the length counter and loops the envelope
+  halt: bool,
+  constant: bool,
+  /// The constant volume, or the envelope's period
+  volume: u8,
+  period: u16,
+  enabled: bool,
+  divider: u16,
+  step: u8,
+  length: u8,
+  envelope_start: bool,
+  envelope_divider: u8,
+  decay: u8,
+}
+
+impl Pulse {
+
+  fn write(&mut self, register: usize, data: u8) {
+    match register {
+      0 => {
+        self.duty = data >> 6;
+        self.halt = data & 0x20 != 0;
+        self.constant = data & 0x10 != 0;
+        self.volume = data & 0x0F;
+      },
+      // Where the APU's sweep unit would be
+      1 => {},
+      2 => self.period = self.period & 0x700 | data as u16,
+      _ => {
+        self.period = self.period & 0x0FF | ((data & 0x07) as u16) << 8;
+        if self.enabled {
+          self.length = LENGTH_TABLE[data as usize >> 3];
+        }
+        self.step = 0;
+        self.envelope_start = true;
+      },
+    }
+  }
+
+  fn set_enabled(&mut self, enabled: bool) {
+    self.enabled = enabled;
+    if !enabled {
+      self.length = 0;
+    }
+  }
+
+  /// The timer runs at half the CPU clock like the APU's
+  fn clock(&mut self) {
+    if self.divider == 0 {
+      self.divider = self.period * 2 + 1;
+      self.step = (self.step + 1) % 8;
+    } else {
+      self.divider -= 1;
+    }
+  }
+
+  fn clock_frame(&mut self) {
+    if self.envelope_start {
+      self.envelope_start = false;
+      self.decay = 15;
+      self.envelope_divider = self.volume;
+    } else if self.envelope_divider == 0 {
+      self.envelope_divider = self.volume;
+      if self.decay > 0 {
+        self.decay -= 1;
+      } else if self.halt {
+        self.decay = 15;
+      }
+    } else {
+      self.envelope_divider -= 1;
+    }
+
+    if !self.halt && self.length > 0 {
+      self.length -= 1;
+    }
+  }
+
+  fn output(&self) -> u8 {
+    if self.length == 0 || DUTY_CYCLES[self.duty as usize][self.step as usize] == 0 {
+      0
+    } else if self.constant {
+      self.volume
+    } else {
+      self.decay
+    }
+  }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    state.write_u8(self.duty);
+    state.write_bool(self.halt);
+    state.write_bool(self.constant);
+    state.write_u8(self.volume);
+    state.write_u16(self.period);
+    state.write_bool(self.enabled);
+    state.write_u16(self.divider);
+    state.write_u8(self.step);
+    state.write_u8(self.length);
+    state.write_bool(self.envelope_start);
+    state.write_u8(self.envelope_divider);
+    state.write_u8(self.decay);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.duty = state.read_u8()? & 0b11;
+    self.halt = state.read_bool()?;
+    self.constant = state.read_bool()?;
+    self.volume = state.read_u8()?;
+    self.period = state.read_u16()?;
+    self.enabled = state.read_bool()?;
+    self.divider = state.read_u16()?;
+    self.step = state.read_u8()? % 8;
+    self.length = state.read_u8()?;
+    self.envelope_start = state.read_bool()?;
+    self.envelope_divider = state.read_u8()?;
+    self.decay = state.read_u8()?;
+    Ok(())
+  }
+
+}
+
+/// MMC5 (mapper 5), used by Castlevania III and Just Breed among others. Four PRG banking
+/// modes that can put RAM in the ROM area, two sets of CHR banks for sprites and backgrounds,
+/// 1 KB of ExRAM that doubles as a nametable or per-tile attributes, a fill mode nametable,
+/// a vertical split screen, a scanline IRQ, an 8x8 multiplier, and two pulse channels plus
+/// a raw PCM channel. PCM read mode, which samples PRG reads, isn't emulated.
+pub struct EXROM {
+  prg_mode: u8,
+  chr_mode: u8,
+  prg_ram_protect: [u8; 2],
+  ex_ram_mode: u8,
+  /// Two bits per nametable: CIRAM page 0 or 1, ExRAM, or fill mode
+  nametable_mapping: u8,
+  fill_tile: u8,
+  fill_color: u8,
+  /// $5113-$5117, the PRG-RAM bank at 0x6000 and the four 8 KB slots above it
+  prg_regs: [u8; 5],
+  /// $5120-$5127 for sprites, $5128-$512B for backgrounds, with the $5130 bits they were written with
+  chr_regs: [u16; 12],
+  chr_upper: u8,
+  background_set_written_last: bool,
+  split_control: u8,
+  split_scroll: u8,
+  split_bank: u8,
+  irq_compare: u8,
+  irq_enabled: bool,
+  irq_pending: bool,
+  in_frame: bool,
+  irq_counter: u8,
+  multiplicand: u8,
+  multiplier: u8,
+  ex_ram: [u8; EX_RAM_SIZE],
+  fetching: Fetching,
+  tall_sprites: bool,
+  scanline: u16,
+  tile_column: u8,
+  in_split: bool,
+  /// ExRAM byte of the background tile being fetched, in extended attribute mode
+  ex_attribute: u8,
+  pulses: [Pulse; 2],
+  frame_divider: u16,
+  /// $5010, bit 0 switches the PCM channel to read mode
+  pcm_mode: u8,
+  pcm: u8,
+  prg_rom_size: usize,
+  prg_ram_size: usize,
+  chr_size: usize,
+}
+
+impl EXROM {
+
+  pub fn load(rom: &ROM) -> Mapper {
+
+    let chr_size = if rom.has_chr_rom() { rom.chr_rom.len() } else { rom.chr_ram.len() };
+    let nametable_mapping = match rom.header.mirroring {
+      ScreenMirroring::Horizontal => 0x50,
+      _ => 0x44,
+    };
+
+    Self::new(rom.prg_rom.len(), rom.prg_ram.len(), chr_size, nametable_mapping).into()
+
+  }
+
+  fn new(prg_rom_size: usize, prg_ram_size: usize, chr_size: usize, nametable_mapping: u8) -> Self {
+    Self {
+      prg_mode: 3,
+      chr_mode: 0,
+      prg_ram_protect: [0; 2],
+      ex_ram_mode: 0,
+      nametable_mapping,
+      fill_tile: 0,
+      fill_color: 0,
+      prg_regs: [0, 0, 0, 0, 0xFF],
+      chr_regs: [0; 12],
+      chr_upper: 0,
+      background_set_written_last: false,
+      split_control: 0,
+      split_scroll: 0,
+      split_bank: 0,
+      irq_compare: 0,
+      irq_enabled: false,
+      irq_pending: false,
+      in_frame: false,
+      irq_counter: 0,
+      multiplicand: 0xFF,
+      multiplier: 0xFF,
+      ex_ram: [0; EX_RAM_SIZE],
+      fetching: Fetching::Idle,
+      tall_sprites: false,
+      scanline: 0,
+      tile_column: 0,
+      in_split: false,
+      ex_attribute: 0,
+      pulses: [Pulse::default(), Pulse::default()],
+      frame_divider: 0,
+      pcm_mode: 0,
+      pcm: 0,
+      prg_rom_size: prg_rom_size.max(1),
+      prg_ram_size: prg_ram_size.max(1),
+      chr_size: chr_size.max(1),
+    }
+  }
+
+  /// Which 8 KB bank the slot of 0x6000-0xFFFF holding `addr` points at
+  fn prg_bank(&self, addr: u16) -> PrgBank {
+
+    let slot = (addr as usize - PRG_START) / 0x2000;
+    if slot == 0 {
+      return PrgBank::Ram(self.prg_regs[0] as usize & 0x07);
+    }
+
+    // The larger windows of modes 0-2 ignore the low bits of their register
+    let quarter = slot - 1;
+    let (reg, mask) = match (self.prg_mode, quarter) {
+      (0, _) => (4, 0x7C),
+      (1, 0 | 1) | (2, 0 | 1) => (2, 0x7E),
+      (1, _) => (4, 0x7E),
+      (_, quarter) => (quarter + 1, 0x7F),
+    };
+
+    let value = self.prg_regs[reg];
+    let bank = (value & mask) as usize + (quarter & !mask as usize & 0b11);
+
+    // $5117 always selects ROM, the others pick with bit 7
+    if reg == 4 || value & 0x80 != 0 {
+      PrgBank::Rom(bank)
+    } else {
+      PrgBank::Ram(bank & 0x07)
+    }
+  }
+
+  fn prg_ram_writable(&self) -> bool {
+    self.prg_ram_protect == [0b10, 0b01]
+  }
+
+  /// In 8x16 sprite mode backgrounds use the $5128-$512B banks and sprites $5120-$5127.
+  /// Otherwise every fetch, PPUDATA included, uses whichever set was written last.
+  fn use_background_set(&self) -> bool {
+    match self.fetching {
+      Fetching::Background if self.tall_sprites => true,
+      Fetching::Sprites if self.tall_sprites => false,
+      _ => self.background_set_written_last,
+    }
+  }
+
+  fn split_y(&self) -> usize {
+    (self.scanline as usize + self.split_scroll as usize) % 240
+  }
+
+  fn split_active(&self, column: u8) -> bool {
+    let threshold = self.split_control & 0x1F;
+    let right_side = self.split_control & 0x40 != 0;
+    self.split_control & 0x80 != 0 && self.ex_ram_mode <= 1 && (if right_side { column >= threshold } else { column < threshold })
+  }
+
+  fn chr_index(&self, addr: u16) -> usize {
+
+    let addr = addr as usize;
+    let background = self.fetching == Fetching::Background;
+
+    let index = if background && self.in_split {
+      // The split has its own 4 KB bank and vertical scroll
+      self.split_bank as usize * 0x1000 + (addr & 0x0FF8 | (self.split_y() % 8))
+    } else if background && self.ex_ram_mode == 1 {
+      let bank = (self.chr_upper as usize) << 6 | self.ex_attribute as usize & 0x3F;
+      bank * 0x1000 + (addr & 0x0FFF)
+    } else {
+      let regs = &self.chr_regs;
+      let set: [u16; 8] = if self.use_background_set() {
+        [regs[8], regs[9], regs[10], regs[11], regs[8], regs[9], regs[10], regs[11]]
+      } else {
+        [regs[0], regs[1], regs[2], regs[3], regs[4], regs[5], regs[6], regs[7]]
+      };
+      let (window, bank) = match self.chr_mode {
+        0 => (0x2000, set[7]),
+        1 => (0x1000, set[addr / 0x1000 * 4 + 3]),
+        2 => (0x800, set[addr / 0x800 * 2 + 1]),
+        _ => (0x400, set[addr / 0x400]),
+      };
+      bank as usize * window + addr % window
+    };
+
+    index % self.chr_size
+  }
+
+  fn read_status(&self) -> u8 {
+    (self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6
+  }
+
+  fn product(&self) -> u16 {
+    self.multiplicand as u16 * self.multiplier as u16
+  }
+
+  /// $5015, which pulses still have length left
+  fn read_audio_status(&self) -> u8 {
+    (self.pulses[0].length > 0) as u8 | ((self.pulses[1].length > 0) as u8) << 1
+  }
+
+}
+
+impl Map for EXROM {
+
+  fn map_read(&mut self, addr: u16) -> MappedRead {
+    let data = self.map_peak(addr);
+    // Reading the status acknowledges the IRQ
+    if addr == 0x5204 {
+      self.irq_pending = false;
+    }
+    data
+  }
+
+  fn map_peak(&self, addr: u16) -> MappedRead {
+    match addr as usize {
+      CHR_START..=CHR_END => MappedRead::Chr(self.chr_index(addr)),
+      0x5015 => MappedRead::Data(self.read_audio_status()),
+      0x5204 => MappedRead::Data(self.read_status()),
+      0x5205 => MappedRead::Data(self.product() as u8),
+      0x5206 => MappedRead::Data((self.product() >> 8) as u8),
+      // ExRAM is only readable while it isn't in use by the PPU
+      EX_RAM_START..=EX_RAM_END if self.ex_ram_mode >= 2 => MappedRead::Data(self.ex_ram[addr as usize - EX_RAM_START]),
+      PRG_START..=PRG_END => {
+        let offset = addr as usize & 0x1FFF;
+        match self.prg_bank(addr) {
+          PrgBank::Rom(bank) => MappedRead::PrgROM((bank * 0x2000 + offset) % self.prg_rom_size),
+          PrgBank::Ram(bank) => MappedRead::PrgRAM((bank * 0x2000 + offset) % self.prg_ram_size),
+        }
+      },
+      _ => MappedRead::None,
+    }
+  }
+
+  fn map_write(&mut self, addr: u16, data: u8) -> MappedWrite {
+    match addr as usize {
+      CHR_START..=CHR_END => return MappedWrite::Chr(self.chr_index(addr), data),
+      PULSE_START..=PULSE_END => {
+        let offset = addr as usize - PULSE_START;
+        self.pulses[offset / 4].write(offset % 4, data);
+      },
+      0x5010 => self.pcm_mode = data,
+      // Zero can't be written, it's what marks the end of a sample in read mode
+      0x5011 if self.pcm_mode & 1 == 0 && data != 0 => self.pcm = data,
+      0x5015 => {
+        self.pulses[0].set_enabled(data & 0b01 != 0);
+        self.pulses[1].set_enabled(data & 0b10 != 0);
+      },
+      0x5100 => self.prg_mode = data & 0b11,
+      0x5101 => self.chr_mode = data & 0b11,
+      0x5102 => self.prg_ram_protect[0] = data & 0b11,
+      0x5103 => self.prg_ram_protect[1] = data & 0b11,
+      0x5104 => self.ex_ram_mode = data & 0b11,
+      0x5105 => self.nametable_mapping = data,
+      0x5106 => self.fill_tile = data,
+      0x5107 => self.fill_color = data & 0b11,
+      0x5113..=0x5117 => self.prg_regs[addr as usize - 0x5113] = data,
+      0x5120..=0x512B => {
+        let reg = addr as usize - 0x5120;
+        self.chr_regs[reg] = (self.chr_upper as u16) << 8 | data as u16;
+        self.background_set_written_last = reg >= 8;
+      },
+      0x5130 => self.chr_upper = data & 0b11,
+      0x5200 => self.split_control = data,
+      0x5201 => self.split_scroll = data,
+      0x5202 => self.split_bank = data,
+      0x5203 => self.irq_compare = data,
+      0x5204 => self.irq_enabled = data & 0x80 != 0,
+      0x5205 => self.multiplicand = data,
+      0x5206 => self.multiplier = data,
+      // Read-only in mode 3
+      EX_RAM_START..=EX_RAM_END if self.ex_ram_mode != 3 => self.ex_ram[addr as usize - EX_RAM_START] = data,
+      PRG_START..=PRG_END => {
+        if let PrgBank::Ram(bank) = self.prg_bank(addr) {
+          if self.prg_ram_writable() {
+            return MappedWrite::PrgRAM((bank * 0x2000 + (addr as usize & 0x1FFF)) % self.prg_ram_size, data);
+          }
+        }
+      },
+      _ => {},
+    }
+    MappedWrite::None
+  }
+
+  fn map_nametable(&self, addr: u16) -> MappedRead {
+
+    let offset = addr as usize & 0x3FF;
+
+    if self.fetching == Fetching::Background {
+      if self.in_split {
+        let y = self.split_y();
+        let column = self.tile_column as usize;
+        return MappedRead::Data(if offset < ATTRIBUTE_OFFSET {
+          self.ex_ram[y / 8 * 32 + column]
+        } else {
+          let attribute = self.ex_ram[ATTRIBUTE_OFFSET + y / 32 * 8 + column / 4];
+          let shift = y % 32 / 16 * 4 + column % 4 / 2 * 2;
+          fill_attribute(attribute >> shift)
+        });
+      }
+      if self.ex_ram_mode == 1 && offset >= ATTRIBUTE_OFFSET {
+        return MappedRead::Data(fill_attribute(self.ex_attribute >> 6));
+      }
+    }
+
+    match self.nametable_mapping >> ((addr >> 10 & 0b11) * 2) & 0b11 {
+      0 => MappedRead::Vram(offset),
+      1 => MappedRead::Vram(0x400 + offset),
+      2 if self.ex_ram_mode <= 1 => MappedRead::Data(self.ex_ram[offset]),
+      2 => MappedRead::Data(0),
+      _ if offset < ATTRIBUTE_OFFSET => MappedRead::Data(self.fill_tile),
+      _ => MappedRead::Data(fill_attribute(self.fill_color)),
+    }
+  }
+
+  fn map_nametable_write(&mut self, addr: u16, data: u8) -> MappedWrite {
+    let offset = addr as usize & 0x3FF;
+    match self.nametable_mapping >> ((addr >> 10 & 0b11) * 2) & 0b11 {
+      0 => MappedWrite::Vram(offset, data),
+      1 => MappedWrite::Vram(0x400 + offset, data),
+      2 if self.ex_ram_mode <= 1 => {
+        self.ex_ram[offset] = data;
+        MappedWrite::None
+      },
+      _ => MappedWrite::None,
+    }
+  }
+
+  fn ppu_fetch(&mut self, addr: u16) {
+    let offset = addr as usize & 0x3FF;
+    let tile_fetch = (0x2000..=0x2FFF).contains(&addr) && offset < ATTRIBUTE_OFFSET;
+    if tile_fetch && self.fetching == Fetching::Background && self.ex_ram_mode == 1 && !self.in_split {
+      self.ex_attribute = self.ex_ram[offset];
+    }
+  }
+
+  fn render_event(&mut self, event: RenderEvent) {
+    match event {
+      RenderEvent::Scanline(scanline) => {
+        self.fetching = Fetching::Idle;
+        self.scanline = scanline;
+        if self.in_frame {
+          self.irq_counter = self.irq_counter.wrapping_add(1);
+          if self.irq_counter == self.irq_compare {
+            self.irq_pending = true;
+          }
+        } else {
+          self.in_frame = true;
+          self.irq_counter = 0;
+          self.irq_pending = false;
+        }
+      },
+      RenderEvent::BackgroundTile(column) => {
+        self.fetching = Fetching::Background;
+        self.tile_column = column;
+        self.in_split = self.split_active(column);
+      },
+      RenderEvent::Sprites { tall } => {
+        self.fetching = Fetching::Sprites;
+        self.tall_sprites = tall;
+      },
+      RenderEvent::FrameEnd => {
+        self.fetching = Fetching::Idle;
+        self.in_frame = false;
+      },
+    }
+  }
+
+  fn cpu_cycle(&mut self) {
+    for pulse in self.pulses.iter_mut() {
+      pulse.clock();
+    }
+    self.frame_divider += 1;
+    if self.frame_divider == FRAME_PERIOD {
+      self.frame_divider = 0;
+      for pulse in self.pulses.iter_mut() {
+        pulse.clock_frame();
+      }
+    }
+  }
+
+  fn irq_pending(&self) -> bool {
+    self.irq_enabled && self.irq_pending
+  }
+
+  /// The pulses and PCM channel at equal weight
+  fn audio_sample(&self) -> f32 {
+    let pulses = (self.pulses[0].output() + self.pulses[1].output()) as f32 / MAX_PULSE_OUTPUT;
+    (pulses + self.pcm as f32 / 255.0) / 2.0
+  }
+
+  /// Registers go back to their power on values, ExRAM keeps its contents
+  fn reset(&mut self, _kind: ResetKind) {
+    let ex_ram = self.ex_ram;
+    *self = Self::new(self.prg_rom_size, self.prg_ram_size, self.chr_size, self.nametable_mapping);
+    self.ex_ram = ex_ram;
+  }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    state.write_u8(self.prg_mode);
+    state.write_u8(self.chr_mode);
+    state.write_bytes(&self.prg_ram_protect);
+    state.write_u8(self.ex_ram_mode);
+    state.write_u8(self.nametable_mapping);
+    state.write_u8(self.fill_tile);
+    state.write_u8(self.fill_color);
+    state.write_bytes(&self.prg_regs);
+    for reg in self.chr_regs.iter() {
+      state.write_u16(*reg);
+    }
+    state.write_u8(self.chr_upper);
+    state.write_bool(self.background_set_written_last);
+    state.write_u8(self.split_control);
+    state.write_u8(self.split_scroll);
+    state.write_u8(self.split_bank);
+    state.write_u8(self.irq_compare);
+    state.write_bool(self.irq_enabled);
+    state.write_bool(self.irq_pending);
+    state.write_bool(self.in_frame);
+    state.write_u8(self.irq_counter);
+    state.write_u8(self.multiplicand);
+    state.write_u8(self.multiplier);
+    state.write_bytes(&self.ex_ram);
+    state.write_u16(self.scanline);
+    for pulse in self.pulses.iter() {
+      pulse.save_state(state);
+    }
+    state.write_u16(self.frame_divider);
+    state.write_u8(self.pcm_mode);
+    state.write_u8(self.pcm);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.prg_mode = state.read_u8()?;
+    self.chr_mode = state.read_u8()?;
+    state.read_bytes(&mut self.prg_ram_protect)?;
+    self.ex_ram_mode = state.read_u8()?;
+    self.nametable_mapping = state.read_u8()?;
+    self.fill_tile = state.read_u8()?;
+    self.fill_color = state.read_u8()?;
+    state.read_bytes(&mut self.prg_regs)?;
+    for reg in self.chr_regs.iter_mut() {
+      *reg = state.read_u16()?;
+    }
+    self.chr_upper = state.read_u8()?;
+    self.background_set_written_last = state.read_bool()?;
+    self.split_control = state.read_u8()?;
+    self.split_scroll = state.read_u8()?;
+    self.split_bank = state.read_u8()?;
+    self.irq_compare = state.read_u8()?;
+    self.irq_enabled = state.read_bool()?;
+    self.irq_pending = state.read_bool()?;
+    self.in_frame = state.read_bool()?;
+    self.irq_counter = state.read_u8()?;
+    self.multiplicand = state.read_u8()?;
+    self.multiplier = state.read_u8()?;
+    state.read_bytes(&mut self.ex_ram)?;
+    self.scanline = state.read_u16()?;
+    for pulse in self.pulses.iter_mut() {
+      pulse.load_state(state)?;
+    }
+    self.frame_divider = state.read_u16()? % FRAME_PERIOD;
+    self.pcm_mode = state.read_u8()?;
+    self.pcm = state.read_u8()?;
+    self.fetching = Fetching::Idle;
+    Ok(())
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::mappers::tests::{banked_rom, chr_bank};
+
+  fn prg(mapper: &Mapper, addr: u16) -> PrgBank {
+    match mapper.map_peak(addr) {
+      MappedRead::PrgROM(index) => PrgBank::Rom(index / 0x2000),
+      MappedRead::PrgRAM(index) => PrgBank::Ram(index / 0x2000),
+      _ => panic!("not PRG"),
+    }
+  }
+
+  #[test]
+  fn test_prg_modes() {
+    let mut mapper = banked_rom(5).mapper;
+
+    // Mode 3 with $5117 at 0xFF, the last bank on top
+    assert_eq!(prg(&mapper, 0xE000), PrgBank::Rom(15));
+
+    mapper.map_write(0x5100, 1);
+    mapper.map_write(0x5115, 0x85);
+    mapper.map_write(0x5117, 0x0F);
+    assert_eq!(prg(&mapper, 0x8000), PrgBank::Rom(4));
+    assert_eq!(prg(&mapper, 0xA000), PrgBank::Rom(5));
+    assert_eq!(prg(&mapper, 0xC000), PrgBank::Rom(14));
+    assert_eq!(prg(&mapper, 0xE000), PrgBank::Rom(15));
+
+    // RAM can be banked into the ROM area, and is only writable once both protect registers agree
+    mapper.map_write(0x5100, 3);
+    mapper.map_write(0x5114, 0x02);
+    mapper.map_write(0x5113, 0x01);
+    assert_eq!(prg(&mapper, 0x8000), PrgBank::Ram(2));
+    assert_eq!(prg(&mapper, 0x6000), PrgBank::Ram(1));
+    assert!(matches!(mapper.map_write(0x8000, 0x12), MappedWrite::None));
+    mapper.map_write(0x5102, 0x02);
+    mapper.map_write(0x5103, 0x01);
+    assert!(matches!(mapper.map_write(0x8001, 0x12), MappedWrite::PrgRAM(0x4001, 0x12)));
+  }
+
+  #[test]
+  fn test_sprite_and_background_chr_sets() {
+    let mut mapper = banked_rom(5).mapper;
+    mapper.map_write(0x5101, 1);
+    mapper.map_write(0x5123, 3);
+    mapper.map_write(0x5127, 4);
+    mapper.map_write(0x512B, 9);
+
+    // 8x8 sprites use the set written last for everything
+    assert_eq!(chr_bank(&mapper, 0x1000), 9);
+    mapper.render_event(RenderEvent::Sprites { tall: false });
+    assert_eq!(chr_bank(&mapper, 0x0000), 9);
+
+    mapper.render_event(RenderEvent::Sprites { tall: true });
+    assert_eq!((chr_bank(&mapper, 0x0000), chr_bank(&mapper, 0x1000)), (3, 4));
+    mapper.render_event(RenderEvent::BackgroundTile(0));
+    assert_eq!((chr_bank(&mapper, 0x0000), chr_bank(&mapper, 0x1000)), (9, 9));
+  }
+
+  #[test]
+  fn test_scanline_irq() {
+    let mut mapper = banked_rom(5).mapper;
+    mapper.map_write(0x5203, 3);
+    mapper.map_write(0x5204, 0x80);
+
+    for scanline in 0..3 {
+      mapper.render_event(RenderEvent::Scanline(scanline));
+      assert!(!mapper.irq_pending());
+    }
+    mapper.render_event(RenderEvent::Scanline(3));
+    assert!(mapper.irq_pending());
+    assert!(matches!(mapper.map_read(0x5204), MappedRead::Data(0xC0)));
+    assert!(!mapper.irq_pending());
+
+    mapper.render_event(RenderEvent::FrameEnd);
+    assert!(matches!(mapper.map_read(0x5204), MappedRead::Data(0x00)));
+  }
+
+  #[test]
+  fn test_nametable_sources() {
+    let mut mapper = banked_rom(5).mapper;
+    // CIRAM page 1, page 0, ExRAM and fill mode
+    mapper.map_write(0x5105, 0b11_10_00_01);
+    mapper.map_write(0x5106, 0x42);
+    mapper.map_write(0x5107, 0x02);
+    mapper.map_write(0x5C10, 0x99);
+
+    assert!(matches!(mapper.map_nametable(0x2010), MappedRead::Vram(0x410)));
+    assert!(matches!(mapper.map_nametable(0x2410), MappedRead::Vram(0x010)));
+    assert!(matches!(mapper.map_nametable(0x2810), MappedRead::Data(0x99)));
+    assert!(matches!(mapper.map_nametable(0x2C10), MappedRead::Data(0x42)));
+    assert!(matches!(mapper.map_nametable(0x2FC0), MappedRead::Data(0xAA)));
+  }
+
+  #[test]
+  fn test_extended_attributes() {
+    let mut mapper = banked_rom(5).mapper;
+    mapper.map_write(0x5104, 1);
+    mapper.map_write(0x5C05, 0xC7);
+
+    mapper.render_event(RenderEvent::BackgroundTile(5));
+    mapper.ppu_fetch(0x2005);
+    assert!(matches!(mapper.map_nametable(0x23C1), MappedRead::Data(0xFF)));
+    assert_eq!(chr_bank(&mapper, 0x1010), 7);
+
+    // Sprites go through the normal banks
+    mapper.render_event(RenderEvent::Sprites { tall: false });
+    assert_eq!(chr_bank(&mapper, 0x0010), 0);
+  }
+
+  #[test]
+  fn test_vertical_split() {
+    let mut mapper = banked_rom(5).mapper;
+    // Left of column 4, scrolled down by 10 and using CHR bank 6
+    mapper.map_write(0x5200, 0x84);
+    mapper.map_write(0x5201, 10);
+    mapper.map_write(0x5202, 6);
+    mapper.map_write(0x5C00 + 2 * 32 + 3, 0x77);
+
+    mapper.render_event(RenderEvent::Scanline(8));
+    mapper.render_event(RenderEvent::BackgroundTile(3));
+    assert!(matches!(mapper.map_nametable(0x2000 + 32 + 3), MappedRead::Data(0x77)));
+    match mapper.map_peak(0x0770) {
+      MappedRead::Chr(index) => assert_eq!(index, 6 * 0x1000 + 0x0772),
+      _ => panic!("not CHR"),
+    }
+
+    mapper.render_event(RenderEvent::BackgroundTile(4));
+    assert!(matches!(mapper.map_nametable(0x2000 + 32 + 4), MappedRead::Vram(0x024)));
+  }
+
+  #[test]
+  fn test_multiplier() {
+    let mut mapper = banked_rom(5).mapper;
+    mapper.map_write(0x5205, 200);
+    mapper.map_write(0x5206, 150);
+    assert!(matches!(mapper.map_read(0x5205), MappedRead::Data(0x30)));
+    assert!(matches!(mapper.map_read(0x5206), MappedRead::Data(0x75)));
+  }
+
+  #[test]
+  fn test_pulse_duty() {
+    let mut mapper = banked_rom(5).mapper;
+    // 50% duty at constant volume 15, period 0 so every other cycle is a step
+    mapper.map_write(0x5015, 0x01);
+    mapper.map_write(0x5000, 0xBF);
+    mapper.map_write(0x5002, 0x00);
+    mapper.map_write(0x5003, 0x08);
+    assert!(matches!(mapper.map_peak(0x5015), MappedRead::Data(0x01)));
+
+    let mut high = 0;
+    for _ in 0..16 {
+      mapper.cpu_cycle();
+      if mapper.audio_sample() > 0.0 {
+        high += 1;
+      }
+    }
+    assert_eq!(high, 8);
+
+    // Disabling clears the length counter, silencing it
+    mapper.map_write(0x5015, 0x00);
+    assert!(matches!(mapper.map_peak(0x5015), MappedRead::Data(0x00)));
+    assert_eq!(mapper.audio_sample(), 0.0);
+  }
+
+  #[test]
+  fn test_raw_pcm() {
+    let mut mapper = banked_rom(5).mapper;
+    mapper.map_write(0x5011, 0xFF);
+    assert_eq!(mapper.audio_sample(), 0.5);
+
+    // Zero is ignored, and nothing is written in read mode
+    mapper.map_write(0x5011, 0x00);
+    assert_eq!(mapper.audio_sample(), 0.5);
+    mapper.map_write(0x5010, 0x01);
+    mapper.map_write(0x5011, 0x10);
+    assert_eq!(mapper.audio_sample(), 0.5);
+  }
+
+}
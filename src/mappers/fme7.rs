@@ -0,0 +1,448 @@
+use crate::cpu::ResetKind;
+use crate::{rom::{ROM, ScreenMirroring}, mem::Membank};
+use crate::savestate::{StateReader, StateWriter};
+
+use super::{Map, Mapper, MappedRead, MappedWrite};
+
+const CHR_START: usize = 0x0000;
+const CHR_END: usize = 0x1FFF;
+const PRG_RAM_START: usize = 0x6000;
+const PRG_RAM_END: usize = 0x7FFF;
+const PRG_ROM_START: usize = 0x8000;
+const PRG_ROM_END: usize = 0xFFFF;
+
+/// Envelope steps, twice the AY-3-8910's 16
+const ENVELOPE_STEPS: u8 = 32;
+
+/// Amplitude of one of the 32 output levels, 1.5 dB apart
+fn amplitude(level: u8) -> f32 {
+  if level == 0 { 0.0 } else { 10f32.powf((level as f32 - 31.0) * 1.5 / 20.0) }
+}
+
+/// The Sunsoft 5B's sound, a YM2149 with three square channels, a noise generator and
+/// an envelope, any of which can be mixed into each channel. Registers are written
+/// by selecting one at $C000 and writing it at $E000.
+#[derive(Debug, Clone)]
+struct Sunsoft5B {
+  select: u8,
+  regs: [u8; 16],
+  tone_dividers: [u32; 3],
+  tone_high: [bool; 3],
+  noise_divider: u32,
+  /// 17-bit shift register, its low bit is the noise output
+  noise: u32,
+  envelope_divider: u32,
+  envelope_step: u8,
+  envelope_rising: bool,
+  envelope_holding: bool,
+}
+
+impl Sunsoft5B {
+
+  fn new() -> Self {
+    Self {
+      select: 0,
+      regs: [0; 16],
+      tone_dividers: [0; 3],
+      tone_high: [false; 3],
+      noise_divider: 0,
+      noise: 1,
+      envelope_divider: 0,
+      envelope_step: 0,
+      envelope_rising: false,
+      envelope_holding: false,
+    }
+  }
+
+  fn write(&mut self, data: u8) {
+    let reg = self.select as usize & 0x0F;
+    self.regs[reg] = data;
+    // Writing the shape restarts the envelope
+    if reg == 0x0D {
+      self.envelope_step = 0;
+      self.envelope_rising = data & 0b0100 != 0;
+      self.envelope_holding = false;
+      self.envelope_divider = 0;
+    }
+  }
+
+  fn tone_period(&self, channel: usize) -> u32 {
+    (self.regs[channel * 2] as u32 | ((self.regs[channel * 2 + 1] & 0x0F) as u32) << 8).max(1)
+  }
+
+  fn clock(&mut self) {
+
+    // Squares at CPU clock / (32 * period)
+    for channel in 0..3 {
+      if self.tone_dividers[channel] == 0 {
+        self.tone_dividers[channel] = self.tone_period(channel) * 16 - 1;
+        self.tone_high[channel] = !self.tone_high[channel];
+      } else {
+        self.tone_dividers[channel] -= 1;
+      }
+    }
+
+    if self.noise_divider == 0 {
+      self.noise_divider = (self.regs[6] as u32 & 0x1F).max(1) * 16 - 1;
+      let feedback = (self.noise ^ self.noise >> 3) & 1;
+      self.noise = self.noise >> 1 | feedback << 16;
+    } else {
+      self.noise_divider -= 1;
+    }
+
+    if self.envelope_divider == 0 {
+      let period = (self.regs[0x0B] as u32 | (self.regs[0x0C] as u32) << 8).max(1);
+      self.envelope_divider = period * 8 - 1;
+      self.step_envelope();
+    } else {
+      self.envelope_divider -= 1;
+    }
+
+  }
+
+  fn step_envelope(&mut self) {
+    if self.envelope_holding {
+      return;
+    }
+    self.envelope_step += 1;
+    if self.envelope_step < ENVELOPE_STEPS {
+      return;
+    }
+
+    // Shape bits: continue, attack, alternate, hold
+    let shape = self.regs[0x0D];
+    let (continues, alternate, hold) = (shape & 0b1000 != 0, shape & 0b0010 != 0, shape & 0b0001 != 0);
+    if !continues {
+      self.envelope_rising = false;
+      self.envelope_holding = true;
+      self.envelope_step = ENVELOPE_STEPS - 1;
+    } else if hold {
+      if alternate {
+        self.envelope_rising = !self.envelope_rising;
+      }
+      self.envelope_holding = true;
+      self.envelope_step = ENVELOPE_STEPS - 1;
+    } else {
+      if alternate {
+        self.envelope_rising = !self.envelope_rising;
+      }
+      self.envelope_step = 0;
+    }
+  }
+
+  fn envelope_level(&self) -> u8 {
+    if self.envelope_rising { self.envelope_step } else { ENVELOPE_STEPS - 1 - self.envelope_step }
+  }
+
+  fn output(&self) -> f32 {
+    let mixer = self.regs[7];
+    let noise_high = self.noise & 1 != 0;
+    let mut sum = 0.0;
+
+    for channel in 0..3 {
+      // The mixer bits disable rather than enable
+      let tone = self.tone_high[channel] || mixer >> channel & 1 != 0;
+      let noise = noise_high || mixer >> (channel + 3) & 1 != 0;
+      if !(tone && noise) {
+        continue;
+      }
+      let volume = self.regs[8 + channel];
+      let level = match volume {
+        volume if volume & 0x10 != 0 => self.envelope_level(),
+        0 => 0,
+        volume => (volume & 0x0F) * 2 + 1,
+      };
+      sum += amplitude(level);
+    }
+
+    sum / 3.0
+  }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    state.write_u8(self.select);
+    state.write_bytes(&self.regs);
+    for channel in 0..3 {
+      state.write_usize(self.tone_dividers[channel] as usize);
+      state.write_bool(self.tone_high[channel]);
+    }
+    state.write_usize(self.noise_divider as usize);
+    state.write_usize(self.noise as usize);
+    state.write_usize(self.envelope_divider as usize);
+    state.write_u8(self.envelope_step);
+    state.write_bool(self.envelope_rising);
+    state.write_bool(self.envelope_holding);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.select = state.read_u8()?;
+    state.read_bytes(&mut self.regs)?;
+    for channel in 0..3 {
+      self.tone_dividers[channel] = state.read_usize()? as u32;
+      self.tone_high[channel] = state.read_bool()?;
+    }
+    self.noise_divider = state.read_usize()? as u32;
+    self.noise = state.read_usize()? as u32;
+    self.envelope_divider = state.read_usize()? as u32;
+    self.envelope_step = state.read_u8()?.min(ENVELOPE_STEPS - 1);
+    self.envelope_rising = state.read_bool()?;
+    self.envelope_holding = state.read_bool()?;
+    Ok(())
+  }
+
+}
+
+/// Sunsoft FME-7 and 5B (mapper 69), used by Batman: Return of the Joker and Gimmick!.
+/// A command register selects what the parameter register sets: eight 1 KB CHR banks,
+/// four 8 KB PRG banks (the one at 0x6000 can be RAM), mirroring and a 16-bit IRQ counter
+/// that runs down every CPU cycle. The 5B adds the Sunsoft 5B sound.
+pub struct FME7 {
+  mirroring: ScreenMirroring,
+  command: u8,
+  chr_regs: [u8; 8],
+  /// 0x6000 first, then 0x8000, 0xA000 and 0xC000
+  prg_regs: [u8; 4],
+  irq_enabled: bool,
+  counter_enabled: bool,
+  irq_counter: u16,
+  irq_pending: bool,
+  audio: Sunsoft5B,
+  prg_rom_banks: Membank,
+  chr_banks: Membank,
+  prg_rom_size: usize,
+}
+
+impl FME7 {
+
+  pub fn load(rom: &ROM) -> Mapper {
+
+    let chr_size = if rom.has_chr_rom() { rom.chr_rom.len() } else { rom.chr_ram.len() };
+
+    let mut fme7 = Self {
+      mirroring: rom.header.mirroring,
+      command: 0,
+      chr_regs: [0; 8],
+      prg_regs: [0; 4],
+      irq_enabled: false,
+      counter_enabled: false,
+      irq_counter: 0,
+      irq_pending: false,
+      audio: Sunsoft5B::new(),
+      prg_rom_banks: Membank::new(PRG_ROM_START, PRG_ROM_END, rom.prg_rom.len(), 0x2000),
+      chr_banks: Membank::new(CHR_START, CHR_END, chr_size, 0x400),
+      prg_rom_size: rom.prg_rom.len().max(1),
+    };
+
+    fme7.update_banks();
+    fme7.into()
+
+  }
+
+  fn update_banks(&mut self) {
+    for slot in 0..3 {
+      self.prg_rom_banks.set(slot, self.prg_regs[slot + 1] as usize & 0x3F);
+    }
+    let last = self.prg_rom_banks.last();
+    self.prg_rom_banks.set(3, last);
+
+    for (slot, bank) in self.chr_regs.iter().enumerate() {
+      self.chr_banks.set(slot, *bank as usize);
+    }
+  }
+
+  fn write_parameter(&mut self, data: u8) {
+    match self.command {
+      0x0..=0x7 => self.chr_regs[self.command as usize] = data,
+      0x8..=0xB => self.prg_regs[self.command as usize - 8] = data,
+      0xC => {
+        self.mirroring = match data & 0b11 {
+          0 => ScreenMirroring::Vertical,
+          1 => ScreenMirroring::Horizontal,
+          2 => ScreenMirroring::SingleScreenLower,
+          _ => ScreenMirroring::SingleScreenUpper,
+        };
+      },
+      0xD => {
+        self.irq_enabled = data & 0x01 != 0;
+        self.counter_enabled = data & 0x80 != 0;
+        self.irq_pending = false;
+      },
+      0xE => self.irq_counter = self.irq_counter & 0xFF00 | data as u16,
+      _ => self.irq_counter = self.irq_counter & 0x00FF | (data as u16) << 8,
+    }
+    self.update_banks();
+  }
+
+}
+
+impl Map for FME7 {
+
+  fn mirroring(&self) -> ScreenMirroring { self.mirroring }
+
+  fn set_mirroring(&mut self, mirroring: ScreenMirroring) { self.mirroring = mirroring; }
+
+  fn map_peak(&self, addr: u16) -> MappedRead {
+    match addr as usize {
+      CHR_START..=CHR_END => MappedRead::Chr(self.chr_banks.translate(addr)),
+      PRG_RAM_START..=PRG_RAM_END => {
+        // Bit 6 picks RAM over ROM, bit 7 enables the RAM
+        let bank = self.prg_regs[0];
+        match bank & 0xC0 {
+          0xC0 => MappedRead::PrgRAM(addr as usize & 0x1FFF),
+          0x40 => MappedRead::None,
+          _ => MappedRead::PrgROM(((bank as usize & 0x3F) * 0x2000 + (addr as usize & 0x1FFF)) % self.prg_rom_size),
+        }
+      },
+      PRG_ROM_START..=PRG_ROM_END => MappedRead::PrgROM(self.prg_rom_banks.translate(addr)),
+      _ => MappedRead::None,
+    }
+  }
+
+  fn map_write(&mut self, addr: u16, data: u8) -> MappedWrite {
+    match addr as usize {
+      CHR_START..=CHR_END => MappedWrite::Chr(self.chr_banks.translate(addr), data),
+      PRG_RAM_START..=PRG_RAM_END if self.prg_regs[0] & 0xC0 == 0xC0 => MappedWrite::PrgRAM(addr as usize & 0x1FFF, data),
+      PRG_ROM_START..=PRG_ROM_END => {
+        match addr & 0xE000 {
+          0x8000 => self.command = data & 0x0F,
+          0xA000 => self.write_parameter(data),
+          0xC000 => self.audio.select = data,
+          _ => self.audio.write(data),
+        }
+        MappedWrite::None
+      },
+      _ => MappedWrite::None,
+    }
+  }
+
+  fn cpu_cycle(&mut self) {
+    if self.counter_enabled {
+      self.irq_counter = self.irq_counter.wrapping_sub(1);
+      if self.irq_counter == 0xFFFF && self.irq_enabled {
+        self.irq_pending = true;
+      }
+    }
+    self.audio.clock();
+  }
+
+  fn irq_pending(&self) -> bool {
+    self.irq_pending
+  }
+
+  fn audio_sample(&self) -> f32 {
+    self.audio.output()
+  }
+
+  fn reset(&mut self, _kind: ResetKind) {
+    self.command = 0;
+    self.chr_regs = [0; 8];
+    self.prg_regs = [0; 4];
+    self.irq_enabled = false;
+    self.counter_enabled = false;
+    self.irq_pending = false;
+    self.audio = Sunsoft5B::new();
+    self.update_banks();
+  }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    state.write_mirroring(self.mirroring);
+    state.write_u8(self.command);
+    state.write_bytes(&self.chr_regs);
+    state.write_bytes(&self.prg_regs);
+    state.write_bool(self.irq_enabled);
+    state.write_bool(self.counter_enabled);
+    state.write_u16(self.irq_counter);
+    state.write_bool(self.irq_pending);
+    self.audio.save_state(state);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.mirroring = state.read_mirroring()?;
+    self.command = state.read_u8()?;
+    state.read_bytes(&mut self.chr_regs)?;
+    state.read_bytes(&mut self.prg_regs)?;
+    self.irq_enabled = state.read_bool()?;
+    self.counter_enabled = state.read_bool()?;
+    self.irq_counter = state.read_u16()?;
+    self.irq_pending = state.read_bool()?;
+    self.audio.load_state(state)?;
+    self.update_banks();
+    Ok(())
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::mappers::tests::banked_rom;
+
+  fn command(mapper: &mut Mapper, command: u8, parameter: u8) {
+    mapper.map_write(0x8000, command);
+    mapper.map_write(0xA000, parameter);
+  }
+
+  #[test]
+  fn test_prg_banks() {
+    let mut mapper = banked_rom(69).mapper;
+    let bank = |mapper: &Mapper, addr| match mapper.map_peak(addr) {
+      MappedRead::PrgROM(index) => Some(index / 0x2000),
+      MappedRead::PrgRAM(_) => None,
+      _ => panic!("not PRG"),
+    };
+
+    command(&mut mapper, 0x9, 4);
+    command(&mut mapper, 0xB, 6);
+    command(&mut mapper, 0x8, 2);
+    assert_eq!([0x6000, 0x8000, 0xC000, 0xE000].map(|addr| bank(&mapper, addr)), [Some(2), Some(4), Some(6), Some(15)]);
+
+    // RAM at 0x6000 only takes writes once it's selected and enabled
+    assert!(matches!(mapper.map_write(0x6000, 1), MappedWrite::None));
+    command(&mut mapper, 0x8, 0xC0);
+    assert_eq!(bank(&mapper, 0x6000), None);
+    assert!(matches!(mapper.map_write(0x6000, 1), MappedWrite::PrgRAM(0, 1)));
+  }
+
+  #[test]
+  fn test_irq_counter() {
+    let mut mapper = banked_rom(69).mapper;
+    command(&mut mapper, 0xE, 2);
+    command(&mut mapper, 0xF, 0);
+    command(&mut mapper, 0xD, 0x81);
+
+    // Fires when it wraps from 0 to 0xFFFF
+    for _ in 0..2 {
+      mapper.cpu_cycle();
+    }
+    assert!(!mapper.irq_pending());
+    mapper.cpu_cycle();
+    assert!(mapper.irq_pending());
+
+    command(&mut mapper, 0xD, 0x00);
+    assert!(!mapper.irq_pending());
+  }
+
+  #[test]
+  fn test_square_channel() {
+    let mut mapper = banked_rom(69).mapper;
+    let write = |mapper: &mut Mapper, reg, data| {
+      mapper.map_write(0xC000, reg);
+      mapper.map_write(0xE000, data);
+    };
+    // Channel A at period 1 and full volume, everything else muted
+    write(&mut mapper, 0x0, 1);
+    write(&mut mapper, 0x7, 0b111_110);
+    write(&mut mapper, 0x8, 0x0F);
+
+    let mut samples = vec![];
+    for _ in 0..64 {
+      mapper.cpu_cycle();
+      samples.push(mapper.audio_sample());
+    }
+    // 16 cycles high, 16 low
+    assert_eq!(samples.iter().filter(|sample| **sample > 0.0).count(), 32);
+    assert_eq!(samples[0], 1.0 / 3.0);
+    assert_eq!(samples[16], 0.0);
+  }
+
+}
@@ -0,0 +1,144 @@
+use crate::cpu::ResetKind;
+use crate::{rom::{ROM, ScreenMirroring}, mem::Membank};
+use crate::savestate::{StateReader, StateWriter};
+
+use super::pxrom::{ChrLatches, write_register};
+use super::{Map, Mapper, MappedRead, MappedWrite};
+
+const CHR_START: usize = 0x0000;
+const CHR_END: usize = 0x1FFF;
+const PRG_RAM_START: usize = 0x6000;
+const PRG_RAM_END: usize = 0x7FFF;
+const PRG_ROM_START: usize = 0x8000;
+const PRG_ROM_END: usize = 0xFFFF;
+
+/// MMC4 (mapper 10), used by the Japanese Fire Emblem games. MMC2's latched CHR banks,
+/// with a switchable 16 KB PRG bank at $8000, the last 16 KB fixed and 8 KB of PRG-RAM.
+pub struct FXROM {
+  mirroring: ScreenMirroring,
+  prg_bank: u8,
+  latches: ChrLatches,
+  prg_rom_banks: Membank,
+  chr_banks: Membank,
+}
+
+impl FXROM {
+
+  pub fn load(rom: &ROM) -> Mapper {
+
+    let chr_size = if rom.has_chr_rom() { rom.chr_rom.len() } else { rom.chr_ram.len() };
+
+    let mut fxrom = Self {
+      mirroring: rom.header.mirroring,
+      prg_bank: 0,
+      latches: ChrLatches::new(),
+      prg_rom_banks: Membank::new(PRG_ROM_START, PRG_ROM_END, rom.prg_rom.len(), 0x4000),
+      chr_banks: Membank::new(CHR_START, CHR_END, chr_size, 0x1000),
+    };
+
+    fxrom.update_banks();
+    fxrom.into()
+
+  }
+
+  fn update_banks(&mut self) {
+    let last = self.prg_rom_banks.last();
+    self.prg_rom_banks.set(0, self.prg_bank as usize);
+    self.prg_rom_banks.set(1, last);
+    self.latches.apply(&mut self.chr_banks);
+  }
+
+}
+
+impl Map for FXROM {
+
+  fn mirroring(&self) -> ScreenMirroring { self.mirroring }
+
+  fn set_mirroring(&mut self, mirroring: ScreenMirroring) { self.mirroring = mirroring; }
+
+  fn map_peak(&self, addr: u16) -> MappedRead {
+    match addr as usize {
+      CHR_START..=CHR_END => MappedRead::Chr(self.chr_banks.translate(addr)),
+      PRG_RAM_START..=PRG_RAM_END => MappedRead::PrgRAM(addr as usize & 0x1FFF),
+      PRG_ROM_START..=PRG_ROM_END => MappedRead::PrgROM(self.prg_rom_banks.translate(addr)),
+      _ => MappedRead::None,
+    }
+  }
+
+  fn map_write(&mut self, addr: u16, data: u8) -> MappedWrite {
+    match addr as usize {
+      CHR_START..=CHR_END => MappedWrite::Chr(self.chr_banks.translate(addr), data),
+      PRG_RAM_START..=PRG_RAM_END => MappedWrite::PrgRAM(addr as usize & 0x1FFF, data),
+      PRG_ROM_START..=PRG_ROM_END => {
+        if let Some(bank) = write_register(addr, data, &mut self.latches, &mut self.mirroring) {
+          self.prg_bank = bank;
+        }
+        self.update_banks();
+        MappedWrite::None
+      },
+      _ => MappedWrite::None,
+    }
+  }
+
+  fn ppu_fetch(&mut self, addr: u16) {
+    self.latches.fetch(addr, true);
+    self.latches.apply(&mut self.chr_banks);
+  }
+
+  fn reset(&mut self, _kind: ResetKind) {
+    self.prg_bank = 0;
+    self.latches = ChrLatches::new();
+    self.update_banks();
+  }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    state.write_mirroring(self.mirroring);
+    state.write_u8(self.prg_bank);
+    self.latches.save_state(state);
+    self.prg_rom_banks.save_state(state);
+    self.chr_banks.save_state(state);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.mirroring = state.read_mirroring()?;
+    self.prg_bank = state.read_u8()?;
+    self.latches.load_state(state)?;
+    self.prg_rom_banks.load_state(state)?;
+    self.chr_banks.load_state(state)
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::mappers::tests::{banked_rom, chr_bank};
+
+  #[test]
+  fn test_prg_banks() {
+    let mut mapper = banked_rom(10).mapper;
+    mapper.map_write(0xA000, 3);
+
+    let bank = |mapper: &Mapper, addr| match mapper.map_peak(addr) {
+      MappedRead::PrgROM(index) => index / 0x4000,
+      _ => panic!("not PRG-ROM"),
+    };
+    assert_eq!(bank(&mapper, 0x8000), 3);
+    assert_eq!(bank(&mapper, 0xBFFF), 3);
+    assert_eq!(bank(&mapper, 0xC000), 7);
+  }
+
+  #[test]
+  fn test_lower_latch_watches_whole_tile() {
+    let mut mapper = banked_rom(10).mapper;
+    mapper.map_write(0xB000, 1);
+    mapper.map_write(0xC000, 2);
+
+    mapper.ppu_fetch(0x0FDF);
+    assert_eq!(chr_bank(&mapper, 0x0000), 1);
+    mapper.ppu_fetch(0x0FEA);
+    assert_eq!(chr_bank(&mapper, 0x0000), 2);
+  }
+
+}
@@ -1,11 +1,31 @@
+use crate::cpu::ResetKind;
 use crate::rom::ScreenMirroring;
+use crate::savestate::{StateReader, StateWriter};
 
+pub mod exrom;
+pub mod fme7;
+pub mod fxrom;
+pub mod namco163;
 pub mod nrom;
+pub mod pxrom;
+pub mod registry;
 pub mod txrom;
+pub mod vrc4;
+pub mod vrc6;
+pub mod vrc7;
+mod vrc_irq;
 
 use enum_dispatch::enum_dispatch;
+use exrom::EXROM;
+use fme7::FME7;
+use fxrom::FXROM;
+use namco163::Namco163;
 use nrom::NROM;
+use pxrom::PXROM;
 use txrom::TXROM;
+use vrc4::VRC4;
+use vrc6::VRC6;
+use vrc7::VRC7;
 
 
 pub enum MappedRead {
@@ -14,12 +34,48 @@ pub enum MappedRead {
   Data(u8),
   PrgROM(usize),
   PrgRAM(usize),
+  /// An index into the console's 2 KB of nametable RAM
+  Vram(usize),
 }
 
 pub enum MappedWrite {
   None,
   Chr(usize, u8),
   PrgRAM(usize, u8),
+  Vram(usize, u8),
+}
+
+/// What the PPU is doing while it renders, for mappers that follow along. Real chips work
+/// these out by watching the PPU's address bus, which the scanline renderer doesn't reproduce.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RenderEvent {
+  /// A visible scanline starts with rendering enabled
+  Scanline(u16),
+  /// The background tile in this column of the scanline is fetched next
+  BackgroundTile(u8),
+  /// The scanline's sprites are fetched next, `tall` when PPUCTRL selects 8x16 sprites
+  Sprites { tall: bool },
+  /// The picture is over for this frame, either at vblank or because rendering was switched off
+  FrameEnd,
+}
+
+/// Index into the console's nametable RAM for a PPU address in 0x2000-0x2FFF.
+/// Four-screen carts provide the other 2 KB themselves, which lands past the end of it.
+pub fn mirror_nametable(mirroring: ScreenMirroring, addr: u16) -> usize {
+
+  let vram_index = (addr & 0x0FFF) as usize;
+  let name_table = vram_index / 0x0400;
+
+  match (mirroring, name_table) {
+    (ScreenMirroring::Vertical, 2) => vram_index - 0x0800,
+    (ScreenMirroring::Vertical, 3) => vram_index - 0x0800,
+    (ScreenMirroring::Horizontal, 1) => vram_index - 0x0400,
+    (ScreenMirroring::Horizontal, 2) => vram_index - 0x0400,
+    (ScreenMirroring::Horizontal, 3) => vram_index - 0x0800,
+    (ScreenMirroring::SingleScreenLower, _) => vram_index & 0x03FF,
+    (ScreenMirroring::SingleScreenUpper, _) => 0x0400 | vram_index & 0x03FF,
+    _ => vram_index
+  }
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -28,6 +84,14 @@ pub enum Mapper {
   Empty,
   NROM,
   TXROM,
+  PXROM,
+  FXROM,
+  EXROM,
+  VRC4,
+  VRC6,
+  VRC7,
+  FME7,
+  Namco163,
 }
 
 
@@ -47,9 +111,57 @@ pub trait Map {
   fn map_read(&mut self, _addr: u16) -> MappedRead { self.map_peak(_addr) }
   fn map_peak(&self, _addr: u16) -> MappedRead { MappedRead::None }
   fn map_write(&mut self, _addr: u16, _data: u8) -> MappedWrite { MappedWrite::None }
+  /// Where a PPU access to the nametables at 0x2000-0x2FFF goes. Most boards only pick the mirroring.
+  fn map_nametable(&self, addr: u16) -> MappedRead { MappedRead::Vram(mirror_nametable(self.mirroring(), addr)) }
+  fn map_nametable_write(&mut self, addr: u16, data: u8) -> MappedWrite { MappedWrite::Vram(mirror_nametable(self.mirroring(), addr), data) }
+  /// Called after every pattern and nametable read the PPU makes, for mappers that watch the PPU address bus
+  fn ppu_fetch(&mut self, _addr: u16) {}
+  fn render_event(&mut self, _event: RenderEvent) {}
+  /// Called once per CPU cycle, for mappers with counters that run off the CPU clock
+  fn cpu_cycle(&mut self) {}
+  /// Whether the cartridge is pulling the CPU's IRQ line low
+  fn irq_pending(&self) -> bool { false }
+  /// The cartridge's own sound channels, between 0 and 1. The bus samples this every CPU cycle
+  /// for `Bus::take_audio_samples`.
+  fn audio_sample(&self) -> f32 { 0.0 }
+  /// Memory inside the mapper chip that a battery keeps, saved along with PRG-RAM
+  fn battery_ram(&self) -> &[u8] { &[] }
+  fn load_battery_ram(&mut self, _data: &[u8]) {}
+  fn reset(&mut self, _kind: ResetKind) {}
+  fn save_state(&self, _state: &mut StateWriter) {}
+  fn load_state(&mut self, _state: &mut StateReader) -> Result<(), String> { Ok(()) }
 
 }
 
 #[derive(Debug)]
 pub struct Empty;
-impl Map for Empty {}
\ No newline at end of file
+impl Map for Empty {}
+
+#[cfg(test)]
+pub(crate) mod tests {
+
+  use super::*;
+  use crate::rom::ROM;
+
+  /// A cart for `mapper` with 128 KB of PRG-ROM and CHR-ROM, where every byte
+  /// holds the number of its 8 KB PRG bank or 4 KB CHR bank
+  pub(crate) fn banked_rom(mapper: u8) -> ROM {
+    let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 0x08, 0x10, mapper << 4, mapper & 0xF0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    for bank in 0..16u8 {
+      bytes.extend(vec![bank; 0x2000]);
+    }
+    for bank in 0..32u8 {
+      bytes.extend(vec![bank; 0x1000]);
+    }
+    ROM::from_bytes("banked", &bytes).unwrap()
+  }
+
+  /// The 4 KB CHR bank `addr` is mapped to
+  pub(crate) fn chr_bank(mapper: &Mapper, addr: u16) -> usize {
+    match mapper.map_peak(addr) {
+      MappedRead::Chr(index) => index / 0x1000,
+      _ => panic!("not CHR"),
+    }
+  }
+
+}
@@ -0,0 +1,420 @@
+use crate::cpu::ResetKind;
+use crate::{rom::{ROM, ScreenMirroring}, mem::Membank};
+use crate::savestate::{StateReader, StateWriter};
+
+use super::{Map, Mapper, MappedRead, MappedWrite};
+
+const SOUND_RAM_SIZE: usize = 0x80;
+
+const CHR_START: usize = 0x0000;
+const CHR_END: usize = 0x1FFF;
+const PRG_RAM_START: usize = 0x6000;
+const PRG_RAM_END: usize = 0x7FFF;
+const PRG_ROM_START: usize = 0x8000;
+const PRG_ROM_END: usize = 0xFFFF;
+
+/// CHR and nametable bank numbers from here up select a page of the console's nametable RAM
+const CIRAM_BANKS: u8 = 0xE0;
+/// The IRQ counter stops and fires when it gets here
+const IRQ_COUNTER_MAX: u16 = 0x7FFF;
+/// The sound channels take turns, one every 15 CPU cycles
+const CHANNEL_UPDATE_CYCLES: u8 = 15;
+
+/// Namco 163 (mapper 19), used by Megami Tensei II and Rolling Thunder (J). Three
+/// switchable 8 KB PRG banks, eight 1 KB CHR banks and four nametables that can each
+/// point at CHR or the console's nametable RAM, and a 15-bit IRQ counter that counts up
+/// every CPU cycle. Its 128 bytes of internal RAM hold up to 8 wavetable sound channels,
+/// and on battery-backed boards save data too, so the RAM is saved with PRG-RAM.
+pub struct Namco163 {
+  sound_ram: [u8; SOUND_RAM_SIZE],
+  /// Address into the sound RAM for $4800, bit 7 increments it after each access
+  sound_addr: u8,
+  sound_disabled: bool,
+  /// Bits 6 and 7 stop the CHR banks at 0x0000 and 0x1000 from selecting nametable RAM
+  ciram_disable: u8,
+  ram_protect: u8,
+  prg_regs: [u8; 3],
+  chr_regs: [u8; 8],
+  nametable_regs: [u8; 4],
+  irq_counter: u16,
+  irq_enabled: bool,
+  irq_pending: bool,
+  channel_cycles: u8,
+  /// The channel the next update goes to, counting down from 7
+  channel: u8,
+  channel_outputs: [u8; 8],
+  prg_rom_banks: Membank,
+  chr_banks: Membank,
+  chr_size: usize,
+}
+
+impl Namco163 {
+
+  pub fn load(rom: &ROM) -> Mapper {
+
+    let chr_size = if rom.has_chr_rom() { rom.chr_rom.len() } else { rom.chr_ram.len() };
+
+    let mut namco163 = Self {
+      sound_ram: [0; SOUND_RAM_SIZE],
+      sound_addr: 0,
+      sound_disabled: false,
+      ciram_disable: 0,
+      ram_protect: 0,
+      prg_regs: [0; 3],
+      chr_regs: [0; 8],
+      nametable_regs: [CIRAM_BANKS; 4],
+      irq_counter: 0,
+      irq_enabled: false,
+      irq_pending: false,
+      channel_cycles: 0,
+      channel: 7,
+      channel_outputs: [0; 8],
+      prg_rom_banks: Membank::new(PRG_ROM_START, PRG_ROM_END, rom.prg_rom.len(), 0x2000),
+      chr_banks: Membank::new(CHR_START, CHR_END, chr_size, 0x400),
+      chr_size: chr_size.max(1),
+    };
+
+    namco163.update_banks();
+    namco163.into()
+
+  }
+
+  fn update_banks(&mut self) {
+    for (slot, bank) in self.prg_regs.iter().enumerate() {
+      self.prg_rom_banks.set(slot, *bank as usize);
+    }
+    let last = self.prg_rom_banks.last();
+    self.prg_rom_banks.set(3, last);
+
+    for (slot, bank) in self.chr_regs.iter().enumerate() {
+      self.chr_banks.set(slot, *bank as usize);
+    }
+  }
+
+  /// How many channels are playing, from the top of the sound RAM down
+  fn channel_count(&self) -> u8 {
+    (self.sound_ram[0x7F] >> 4 & 0b111) + 1
+  }
+
+  fn advance_sound_addr(&mut self) {
+    if self.sound_addr & 0x80 != 0 {
+      self.sound_addr = 0x80 | self.sound_addr.wrapping_add(1) & 0x7F;
+    }
+  }
+
+  /// Moves one channel's phase along its waveform. Each channel has 8 bytes of registers:
+  /// an 18-bit frequency and 24-bit phase interleaved, the waveform length, where the
+  /// waveform starts in 4-bit samples, and the volume.
+  fn update_channel(&mut self, channel: u8) {
+    let base = 0x40 + channel as usize * 8;
+    let regs = &mut self.sound_ram[base..base + 8];
+
+    let frequency = regs[0] as u32 | (regs[2] as u32) << 8 | (regs[4] as u32 & 0b11) << 16;
+    let length = 256 - (regs[4] & 0xFC) as u32;
+    let phase = regs[1] as u32 | (regs[3] as u32) << 8 | (regs[5] as u32) << 16;
+    let phase = (phase + frequency) % (length << 16);
+    regs[1] = phase as u8;
+    regs[3] = (phase >> 8) as u8;
+    regs[5] = (phase >> 16) as u8;
+
+    let sample_addr = (regs[6] as u32 + (phase >> 16)) as usize & 0xFF;
+    let volume = regs[7] & 0x0F;
+    let sample = self.sound_ram[sample_addr / 2] >> (sample_addr % 2 * 4) & 0x0F;
+    self.channel_outputs[channel as usize] = sample * volume;
+  }
+
+  fn chr_target(&self, addr: u16) -> Option<usize> {
+    let bank = self.chr_regs[addr as usize / 0x400];
+    let disabled = self.ciram_disable & if addr < 0x1000 { 0x40 } else { 0x80 } != 0;
+    if bank >= CIRAM_BANKS && !disabled {
+      Some((bank as usize & 1) * 0x400 + (addr as usize & 0x3FF))
+    } else {
+      None
+    }
+  }
+
+  fn prg_ram_writable(&self, addr: u16) -> bool {
+    let chunk = (addr as usize - PRG_RAM_START) / 0x800;
+    self.ram_protect & 0xF0 == 0x40 && self.ram_protect >> chunk & 1 == 0
+  }
+
+}
+
+impl Map for Namco163 {
+
+  fn mirroring(&self) -> ScreenMirroring { ScreenMirroring::Default }
+
+  fn map_read(&mut self, addr: u16) -> MappedRead {
+    let read = self.map_peak(addr);
+    if (0x4800..=0x4FFF).contains(&addr) {
+      self.advance_sound_addr();
+    }
+    read
+  }
+
+  fn map_peak(&self, addr: u16) -> MappedRead {
+    match addr as usize {
+      CHR_START..=CHR_END => match self.chr_target(addr) {
+        Some(index) => MappedRead::Vram(index),
+        None => MappedRead::Chr(self.chr_banks.translate(addr)),
+      },
+      0x4800..=0x4FFF => MappedRead::Data(self.sound_ram[self.sound_addr as usize & 0x7F]),
+      0x5000..=0x57FF => MappedRead::Data(self.irq_counter as u8),
+      0x5800..=0x5FFF => MappedRead::Data((self.irq_counter >> 8) as u8 | (self.irq_enabled as u8) << 7),
+      PRG_RAM_START..=PRG_RAM_END => MappedRead::PrgRAM(addr as usize & 0x1FFF),
+      PRG_ROM_START..=PRG_ROM_END => MappedRead::PrgROM(self.prg_rom_banks.translate(addr)),
+      _ => MappedRead::None,
+    }
+  }
+
+  fn map_write(&mut self, addr: u16, data: u8) -> MappedWrite {
+    match addr as usize {
+      CHR_START..=CHR_END => match self.chr_target(addr) {
+        Some(index) => return MappedWrite::Vram(index, data),
+        None => return MappedWrite::Chr(self.chr_banks.translate(addr), data),
+      },
+      0x4800..=0x4FFF => {
+        self.sound_ram[self.sound_addr as usize & 0x7F] = data;
+        self.advance_sound_addr();
+      },
+      0x5000..=0x57FF => {
+        self.irq_counter = self.irq_counter & 0x7F00 | data as u16;
+        self.irq_pending = false;
+      },
+      0x5800..=0x5FFF => {
+        self.irq_counter = self.irq_counter & 0x00FF | ((data & 0x7F) as u16) << 8;
+        self.irq_enabled = data & 0x80 != 0;
+        self.irq_pending = false;
+      },
+      PRG_RAM_START..=PRG_RAM_END if self.prg_ram_writable(addr) => return MappedWrite::PrgRAM(addr as usize & 0x1FFF, data),
+      0x8000..=0xBFFF => self.chr_regs[(addr as usize - 0x8000) / 0x800] = data,
+      0xC000..=0xDFFF => self.nametable_regs[(addr as usize - 0xC000) / 0x800] = data,
+      0xE000..=0xE7FF => {
+        self.prg_regs[0] = data & 0x3F;
+        self.sound_disabled = data & 0x40 != 0;
+      },
+      0xE800..=0xEFFF => {
+        self.prg_regs[1] = data & 0x3F;
+        self.ciram_disable = data & 0xC0;
+      },
+      0xF000..=0xF7FF => self.prg_regs[2] = data & 0x3F,
+      0xF800..=0xFFFF => {
+        self.sound_addr = data;
+        self.ram_protect = data;
+      },
+      _ => {},
+    }
+
+    self.update_banks();
+    MappedWrite::None
+  }
+
+  fn map_nametable(&self, addr: u16) -> MappedRead {
+    let bank = self.nametable_regs[(addr as usize & 0x0FFF) / 0x400];
+    let offset = addr as usize & 0x3FF;
+    if bank >= CIRAM_BANKS {
+      MappedRead::Vram((bank as usize & 1) * 0x400 + offset)
+    } else {
+      MappedRead::Chr((bank as usize * 0x400 + offset) % self.chr_size)
+    }
+  }
+
+  fn map_nametable_write(&mut self, addr: u16, data: u8) -> MappedWrite {
+    match self.map_nametable(addr) {
+      MappedRead::Vram(index) => MappedWrite::Vram(index, data),
+      MappedRead::Chr(index) => MappedWrite::Chr(index, data),
+      _ => MappedWrite::None,
+    }
+  }
+
+  fn cpu_cycle(&mut self) {
+    if self.irq_enabled && self.irq_counter < IRQ_COUNTER_MAX {
+      self.irq_counter += 1;
+      if self.irq_counter == IRQ_COUNTER_MAX {
+        self.irq_pending = true;
+      }
+    }
+
+    if self.sound_disabled {
+      return;
+    }
+    self.channel_cycles += 1;
+    if self.channel_cycles < CHANNEL_UPDATE_CYCLES {
+      return;
+    }
+    self.channel_cycles = 0;
+    let channel = self.channel;
+    self.update_channel(channel);
+    let lowest = 8 - self.channel_count();
+    self.channel = if channel <= lowest { 7 } else { channel - 1 };
+  }
+
+  fn irq_pending(&self) -> bool {
+    self.irq_pending
+  }
+
+  /// The channels are mixed by averaging, real chips play them one after another
+  /// which sounds the same once filtered
+  fn audio_sample(&self) -> f32 {
+    if self.sound_disabled {
+      return 0.0;
+    }
+    let count = self.channel_count();
+    let playing = &self.channel_outputs[(8 - count) as usize..];
+    playing.iter().map(|output| *output as f32).sum::<f32>() / (count as f32 * 225.0)
+  }
+
+  fn battery_ram(&self) -> &[u8] {
+    &self.sound_ram
+  }
+
+  fn load_battery_ram(&mut self, data: &[u8]) {
+    self.sound_ram.copy_from_slice(data);
+  }
+
+  fn reset(&mut self, _kind: ResetKind) {
+    self.sound_addr = 0;
+    self.sound_disabled = false;
+    self.ciram_disable = 0;
+    self.ram_protect = 0;
+    self.prg_regs = [0; 3];
+    self.chr_regs = [0; 8];
+    self.nametable_regs = [CIRAM_BANKS; 4];
+    self.irq_enabled = false;
+    self.irq_pending = false;
+    self.channel_cycles = 0;
+    self.channel = 7;
+    self.channel_outputs = [0; 8];
+    self.update_banks();
+  }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    state.write_bytes(&self.sound_ram);
+    state.write_u8(self.sound_addr);
+    state.write_bool(self.sound_disabled);
+    state.write_u8(self.ciram_disable);
+    state.write_u8(self.ram_protect);
+    state.write_bytes(&self.prg_regs);
+    state.write_bytes(&self.chr_regs);
+    state.write_bytes(&self.nametable_regs);
+    state.write_u16(self.irq_counter);
+    state.write_bool(self.irq_enabled);
+    state.write_bool(self.irq_pending);
+    state.write_u8(self.channel_cycles);
+    state.write_u8(self.channel);
+    state.write_bytes(&self.channel_outputs);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    state.read_bytes(&mut self.sound_ram)?;
+    self.sound_addr = state.read_u8()?;
+    self.sound_disabled = state.read_bool()?;
+    self.ciram_disable = state.read_u8()?;
+    self.ram_protect = state.read_u8()?;
+    state.read_bytes(&mut self.prg_regs)?;
+    state.read_bytes(&mut self.chr_regs)?;
+    state.read_bytes(&mut self.nametable_regs)?;
+    self.irq_counter = state.read_u16()? & IRQ_COUNTER_MAX;
+    self.irq_enabled = state.read_bool()?;
+    self.irq_pending = state.read_bool()?;
+    self.channel_cycles = state.read_u8()?;
+    self.channel = state.read_u8()? & 0b111;
+    state.read_bytes(&mut self.channel_outputs)?;
+    self.update_banks();
+    Ok(())
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::mappers::tests::banked_rom;
+
+  #[test]
+  fn test_chr_and_nametable_banks() {
+    let mut mapper = banked_rom(19).mapper;
+
+    mapper.map_write(0x8800, 0x05);
+    mapper.map_write(0x9000, 0xE1);
+    assert!(matches!(mapper.map_peak(0x0400), MappedRead::Chr(0x1400)));
+    assert!(matches!(mapper.map_peak(0x0810), MappedRead::Vram(0x410)));
+
+    // Until $E800 keeps the lower pattern table in CHR
+    mapper.map_write(0xE800, 0x40);
+    assert!(matches!(mapper.map_peak(0x0810), MappedRead::Chr(0x18410)));
+
+    // Nametables default to nametable RAM but can come from CHR
+    assert!(matches!(mapper.map_nametable(0x2410), MappedRead::Vram(0x10)));
+    mapper.map_write(0xC800, 0x03);
+    assert!(matches!(mapper.map_nametable(0x2410), MappedRead::Chr(0xC10)));
+  }
+
+  #[test]
+  fn test_irq_counter() {
+    let mut mapper = banked_rom(19).mapper;
+    mapper.map_write(0x5000, 0xFD);
+    mapper.map_write(0x5800, 0xFF);
+    assert!(matches!(mapper.map_peak(0x5800), MappedRead::Data(0xFF)));
+
+    mapper.cpu_cycle();
+    assert!(!mapper.irq_pending());
+    mapper.cpu_cycle();
+    assert!(mapper.irq_pending());
+
+    // It stays put at the top
+    mapper.cpu_cycle();
+    assert!(matches!(mapper.map_peak(0x5000), MappedRead::Data(0xFF)));
+    mapper.map_write(0x5000, 0x00);
+    assert!(!mapper.irq_pending());
+  }
+
+  #[test]
+  fn test_sound_ram_port() {
+    let mut mapper = banked_rom(19).mapper;
+    mapper.map_write(0xF800, 0x80 | 0x10);
+    mapper.map_write(0x4800, 0xAA);
+    mapper.map_write(0x4800, 0xBB);
+
+    mapper.map_write(0xF800, 0x10);
+    assert!(matches!(mapper.map_read(0x4800), MappedRead::Data(0xAA)));
+    assert!(matches!(mapper.map_read(0x4800), MappedRead::Data(0xAA)));
+    mapper.map_write(0xF800, 0x80 | 0x11);
+    assert!(matches!(mapper.map_read(0x4800), MappedRead::Data(0xBB)));
+
+    // Auto-increment wraps from $7F back to $00
+    mapper.map_write(0xF800, 0x00);
+    mapper.map_write(0x4800, 0xCC);
+    mapper.map_write(0xF800, 0x80 | 0x7F);
+    mapper.map_write(0x4800, 0xDD);
+    assert!(matches!(mapper.map_read(0x4800), MappedRead::Data(0xCC)));
+    mapper.map_write(0xF800, 0x7F);
+    assert!(matches!(mapper.map_read(0x4800), MappedRead::Data(0xDD)));
+  }
+
+  #[test]
+  fn test_wavetable_channel() {
+    let mut mapper = banked_rom(19).mapper;
+    let poke = |mapper: &mut Mapper, addr: u8, data: u8| {
+      mapper.map_write(0xF800, addr);
+      mapper.map_write(0x4800, data);
+    };
+    // Channel 7 alone at full volume, stepping through the 4-sample waveform
+    // 0, 0xF, 0xF, 0 at address 0 one sample per update
+    poke(&mut mapper, 0x00, 0xF0);
+    poke(&mut mapper, 0x01, 0x0F);
+    poke(&mut mapper, 0x7C, 0xFC | 0x01);
+    poke(&mut mapper, 0x7F, 0x0F);
+
+    let mut samples = vec![];
+    for _ in 0..4 {
+      for _ in 0..CHANNEL_UPDATE_CYCLES {
+        mapper.cpu_cycle();
+      }
+      samples.push(mapper.audio_sample());
+    }
+    assert_eq!(samples, vec![1.0, 1.0, 0.0, 0.0]);
+  }
+
+}
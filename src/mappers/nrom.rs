@@ -1,4 +1,5 @@
 use crate::rom::{ScreenMirroring, ROM};
+use crate::savestate::{StateReader, StateWriter};
 
 use super::{Mapper, Map, MappedRead, MappedWrite};
 
@@ -19,11 +20,7 @@ pub struct NROM {
 
 impl NROM {
 
-  pub fn load(rom: &mut ROM) -> Mapper {
-    
-    if !rom.has_chr_rom() {
-      rom.chr_ram = vec![0; 0x2000]
-    }
+  pub fn load(rom: &ROM) -> Mapper {
 
     let nrom = Self {
       mirroring: rom.header.mirroring,
@@ -44,7 +41,7 @@ impl Map for NROM {
       self.mirroring = _mirroring;
   }
 
-  fn map_read(&mut self, addr: u16) -> MappedRead {
+  fn map_peak(&self, addr: u16) -> MappedRead {
     match addr {
       CHR_ROM_BANK_START..=CHR_ROM_BANK_END => MappedRead::Chr(addr.into()),
       PRG_RAM_START..=PRG_RAM_END => MappedRead::PrgRAM((addr & 0x1FFF).into()),
@@ -64,4 +61,13 @@ impl Map for NROM {
       _ => MappedWrite::None,
     }
   }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    state.write_mirroring(self.mirroring);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.mirroring = state.read_mirroring()?;
+    Ok(())
+  }
 }
\ No newline at end of file
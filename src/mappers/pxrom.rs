@@ -0,0 +1,242 @@
+use crate::cpu::ResetKind;
+use crate::{rom::{ROM, ScreenMirroring}, mem::Membank};
+use crate::savestate::{StateReader, StateWriter};
+
+use super::{Map, Mapper, MappedRead, MappedWrite};
+
+const CHR_START: usize = 0x0000;
+const CHR_END: usize = 0x1FFF;
+const PRG_RAM_START: usize = 0x6000;
+const PRG_RAM_END: usize = 0x7FFF;
+const PRG_ROM_START: usize = 0x8000;
+const PRG_ROM_END: usize = 0xFFFF;
+
+/// Tile numbers that flip a latch when the PPU fetches them
+const LATCH_FD: usize = 0;
+const LATCH_FE: usize = 1;
+
+/// The CHR latches shared by MMC2 and MMC4. Each 4 KB half of the pattern tables has
+/// one bank for tile $FD and one for tile $FE, and shows whichever of the two tiles it
+/// fetched last. Games put the switch tiles at the edge of a picture to change banks mid-frame.
+#[derive(Debug, Clone)]
+pub(super) struct ChrLatches {
+  banks: [[u8; 2]; 2],
+  latches: [usize; 2],
+}
+
+impl ChrLatches {
+
+  pub(super) const fn new() -> Self {
+    Self { banks: [[0; 2]; 2], latches: [LATCH_FE; 2] }
+  }
+
+  /// `register` is 0-3 for $B000, $C000, $D000 and $E000
+  pub(super) fn write(&mut self, register: usize, data: u8) {
+    self.banks[register / 2][register % 2] = data & 0x1F;
+  }
+
+  /// Updates the latches after the PPU fetched from `addr`. MMC2 only watches the first
+  /// row of the tiles in the lower half, while MMC4 and MMC2's upper half watch all eight.
+  pub(super) fn fetch(&mut self, addr: u16, whole_tile_low: bool) {
+    if addr > 0x1FFF {
+      return;
+    }
+    let half = (addr >> 12 & 1) as usize;
+    let whole_tile = half == 1 || whole_tile_low;
+    let latch = match addr & 0x0FFF {
+      0x0FD8 => LATCH_FD,
+      0x0FE8 => LATCH_FE,
+      0x0FD9..=0x0FDF if whole_tile => LATCH_FD,
+      0x0FE9..=0x0FEF if whole_tile => LATCH_FE,
+      _ => return,
+    };
+    self.latches[half] = latch;
+  }
+
+  pub(super) fn apply(&self, chr_banks: &mut Membank) {
+    for half in 0..2 {
+      chr_banks.set(half, self.banks[half][self.latches[half]] as usize);
+    }
+  }
+
+  pub(super) fn save_state(&self, state: &mut StateWriter) {
+    for half in self.banks.iter() {
+      state.write_bytes(half);
+    }
+    for latch in self.latches.iter() {
+      state.write_usize(*latch);
+    }
+  }
+
+  pub(super) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    for half in self.banks.iter_mut() {
+      state.read_bytes(half)?;
+    }
+    for latch in self.latches.iter_mut() {
+      *latch = state.read_usize()?.min(LATCH_FE);
+    }
+    Ok(())
+  }
+
+}
+
+/// MMC2 (mapper 9), used by Punch-Out!!. One switchable 8 KB PRG bank at $8000
+/// with the last three fixed behind it, and latched 4 KB CHR banks.
+pub struct PXROM {
+  mirroring: ScreenMirroring,
+  prg_bank: u8,
+  latches: ChrLatches,
+  prg_rom_banks: Membank,
+  chr_banks: Membank,
+}
+
+impl PXROM {
+
+  pub fn load(rom: &ROM) -> Mapper {
+
+    let chr_size = if rom.has_chr_rom() { rom.chr_rom.len() } else { rom.chr_ram.len() };
+
+    let mut pxrom = Self {
+      mirroring: rom.header.mirroring,
+      prg_bank: 0,
+      latches: ChrLatches::new(),
+      prg_rom_banks: Membank::new(PRG_ROM_START, PRG_ROM_END, rom.prg_rom.len(), 0x2000),
+      chr_banks: Membank::new(CHR_START, CHR_END, chr_size, 0x1000),
+    };
+
+    pxrom.update_banks();
+    pxrom.into()
+
+  }
+
+  fn update_banks(&mut self) {
+    let last = self.prg_rom_banks.last();
+    self.prg_rom_banks.set(0, self.prg_bank as usize);
+    self.prg_rom_banks.set(1, last.saturating_sub(2));
+    self.prg_rom_banks.set(2, last.saturating_sub(1));
+    self.prg_rom_banks.set(3, last);
+    self.latches.apply(&mut self.chr_banks);
+  }
+
+}
+
+/// Handles a write to one of the registers MMC2 and MMC4 share, $A000-$FFFF.
+/// Returns the new PRG bank if it was the PRG register.
+pub(super) fn write_register(addr: u16, data: u8, latches: &mut ChrLatches, mirroring: &mut ScreenMirroring) -> Option<u8> {
+  match addr & 0xF000 {
+    0xA000 => return Some(data & 0x0F),
+    0xB000 => latches.write(0, data),
+    0xC000 => latches.write(1, data),
+    0xD000 => latches.write(2, data),
+    0xE000 => latches.write(3, data),
+    0xF000 => {
+      *mirroring = if data & 1 == 0 { ScreenMirroring::Vertical } else { ScreenMirroring::Horizontal };
+    },
+    _ => {},
+  }
+  None
+}
+
+impl Map for PXROM {
+
+  fn mirroring(&self) -> ScreenMirroring { self.mirroring }
+
+  fn set_mirroring(&mut self, mirroring: ScreenMirroring) { self.mirroring = mirroring; }
+
+  fn map_peak(&self, addr: u16) -> MappedRead {
+    match addr as usize {
+      CHR_START..=CHR_END => MappedRead::Chr(self.chr_banks.translate(addr)),
+      PRG_RAM_START..=PRG_RAM_END => MappedRead::PrgRAM(addr as usize & 0x1FFF),
+      PRG_ROM_START..=PRG_ROM_END => MappedRead::PrgROM(self.prg_rom_banks.translate(addr)),
+      _ => MappedRead::None,
+    }
+  }
+
+  fn map_write(&mut self, addr: u16, data: u8) -> MappedWrite {
+    match addr as usize {
+      CHR_START..=CHR_END => MappedWrite::Chr(self.chr_banks.translate(addr), data),
+      PRG_RAM_START..=PRG_RAM_END => MappedWrite::PrgRAM(addr as usize & 0x1FFF, data),
+      PRG_ROM_START..=PRG_ROM_END => {
+        if let Some(bank) = write_register(addr, data, &mut self.latches, &mut self.mirroring) {
+          self.prg_bank = bank;
+        }
+        self.update_banks();
+        MappedWrite::None
+      },
+      _ => MappedWrite::None,
+    }
+  }
+
+  fn ppu_fetch(&mut self, addr: u16) {
+    self.latches.fetch(addr, false);
+    self.latches.apply(&mut self.chr_banks);
+  }
+
+  fn reset(&mut self, _kind: ResetKind) {
+    self.prg_bank = 0;
+    self.latches = ChrLatches::new();
+    self.update_banks();
+  }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    state.write_mirroring(self.mirroring);
+    state.write_u8(self.prg_bank);
+    self.latches.save_state(state);
+    self.prg_rom_banks.save_state(state);
+    self.chr_banks.save_state(state);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.mirroring = state.read_mirroring()?;
+    self.prg_bank = state.read_u8()?;
+    self.latches.load_state(state)?;
+    self.prg_rom_banks.load_state(state)?;
+    self.chr_banks.load_state(state)
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::mappers::tests::{banked_rom, chr_bank};
+
+  #[test]
+  fn test_prg_banks() {
+    let mut mapper = banked_rom(9).mapper;
+    mapper.map_write(0xA000, 5);
+
+    let bank = |mapper: &Mapper, addr| match mapper.map_peak(addr) {
+      MappedRead::PrgROM(index) => index / 0x2000,
+      _ => panic!("not PRG-ROM"),
+    };
+    assert_eq!(bank(&mapper, 0x8000), 5);
+    assert_eq!(bank(&mapper, 0xA000), 13);
+    assert_eq!(bank(&mapper, 0xC000), 14);
+    assert_eq!(bank(&mapper, 0xFFFF), 15);
+  }
+
+  #[test]
+  fn test_chr_latches() {
+    let mut mapper = banked_rom(9).mapper;
+    for (register, bank) in [(0xB000, 1), (0xC000, 2), (0xD000, 3), (0xE000, 4)] {
+      mapper.map_write(register, bank);
+    }
+
+    // Both latches power up on $FE
+    assert_eq!((chr_bank(&mapper, 0x0000), chr_bank(&mapper, 0x1000)), (2, 4));
+
+    // The fetch itself still comes from the old bank, the switch is for the ones after it
+    mapper.ppu_fetch(0x0FD8);
+    mapper.ppu_fetch(0x1FDD);
+    assert_eq!((chr_bank(&mapper, 0x0000), chr_bank(&mapper, 0x1000)), (1, 3));
+
+    // Only the first row of tile $FE counts in the lower half
+    mapper.ppu_fetch(0x0FE9);
+    assert_eq!(chr_bank(&mapper, 0x0000), 1);
+    mapper.ppu_fetch(0x0FE8);
+    assert_eq!(chr_bank(&mapper, 0x0000), 2);
+  }
+
+}
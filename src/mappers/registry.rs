@@ -0,0 +1,248 @@
+use crate::rom::{ROM, ScreenMirroring};
+
+use super::Mapper;
+use super::exrom::EXROM;
+use super::fme7::FME7;
+use super::fxrom::FXROM;
+use super::namco163::Namco163;
+use super::nrom::NROM;
+use super::pxrom::PXROM;
+use super::txrom::TXROM;
+use super::vrc4::VRC4;
+use super::vrc6::VRC6;
+use super::vrc7::VRC7;
+
+const KB: usize = 0x400;
+
+/// How many supported mappers an unsupported mapper's error suggests
+const SUGGESTIONS: usize = 3;
+
+/// Everything about a board that isn't its banking logic. `ROM::from_bytes` looks the
+/// cart's board up here, gives it the RAM it declares and hands it to `new`.
+pub struct Board {
+  pub name: &'static str,
+  pub mapper_id: u16,
+  /// The NES 2.0 submapper this entry is for, `None` for any of them
+  pub submapper: Option<u8>,
+  /// The most PRG-ROM and CHR-ROM the board can address
+  pub max_prg_rom: usize,
+  pub max_chr_rom: usize,
+  pub prg_ram: usize,
+  /// Only provided when the cart has no CHR-ROM
+  pub chr_ram: usize,
+  /// Replaces the header's mirroring, for boards that ignore the cart's solder pads
+  pub mirroring: Option<ScreenMirroring>,
+  pub new: fn(&ROM) -> Mapper,
+}
+
+const NROM_BOARD: Board = Board {
+  name: "NROM",
+  mapper_id: 0,
+  submapper: None,
+  max_prg_rom: 32 * KB,
+  max_chr_rom: 8 * KB,
+  // Only Family BASIC carts actually have PRG-RAM, but test ROMs report their results through it
+  prg_ram: 8 * KB,
+  chr_ram: 8 * KB,
+  mirroring: None,
+  new: NROM::load,
+};
+
+const TXROM_BOARD: Board = Board {
+  name: "MMC3 (TxROM)",
+  mapper_id: 4,
+  submapper: None,
+  max_prg_rom: 512 * KB,
+  max_chr_rom: 256 * KB,
+  prg_ram: 8 * KB,
+  chr_ram: 8 * KB,
+  mirroring: None,
+  new: TXROM::load,
+};
+
+const EXROM_BOARD: Board = Board {
+  name: "MMC5 (ExROM)",
+  mapper_id: 5,
+  submapper: None,
+  max_prg_rom: 1024 * KB,
+  max_chr_rom: 1024 * KB,
+  prg_ram: 64 * KB,
+  chr_ram: 8 * KB,
+  mirroring: None,
+  new: EXROM::load,
+};
+
+const PXROM_BOARD: Board = Board {
+  name: "MMC2 (PxROM)",
+  mapper_id: 9,
+  submapper: None,
+  max_prg_rom: 128 * KB,
+  max_chr_rom: 128 * KB,
+  // Only the PlayChoice-10 board has PRG-RAM, but test ROMs report their results through it
+  prg_ram: 8 * KB,
+  chr_ram: 8 * KB,
+  mirroring: None,
+  new: PXROM::load,
+};
+
+const FXROM_BOARD: Board = Board {
+  name: "MMC4 (FxROM)",
+  mapper_id: 10,
+  submapper: None,
+  max_prg_rom: 256 * KB,
+  max_chr_rom: 128 * KB,
+  prg_ram: 8 * KB,
+  chr_ram: 8 * KB,
+  mirroring: None,
+  new: FXROM::load,
+};
+
+const NAMCO_163_BOARD: Board = Board {
+  name: "Namco 163",
+  mapper_id: 19,
+  submapper: None,
+  max_prg_rom: 512 * KB,
+  max_chr_rom: 256 * KB,
+  prg_ram: 8 * KB,
+  chr_ram: 8 * KB,
+  // Nametables are banked like CHR, the header's mirroring means nothing
+  mirroring: Some(ScreenMirroring::Default),
+  new: Namco163::load,
+};
+
+const VRC4_BOARD: Board = Board {
+  name: "VRC4a/VRC4c",
+  mapper_id: 21,
+  submapper: None,
+  max_prg_rom: 256 * KB,
+  max_chr_rom: 512 * KB,
+  prg_ram: 8 * KB,
+  chr_ram: 8 * KB,
+  mirroring: None,
+  new: VRC4::vrc4a_vrc4c,
+};
+
+const VRC2_BOARD: Board = Board {
+  name: "VRC2a",
+  mapper_id: 22,
+  max_chr_rom: 256 * KB,
+  new: VRC4::vrc2a,
+  ..VRC4_BOARD
+};
+
+const VRC6_BOARD: Board = Board {
+  name: "VRC6a",
+  mapper_id: 24,
+  submapper: None,
+  max_prg_rom: 256 * KB,
+  max_chr_rom: 256 * KB,
+  prg_ram: 8 * KB,
+  chr_ram: 8 * KB,
+  mirroring: None,
+  new: VRC6::load,
+};
+
+const FME7_BOARD: Board = Board {
+  name: "Sunsoft FME-7",
+  mapper_id: 69,
+  submapper: None,
+  max_prg_rom: 512 * KB,
+  max_chr_rom: 256 * KB,
+  prg_ram: 8 * KB,
+  chr_ram: 8 * KB,
+  mirroring: None,
+  new: FME7::load,
+};
+
+const VRC7_BOARD: Board = Board {
+  name: "VRC7",
+  mapper_id: 85,
+  submapper: None,
+  max_prg_rom: 512 * KB,
+  max_chr_rom: 256 * KB,
+  prg_ram: 8 * KB,
+  chr_ram: 8 * KB,
+  mirroring: None,
+  new: VRC7::load,
+};
+
+/// Every supported board. Konami reused mapper numbers across boards wired differently,
+/// NES 2.0 submappers say which one a cart is.
+pub const BOARDS: &[Board] = &[
+  NROM_BOARD,
+  TXROM_BOARD,
+  EXROM_BOARD,
+  PXROM_BOARD,
+  FXROM_BOARD,
+  NAMCO_163_BOARD,
+  VRC4_BOARD,
+  Board { name: "VRC4a", submapper: Some(1), new: VRC4::vrc4a, ..VRC4_BOARD },
+  Board { name: "VRC4c", submapper: Some(2), new: VRC4::vrc4c, ..VRC4_BOARD },
+  VRC2_BOARD,
+  Board { name: "VRC2b/VRC4e/VRC4f", mapper_id: 23, new: VRC4::vrc2b_vrc4e_vrc4f, ..VRC4_BOARD },
+  Board { name: "VRC4f", mapper_id: 23, submapper: Some(1), new: VRC4::vrc4f, ..VRC4_BOARD },
+  Board { name: "VRC4e", mapper_id: 23, submapper: Some(2), new: VRC4::vrc4e, ..VRC4_BOARD },
+  Board { name: "VRC2b", mapper_id: 23, submapper: Some(3), new: VRC4::vrc2b, ..VRC2_BOARD },
+  VRC6_BOARD,
+  Board { name: "VRC2c/VRC4b/VRC4d", mapper_id: 25, new: VRC4::vrc2c_vrc4b_vrc4d, ..VRC4_BOARD },
+  Board { name: "VRC4b", mapper_id: 25, submapper: Some(1), new: VRC4::vrc4b, ..VRC4_BOARD },
+  Board { name: "VRC4d", mapper_id: 25, submapper: Some(2), new: VRC4::vrc4d, ..VRC4_BOARD },
+  Board { name: "VRC2c", mapper_id: 25, submapper: Some(3), new: VRC4::vrc2c, ..VRC2_BOARD },
+  Board { name: "VRC6b", mapper_id: 26, ..VRC6_BOARD },
+  FME7_BOARD,
+  VRC7_BOARD,
+  Board { name: "VRC7b", submapper: Some(1), ..VRC7_BOARD },
+  Board { name: "VRC7a", submapper: Some(2), ..VRC7_BOARD },
+];
+
+/// The board for a mapper and submapper, preferring an entry for that exact submapper
+pub fn find_board(mapper_id: u16, submapper: u8) -> Result<&'static Board, String> {
+
+  let mut boards = BOARDS.iter().filter(|board| board.mapper_id == mapper_id);
+  let exact = boards.clone().find(|board| board.submapper == Some(submapper));
+
+  if let Some(board) = exact.or_else(|| boards.find(|board| board.submapper.is_none())) {
+    return Ok(board);
+  }
+
+  // The nearest mapper numbers, since a wrong or misread header is usually off by a little
+  let mut supported: Vec<&Board> = BOARDS.iter().filter(|board| board.submapper.is_none()).collect();
+  supported.sort_by_key(|board| (board.mapper_id.abs_diff(mapper_id), board.mapper_id));
+  let closest: Vec<String> = supported.iter()
+    .take(SUGGESTIONS)
+    .map(|board| format!("{} as mapper {}", board.name, board.mapper_id))
+    .collect();
+
+  Err(format!("Mapper {} not supported, the closest supported boards are {}", mapper_id, closest.join(", ")))
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_find_board() {
+    assert_eq!(find_board(23, 3).unwrap().name, "VRC2b");
+    assert_eq!(find_board(23, 0).unwrap().name, "VRC2b/VRC4e/VRC4f");
+    // Unknown submappers fall back to the whole mapper
+    assert_eq!(find_board(85, 7).unwrap().name, "VRC7");
+  }
+
+  #[test]
+  fn test_unsupported_mapper() {
+    let error = find_board(7, 0).err().unwrap();
+    assert_eq!(error, "Mapper 7 not supported, the closest supported boards are MMC5 (ExROM) as mapper 5, MMC2 (PxROM) as mapper 9, MMC3 (TxROM) as mapper 4");
+  }
+
+  #[test]
+  fn test_boards_are_unique() {
+    for (index, board) in BOARDS.iter().enumerate() {
+      let duplicate = BOARDS[index + 1..].iter()
+        .any(|other| other.mapper_id == board.mapper_id && other.submapper == board.submapper);
+      assert!(!duplicate, "{} is registered twice", board.name);
+    }
+  }
+
+}
@@ -1,10 +1,9 @@
 use crate::{rom::{ROM, ScreenMirroring}, mem::Membank};
+use crate::cpu::ResetKind;
+use crate::savestate::{StateReader, StateWriter};
 
 use super::{Map, Mapper, MappedRead, MappedWrite};
 
-const PRG_RAM_SIZE: u16 = 0x2000;
-const CHR_RAM_SIZE: u16 = 0x2000;
-
 const CHR_RAM_START: usize = 0x0000;
 const CHR_RAM_END: usize = 0x1FFF;
 const PRG_RAM_START: usize = 0x6000;
@@ -35,6 +34,23 @@ impl TxRegs {
       // last_clock: 0x0000,
     }
   }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    state.write_u8(self.bank_select);
+    state.write_bytes(&self.bank_values);
+    state.write_u8(self.irq_latch);
+    state.write_bool(self.irq_enabled);
+    state.write_bool(self.irq_reload);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.bank_select = state.read_u8()?;
+    state.read_bytes(&mut self.bank_values)?;
+    self.irq_latch = state.read_u8()?;
+    self.irq_enabled = state.read_bool()?;
+    self.irq_reload = state.read_bool()?;
+    Ok(())
+  }
 }
 
 pub struct TXROM {
@@ -48,22 +64,14 @@ pub struct TXROM {
 
 impl TXROM {
 
-  pub fn load(rom: &mut ROM) -> Mapper {
-
-    rom.prg_ram = vec![0; PRG_RAM_SIZE as usize];
-
-    if !rom.has_chr_rom() {
-      rom.chr_ram = vec![0; CHR_RAM_SIZE as usize];
-    }
+  pub fn load(rom: &ROM) -> Mapper {
 
-    if rom.header.mirroring == ScreenMirroring::FourScreen {
-      rom.ex_ram = vec![0; 0x1000];
-    }
+    let chr_size = if rom.has_chr_rom() { rom.chr_rom.len() } else { rom.chr_ram.len() };
 
     let mut txrom = Self {
       mirroring: rom.header.mirroring,
       regs: TxRegs::new(),
-      chr_banks: Membank::new(CHR_RAM_START, CHR_RAM_END, rom.chr_ram.len(), 0x400),
+      chr_banks: Membank::new(CHR_RAM_START, CHR_RAM_END, chr_size, 0x400),
       prg_rom_banks: Membank::new(PRG_ROM_START, PRG_ROM_END, rom.prg_rom.len(), 0x2000),
       prg_ram_banks: Membank::new(PRG_RAM_START, PRG_RAM_END, rom.prg_ram.len(), 0x2000),
       irq_pending: false,
@@ -175,4 +183,30 @@ impl Map for TXROM {
 
   }
 
-}
\ No newline at end of file
+  /// Back to the power on banks: the last two 8 KB PRG banks at the top, everything else bank 0
+  fn reset(&mut self, _kind: ResetKind) {
+    self.regs = TxRegs::new();
+    self.irq_pending = false;
+    self.update_banks();
+  }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    state.write_mirroring(self.mirroring);
+    self.regs.save_state(state);
+    self.prg_rom_banks.save_state(state);
+    self.prg_ram_banks.save_state(state);
+    self.chr_banks.save_state(state);
+    state.write_bool(self.irq_pending);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.mirroring = state.read_mirroring()?;
+    self.regs.load_state(state)?;
+    self.prg_rom_banks.load_state(state)?;
+    self.prg_ram_banks.load_state(state)?;
+    self.chr_banks.load_state(state)?;
+    self.irq_pending = state.read_bool()?;
+    Ok(())
+  }
+
+}
@@ -0,0 +1,287 @@
+use crate::cpu::ResetKind;
+use crate::{rom::{ROM, ScreenMirroring}, mem::Membank};
+use crate::savestate::{StateReader, StateWriter};
+
+use super::vrc_irq::VrcIrq;
+use super::{Map, Mapper, MappedRead, MappedWrite};
+
+const CHR_START: usize = 0x0000;
+const CHR_END: usize = 0x1FFF;
+const PRG_RAM_START: usize = 0x6000;
+const PRG_RAM_END: usize = 0x7FFF;
+const PRG_ROM_START: usize = 0x8000;
+const PRG_ROM_END: usize = 0xFFFF;
+
+/// Which chip a board has, VRC2a being a VRC2 with one less CHR bank line
+#[derive(Clone, Copy, PartialEq)]
+enum Chip {
+  VRC2a,
+  VRC2,
+  VRC4,
+}
+
+/// Konami VRC2 and VRC4 (mappers 21, 22, 23 and 25), used by Contra (J), Ganbare Goemon 2
+/// and Wai Wai World 2. Two switchable 8 KB PRG banks, eight 1 KB CHR banks and, on VRC4,
+/// a PRG swap mode, single screen mirroring and the VRC IRQ counter. The boards differ in
+/// which address lines select the registers within each 4 KB block.
+pub struct VRC4 {
+  mirroring: ScreenMirroring,
+  vrc2: bool,
+  /// VRC2a only has the upper 7 bits of its CHR bank numbers wired
+  chr_shift: u8,
+  lines: (u16, u16),
+  prg_regs: [u8; 2],
+  prg_swap: bool,
+  chr_regs: [u16; 8],
+  irq: VrcIrq,
+  prg_rom_banks: Membank,
+  chr_banks: Membank,
+}
+
+impl VRC4 {
+
+  /// `lines` are the CPU address lines the board wires to the chip's register select
+  /// inputs, as the mask for register bit 0 and the one for bit 1
+  fn load(rom: &ROM, chip: Chip, lines: (u16, u16)) -> Mapper {
+
+    let chr_size = if rom.has_chr_rom() { rom.chr_rom.len() } else { rom.chr_ram.len() };
+
+    let mut vrc4 = Self {
+      mirroring: rom.header.mirroring,
+      vrc2: chip != Chip::VRC4,
+      chr_shift: if chip == Chip::VRC2a { 1 } else { 0 },
+      lines,
+      prg_regs: [0; 2],
+      prg_swap: false,
+      chr_regs: [0; 8],
+      irq: VrcIrq::new(),
+      prg_rom_banks: Membank::new(PRG_ROM_START, PRG_ROM_END, rom.prg_rom.len(), 0x2000),
+      chr_banks: Membank::new(CHR_START, CHR_END, chr_size, 0x400),
+    };
+
+    vrc4.update_banks();
+    vrc4.into()
+
+  }
+
+  // Without a submapper to say which board a cart is, the registers answer on both of
+  // the candidates' lines, since games only write one of them
+
+  pub fn vrc4a_vrc4c(rom: &ROM) -> Mapper { Self::load(rom, Chip::VRC4, (0x42, 0x84)) }
+  pub fn vrc4a(rom: &ROM) -> Mapper { Self::load(rom, Chip::VRC4, (0x02, 0x04)) }
+  pub fn vrc4c(rom: &ROM) -> Mapper { Self::load(rom, Chip::VRC4, (0x40, 0x80)) }
+  pub fn vrc2a(rom: &ROM) -> Mapper { Self::load(rom, Chip::VRC2a, (0x02, 0x01)) }
+  pub fn vrc2b_vrc4e_vrc4f(rom: &ROM) -> Mapper { Self::load(rom, Chip::VRC4, (0x05, 0x0A)) }
+  pub fn vrc4f(rom: &ROM) -> Mapper { Self::load(rom, Chip::VRC4, (0x01, 0x02)) }
+  pub fn vrc4e(rom: &ROM) -> Mapper { Self::load(rom, Chip::VRC4, (0x04, 0x08)) }
+  pub fn vrc2b(rom: &ROM) -> Mapper { Self::load(rom, Chip::VRC2, (0x01, 0x02)) }
+  pub fn vrc2c_vrc4b_vrc4d(rom: &ROM) -> Mapper { Self::load(rom, Chip::VRC4, (0x0A, 0x05)) }
+  pub fn vrc4b(rom: &ROM) -> Mapper { Self::load(rom, Chip::VRC4, (0x02, 0x01)) }
+  pub fn vrc4d(rom: &ROM) -> Mapper { Self::load(rom, Chip::VRC4, (0x08, 0x04)) }
+  pub fn vrc2c(rom: &ROM) -> Mapper { Self::load(rom, Chip::VRC2, (0x02, 0x01)) }
+
+  /// Register 0-3 within the 4 KB block `addr` is in
+  fn register(&self, addr: u16) -> u16 {
+    let (bit0, bit1) = self.lines;
+    (addr & bit0 != 0) as u16 | ((addr & bit1 != 0) as u16) << 1
+  }
+
+  fn update_banks(&mut self) {
+    let last = self.prg_rom_banks.last();
+    let (swappable, fixed) = (self.prg_regs[0] as usize, last.saturating_sub(1));
+    let (low, high) = if self.prg_swap { (fixed, swappable) } else { (swappable, fixed) };
+    self.prg_rom_banks.set(0, low);
+    self.prg_rom_banks.set(1, self.prg_regs[1] as usize);
+    self.prg_rom_banks.set(2, high);
+    self.prg_rom_banks.set(3, last);
+
+    for (slot, bank) in self.chr_regs.iter().enumerate() {
+      self.chr_banks.set(slot, (*bank >> self.chr_shift) as usize);
+    }
+  }
+
+  /// Each CHR bank number is written a nibble at a time, low then high. VRC4 takes a
+  /// fifth bit with the high nibble, VRC2's registers are only 8 bits wide.
+  fn write_chr(&mut self, addr: u16, register: u16, data: u8) {
+    let slot = ((addr - 0xB000) / 0x1000 * 2 + register / 2) as usize;
+    let bank = self.chr_regs[slot];
+    let high_mask = if self.vrc2 { 0x0F } else { 0x1F };
+    self.chr_regs[slot] = match register & 1 {
+      0 => bank & 0x1F0 | (data & 0x0F) as u16,
+      _ => bank & 0x00F | ((data & high_mask) as u16) << 4,
+    };
+  }
+
+}
+
+impl Map for VRC4 {
+
+  fn mirroring(&self) -> ScreenMirroring { self.mirroring }
+
+  fn set_mirroring(&mut self, mirroring: ScreenMirroring) { self.mirroring = mirroring; }
+
+  fn map_peak(&self, addr: u16) -> MappedRead {
+    match addr as usize {
+      CHR_START..=CHR_END => MappedRead::Chr(self.chr_banks.translate(addr)),
+      PRG_RAM_START..=PRG_RAM_END => MappedRead::PrgRAM(addr as usize & 0x1FFF),
+      PRG_ROM_START..=PRG_ROM_END => MappedRead::PrgROM(self.prg_rom_banks.translate(addr)),
+      _ => MappedRead::None,
+    }
+  }
+
+  fn map_write(&mut self, addr: u16, data: u8) -> MappedWrite {
+
+    let block = addr & 0xF000;
+    let register = self.register(addr);
+
+    match addr as usize {
+      CHR_START..=CHR_END => return MappedWrite::Chr(self.chr_banks.translate(addr), data),
+      PRG_RAM_START..=PRG_RAM_END => return MappedWrite::PrgRAM(addr as usize & 0x1FFF, data),
+      PRG_ROM_START..=PRG_ROM_END => match (block, register) {
+        (0x8000, _) => self.prg_regs[0] = data & 0x1F,
+        (0x9000, _) if self.vrc2 => {
+          self.mirroring = if data & 1 == 0 { ScreenMirroring::Vertical } else { ScreenMirroring::Horizontal };
+        },
+        (0x9000, 0) => {
+          self.mirroring = match data & 0b11 {
+            0 => ScreenMirroring::Vertical,
+            1 => ScreenMirroring::Horizontal,
+            2 => ScreenMirroring::SingleScreenLower,
+            _ => ScreenMirroring::SingleScreenUpper,
+          };
+        },
+        (0x9000, 2) => self.prg_swap = data & 0b10 != 0,
+        (0xA000, _) => self.prg_regs[1] = data & 0x1F,
+        (0xB000..=0xE000, _) => self.write_chr(block, register, data),
+        (0xF000, _) if self.vrc2 => {},
+        (0xF000, 0) => self.irq.write_latch_low(data),
+        (0xF000, 1) => self.irq.write_latch_high(data),
+        (0xF000, 2) => self.irq.write_control(data),
+        (0xF000, _) => self.irq.acknowledge(),
+        _ => {},
+      },
+      _ => {},
+    }
+
+    self.update_banks();
+    MappedWrite::None
+
+  }
+
+  fn cpu_cycle(&mut self) {
+    self.irq.cpu_cycle();
+  }
+
+  fn irq_pending(&self) -> bool {
+    self.irq.pending()
+  }
+
+  fn reset(&mut self, _kind: ResetKind) {
+    self.prg_regs = [0; 2];
+    self.prg_swap = false;
+    self.chr_regs = [0; 8];
+    self.irq = VrcIrq::new();
+    self.update_banks();
+  }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    state.write_mirroring(self.mirroring);
+    state.write_bytes(&self.prg_regs);
+    state.write_bool(self.prg_swap);
+    for bank in self.chr_regs.iter() {
+      state.write_u16(*bank);
+    }
+    self.irq.save_state(state);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.mirroring = state.read_mirroring()?;
+    state.read_bytes(&mut self.prg_regs)?;
+    self.prg_swap = state.read_bool()?;
+    for bank in self.chr_regs.iter_mut() {
+      *bank = state.read_u16()?;
+    }
+    self.irq.load_state(state)?;
+    self.update_banks();
+    Ok(())
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::mappers::registry::find_board;
+  use crate::mappers::tests::banked_rom;
+
+  fn chr_1k_bank(mapper: &Mapper, addr: u16) -> usize {
+    match mapper.map_peak(addr) {
+      MappedRead::Chr(index) => index / 0x400,
+      _ => panic!("not CHR"),
+    }
+  }
+
+  #[test]
+  fn test_register_lines() {
+    // Without a submapper, mapper 21 answers on both VRC4a's A1/A2 and VRC4c's A6/A7
+    let mut mapper = banked_rom(21).mapper;
+    mapper.map_write(0xB000, 0x05);
+    mapper.map_write(0xB040, 0x01);
+    assert_eq!(chr_1k_bank(&mapper, 0x0000), 0x15);
+    mapper.map_write(0xB004, 0x03);
+    assert_eq!(chr_1k_bank(&mapper, 0x0400), 0x03);
+
+    // VRC2a swaps the lines and drops the low bit of CHR banks
+    let mut mapper = banked_rom(22).mapper;
+    mapper.map_write(0xC000, 0x07);
+    mapper.map_write(0xC002, 0x01);
+    assert_eq!(chr_1k_bank(&mapper, 0x0800), 0x0B);
+    mapper.map_write(0xC001, 0x08);
+    assert_eq!(chr_1k_bank(&mapper, 0x0C00), 0x04);
+  }
+
+  #[test]
+  fn test_vrc2_chr_width() {
+    // The ninth bit is past the end of the test cart's CHR, so look at the registers
+    let Mapper::VRC4(mut vrc2) = banked_rom(22).mapper else { panic!("not VRC4") };
+    vrc2.map_write(0xB002, 0x1F);
+    assert_eq!(vrc2.chr_regs[0], 0x0F0);
+
+    let Mapper::VRC4(mut vrc4) = banked_rom(21).mapper else { panic!("not VRC4") };
+    vrc4.map_write(0xB002, 0x1F);
+    assert_eq!(vrc4.chr_regs[0], 0x1F0);
+
+    // Submapper 3 only means VRC2 on mappers 23 and 25, mapper 21 falls back to VRC4
+    let board = find_board(21, 3).unwrap();
+    let Mapper::VRC4(mut vrc4) = (board.new)(&banked_rom(21)) else { panic!("not VRC4") };
+    vrc4.map_write(0xB002, 0x1F);
+    assert_eq!(vrc4.chr_regs[0], 0x1F0);
+  }
+
+  #[test]
+  fn test_prg_swap_mode() {
+    let mut mapper = banked_rom(25).mapper;
+    let bank = |mapper: &Mapper, addr| match mapper.map_peak(addr) {
+      MappedRead::PrgROM(index) => index / 0x2000,
+      _ => panic!("not PRG-ROM"),
+    };
+
+    mapper.map_write(0x8000, 3);
+    mapper.map_write(0xA000, 4);
+    assert_eq!([0x8000, 0xA000, 0xC000, 0xE000].map(|addr| bank(&mapper, addr)), [3, 4, 14, 15]);
+
+    // Mapper 25 puts register 2 on A0 (VRC4b) or A2 (VRC4d)
+    mapper.map_write(0x9004, 0b10);
+    assert_eq!([0x8000, 0xA000, 0xC000, 0xE000].map(|addr| bank(&mapper, addr)), [14, 4, 3, 15]);
+  }
+
+  #[test]
+  fn test_single_screen_mirroring() {
+    let mut mapper = banked_rom(23).mapper;
+    mapper.map_write(0x9000, 3);
+    assert!(matches!(mapper.map_nametable(0x2005), MappedRead::Vram(0x405)));
+    assert!(matches!(mapper.map_nametable(0x2C05), MappedRead::Vram(0x405)));
+  }
+
+}
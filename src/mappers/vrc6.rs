@@ -0,0 +1,415 @@
+use crate::cpu::ResetKind;
+use crate::{rom::{ROM, ScreenMirroring}, mem::Membank};
+use crate::savestate::{StateReader, StateWriter};
+
+use super::vrc_irq::VrcIrq;
+use super::{Map, Mapper, MappedRead, MappedWrite};
+
+const CHR_START: usize = 0x0000;
+const CHR_END: usize = 0x1FFF;
+const PRG_RAM_START: usize = 0x6000;
+const PRG_RAM_END: usize = 0x7FFF;
+const PRG_ROM_START: usize = 0x8000;
+const PRG_ROM_END: usize = 0xFFFF;
+
+/// Sum of the loudest each channel gets: two 4-bit pulses and the 5-bit sawtooth
+const MAX_OUTPUT: f32 = (15 + 15 + 31) as f32;
+
+/// One of the two VRC6 square channels. Unlike the APU's, the duty cycle
+/// is one of 8 widths out of 16 steps, and there's no envelope or sweep.
+#[derive(Debug, Clone, Default)]
+struct Pulse {
+  volume: u8,
+  duty: u8,
+  /// Outputs the volume all the time, for sampled sound
+  constant: bool,
+  period: u16,
+  enabled: bool,
+  divider: u16,
+  step: u8,
+}
+
+impl Pulse {
+
+  fn write(&mut self, register: u16, data: u8) {
+    match register {
+      0 => {
+        self.volume = data & 0x0F;
+        self.duty = data >> 4 & 0x07;
+        self.constant = data & 0x80 != 0;
+      },
+      1 => self.period = self.period & 0xF00 | data as u16,
+      _ => {
+        self.period = self.period & 0x0FF | ((data & 0x0F) as u16) << 8;
+        self.enabled = data & 0x80 != 0;
+        if !self.enabled {
+          self.step = 0;
+        }
+      },
+    }
+  }
+
+  fn clock(&mut self, shift: u8) {
+    if !self.enabled {
+      return;
+    }
+    if self.divider == 0 {
+      self.divider = self.period >> shift;
+      self.step = (self.step + 1) % 16;
+    } else {
+      self.divider -= 1;
+    }
+  }
+
+  fn output(&self) -> u8 {
+    if self.enabled && (self.constant || self.step <= self.duty) { self.volume } else { 0 }
+  }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    state.write_u8(self.volume);
+    state.write_u8(self.duty);
+    state.write_bool(self.constant);
+    state.write_u16(self.period);
+    state.write_bool(self.enabled);
+    state.write_u16(self.divider);
+    state.write_u8(self.step);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.volume = state.read_u8()?;
+    self.duty = state.read_u8()?;
+    self.constant = state.read_bool()?;
+    self.period = state.read_u16()?;
+    self.enabled = state.read_bool()?;
+    self.divider = state.read_u16()?;
+    self.step = state.read_u8()?;
+    Ok(())
+  }
+
+}
+
+/// The VRC6 sawtooth. An accumulator gets the rate added on every other
+/// step and is cleared on the 14th, and its top 5 bits are the output.
+#[derive(Debug, Clone, Default)]
+struct Sawtooth {
+  rate: u8,
+  period: u16,
+  enabled: bool,
+  divider: u16,
+  step: u8,
+  accumulator: u8,
+}
+
+impl Sawtooth {
+
+  fn write(&mut self, register: u16, data: u8) {
+    match register {
+      0 => self.rate = data & 0x3F,
+      1 => self.period = self.period & 0xF00 | data as u16,
+      _ => {
+        self.period = self.period & 0x0FF | ((data & 0x0F) as u16) << 8;
+        self.enabled = data & 0x80 != 0;
+        if !self.enabled {
+          self.step = 0;
+          self.accumulator = 0;
+        }
+      },
+    }
+  }
+
+  fn clock(&mut self, shift: u8) {
+    if !self.enabled {
+      return;
+    }
+    if self.divider > 0 {
+      self.divider -= 1;
+      return;
+    }
+    self.divider = self.period >> shift;
+    self.step += 1;
+    if self.step == 14 {
+      self.step = 0;
+      self.accumulator = 0;
+    } else if self.step & 1 == 0 {
+      self.accumulator = self.accumulator.wrapping_add(self.rate);
+    }
+  }
+
+  fn output(&self) -> u8 {
+    self.accumulator >> 3
+  }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    state.write_u8(self.rate);
+    state.write_u16(self.period);
+    state.write_bool(self.enabled);
+    state.write_u16(self.divider);
+    state.write_u8(self.step);
+    state.write_u8(self.accumulator);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.rate = state.read_u8()?;
+    self.period = state.read_u16()?;
+    self.enabled = state.read_bool()?;
+    self.divider = state.read_u16()?;
+    self.step = state.read_u8()?;
+    self.accumulator = state.read_u8()?;
+    Ok(())
+  }
+
+}
+
+/// Konami VRC6 (mappers 24 and 26), used by Akumajou Densetsu, Madara and Esper Dream 2.
+/// A 16 KB and an 8 KB switchable PRG bank, eight 1 KB CHR banks, the VRC IRQ counter,
+/// and two pulse channels and a sawtooth. Mapper 26 swaps the two register select lines.
+pub struct VRC6 {
+  mirroring: ScreenMirroring,
+  swap_lines: bool,
+  prg_regs: [u8; 2],
+  chr_regs: [u8; 8],
+  /// $B003, only the mirroring bits of the default CHR mode are used
+  banking_control: u8,
+  irq: VrcIrq,
+  pulses: [Pulse; 2],
+  sawtooth: Sawtooth,
+  /// $9003: halt, and whether the channel periods are divided by 16 or 256
+  audio_control: u8,
+  prg_rom_banks: Membank,
+  chr_banks: Membank,
+}
+
+impl VRC6 {
+
+  pub fn load(rom: &ROM) -> Mapper {
+
+    let chr_size = if rom.has_chr_rom() { rom.chr_rom.len() } else { rom.chr_ram.len() };
+
+    let mut vrc6 = Self {
+      mirroring: rom.header.mirroring,
+      swap_lines: rom.header.mapper_id == 26,
+      prg_regs: [0; 2],
+      chr_regs: [0; 8],
+      banking_control: 0,
+      irq: VrcIrq::new(),
+      pulses: [Pulse::default(), Pulse::default()],
+      sawtooth: Sawtooth::default(),
+      audio_control: 0,
+      prg_rom_banks: Membank::new(PRG_ROM_START, PRG_ROM_END, rom.prg_rom.len(), 0x2000),
+      chr_banks: Membank::new(CHR_START, CHR_END, chr_size, 0x400),
+    };
+
+    vrc6.update_banks();
+    vrc6.into()
+
+  }
+
+  /// Register 0-3 within the 4 KB block `addr` is in
+  fn register(&self, addr: u16) -> u16 {
+    let register = addr & 0b11;
+    if self.swap_lines { register >> 1 | (register & 1) << 1 } else { register }
+  }
+
+  fn update_banks(&mut self) {
+    let last = self.prg_rom_banks.last();
+    self.prg_rom_banks.set_range(0, 1, (self.prg_regs[0] as usize & 0x0F) * 2);
+    self.prg_rom_banks.set(2, self.prg_regs[1] as usize & 0x1F);
+    self.prg_rom_banks.set(3, last);
+
+    for (slot, bank) in self.chr_regs.iter().enumerate() {
+      self.chr_banks.set(slot, *bank as usize);
+    }
+
+    self.mirroring = match self.banking_control >> 2 & 0b11 {
+      0 => ScreenMirroring::Vertical,
+      1 => ScreenMirroring::Horizontal,
+      2 => ScreenMirroring::SingleScreenLower,
+      _ => ScreenMirroring::SingleScreenUpper,
+    };
+  }
+
+  /// How far the channel periods are shifted down, and whether they're running at all
+  fn audio_shift(&self) -> Option<u8> {
+    match self.audio_control {
+      control if control & 1 != 0 => None,
+      control if control & 0b100 != 0 => Some(8),
+      control if control & 0b010 != 0 => Some(4),
+      _ => Some(0),
+    }
+  }
+
+}
+
+impl Map for VRC6 {
+
+  fn mirroring(&self) -> ScreenMirroring { self.mirroring }
+
+  fn set_mirroring(&mut self, mirroring: ScreenMirroring) { self.mirroring = mirroring; }
+
+  fn map_peak(&self, addr: u16) -> MappedRead {
+    match addr as usize {
+      CHR_START..=CHR_END => MappedRead::Chr(self.chr_banks.translate(addr)),
+      PRG_RAM_START..=PRG_RAM_END => MappedRead::PrgRAM(addr as usize & 0x1FFF),
+      PRG_ROM_START..=PRG_ROM_END => MappedRead::PrgROM(self.prg_rom_banks.translate(addr)),
+      _ => MappedRead::None,
+    }
+  }
+
+  fn map_write(&mut self, addr: u16, data: u8) -> MappedWrite {
+
+    let block = addr & 0xF000;
+    let register = self.register(addr);
+
+    match addr as usize {
+      CHR_START..=CHR_END => return MappedWrite::Chr(self.chr_banks.translate(addr), data),
+      PRG_RAM_START..=PRG_RAM_END => return MappedWrite::PrgRAM(addr as usize & 0x1FFF, data),
+      PRG_ROM_START..=PRG_ROM_END => match (block, register) {
+        (0x8000, _) => self.prg_regs[0] = data,
+        (0x9000, 3) => self.audio_control = data,
+        (0x9000, _) => self.pulses[0].write(register, data),
+        (0xA000, 3) => {},
+        (0xA000, _) => self.pulses[1].write(register, data),
+        (0xB000, 3) => self.banking_control = data,
+        (0xB000, _) => self.sawtooth.write(register, data),
+        (0xC000, _) => self.prg_regs[1] = data,
+        (0xD000, _) => self.chr_regs[register as usize] = data,
+        (0xE000, _) => self.chr_regs[4 + register as usize] = data,
+        (0xF000, 0) => self.irq.write_latch(data),
+        (0xF000, 1) => self.irq.write_control(data),
+        (0xF000, 2) => self.irq.acknowledge(),
+        _ => {},
+      },
+      _ => {},
+    }
+
+    self.update_banks();
+    MappedWrite::None
+
+  }
+
+  fn cpu_cycle(&mut self) {
+    self.irq.cpu_cycle();
+    if let Some(shift) = self.audio_shift() {
+      for pulse in self.pulses.iter_mut() {
+        pulse.clock(shift);
+      }
+      self.sawtooth.clock(shift);
+    }
+  }
+
+  fn irq_pending(&self) -> bool {
+    self.irq.pending()
+  }
+
+  fn audio_sample(&self) -> f32 {
+    let sum = self.pulses[0].output() + self.pulses[1].output() + self.sawtooth.output();
+    sum as f32 / MAX_OUTPUT
+  }
+
+  fn reset(&mut self, _kind: ResetKind) {
+    self.prg_regs = [0; 2];
+    self.chr_regs = [0; 8];
+    self.irq = VrcIrq::new();
+    self.pulses = [Pulse::default(), Pulse::default()];
+    self.sawtooth = Sawtooth::default();
+    self.audio_control = 0;
+    self.update_banks();
+  }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    state.write_bytes(&self.prg_regs);
+    state.write_bytes(&self.chr_regs);
+    state.write_u8(self.banking_control);
+    self.irq.save_state(state);
+    for pulse in self.pulses.iter() {
+      pulse.save_state(state);
+    }
+    self.sawtooth.save_state(state);
+    state.write_u8(self.audio_control);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    state.read_bytes(&mut self.prg_regs)?;
+    state.read_bytes(&mut self.chr_regs)?;
+    self.banking_control = state.read_u8()?;
+    self.irq.load_state(state)?;
+    for pulse in self.pulses.iter_mut() {
+      pulse.load_state(state)?;
+    }
+    self.sawtooth.load_state(state)?;
+    self.audio_control = state.read_u8()?;
+    self.update_banks();
+    Ok(())
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::mappers::tests::banked_rom;
+
+  #[test]
+  fn test_banks() {
+    let mut mapper = banked_rom(26).mapper;
+    let bank = |mapper: &Mapper, addr| match mapper.map_peak(addr) {
+      MappedRead::PrgROM(index) => index / 0x2000,
+      MappedRead::Chr(index) => index / 0x400,
+      _ => panic!("not mapped"),
+    };
+
+    mapper.map_write(0x8000, 3);
+    mapper.map_write(0xC000, 9);
+    assert_eq!([0x8000, 0xA000, 0xC000, 0xE000].map(|addr| bank(&mapper, addr)), [6, 7, 9, 15]);
+
+    // Mapper 26 has A0 and A1 the other way around
+    mapper.map_write(0xE002, 0x21);
+    assert_eq!(bank(&mapper, 0x1400), 0x21);
+
+    mapper.map_write(0xB003, 0b1000);
+    assert!(matches!(mapper.map_nametable(0x2C00), MappedRead::Vram(0x000)));
+  }
+
+  #[test]
+  fn test_pulse_duty() {
+    let mut mapper = banked_rom(24).mapper;
+    // Volume 15, duty 2 of 16, period 0 so every cycle is a step
+    mapper.map_write(0x9000, 0x2F);
+    mapper.map_write(0x9001, 0x00);
+    mapper.map_write(0x9002, 0x80);
+
+    let mut high = 0;
+    for _ in 0..16 {
+      mapper.cpu_cycle();
+      if mapper.audio_sample() > 0.0 {
+        high += 1;
+      }
+    }
+    assert_eq!(high, 3);
+
+    // Halted, the channels keep their output
+    mapper.map_write(0x9003, 0x01);
+    let sample = mapper.audio_sample();
+    mapper.cpu_cycle();
+    assert_eq!(mapper.audio_sample(), sample);
+  }
+
+  #[test]
+  fn test_sawtooth() {
+    let mut mapper = banked_rom(24).mapper;
+    mapper.map_write(0xB000, 0x08);
+    mapper.map_write(0xB002, 0x80);
+
+    // Six additions of 8 reach 48, the 14th step clears it
+    let mut peak = 0.0f32;
+    for _ in 0..13 {
+      mapper.cpu_cycle();
+      peak = peak.max(mapper.audio_sample());
+    }
+    assert_eq!(peak, 6.0 / MAX_OUTPUT);
+    mapper.cpu_cycle();
+    assert_eq!(mapper.audio_sample(), 0.0);
+  }
+
+}
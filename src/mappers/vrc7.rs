@@ -0,0 +1,182 @@
+use crate::cpu::ResetKind;
+use crate::{rom::{ROM, ScreenMirroring}, mem::Membank};
+use crate::savestate::{StateReader, StateWriter};
+
+use super::vrc_irq::VrcIrq;
+use super::{Map, Mapper, MappedRead, MappedWrite};
+
+const CHR_START: usize = 0x0000;
+const CHR_END: usize = 0x1FFF;
+const PRG_RAM_START: usize = 0x6000;
+const PRG_RAM_END: usize = 0x7FFF;
+const PRG_ROM_START: usize = 0x8000;
+const PRG_ROM_END: usize = 0xFFFF;
+
+/// Konami VRC7 (mapper 85), used by Lagrange Point and Tiny Toon Adventures 2 (J). Three
+/// switchable 8 KB PRG banks, eight 1 KB CHR banks and the VRC IRQ counter. Its FM sound
+/// isn't emulated, writes to the audio registers are dropped.
+pub struct VRC7 {
+  mirroring: ScreenMirroring,
+  /// The address line that selects the second register in each block, A4 on VRC7a and A3 on VRC7b
+  high_line: u16,
+  prg_regs: [u8; 3],
+  chr_regs: [u8; 8],
+  irq: VrcIrq,
+  prg_rom_banks: Membank,
+  chr_banks: Membank,
+}
+
+impl VRC7 {
+
+  pub fn load(rom: &ROM) -> Mapper {
+
+    let chr_size = if rom.has_chr_rom() { rom.chr_rom.len() } else { rom.chr_ram.len() };
+
+    let mut vrc7 = Self {
+      mirroring: rom.header.mirroring,
+      high_line: match rom.header.submapper {
+        1 => 0x08,
+        2 => 0x10,
+        _ => 0x18,
+      },
+      prg_regs: [0; 3],
+      chr_regs: [0; 8],
+      irq: VrcIrq::new(),
+      prg_rom_banks: Membank::new(PRG_ROM_START, PRG_ROM_END, rom.prg_rom.len(), 0x2000),
+      chr_banks: Membank::new(CHR_START, CHR_END, chr_size, 0x400),
+    };
+
+    vrc7.update_banks();
+    vrc7.into()
+
+  }
+
+  fn update_banks(&mut self) {
+    for (slot, bank) in self.prg_regs.iter().enumerate() {
+      self.prg_rom_banks.set(slot, *bank as usize);
+    }
+    let last = self.prg_rom_banks.last();
+    self.prg_rom_banks.set(3, last);
+
+    for (slot, bank) in self.chr_regs.iter().enumerate() {
+      self.chr_banks.set(slot, *bank as usize);
+    }
+  }
+
+}
+
+impl Map for VRC7 {
+
+  fn mirroring(&self) -> ScreenMirroring { self.mirroring }
+
+  fn set_mirroring(&mut self, mirroring: ScreenMirroring) { self.mirroring = mirroring; }
+
+  fn map_peak(&self, addr: u16) -> MappedRead {
+    match addr as usize {
+      CHR_START..=CHR_END => MappedRead::Chr(self.chr_banks.translate(addr)),
+      PRG_RAM_START..=PRG_RAM_END => MappedRead::PrgRAM(addr as usize & 0x1FFF),
+      PRG_ROM_START..=PRG_ROM_END => MappedRead::PrgROM(self.prg_rom_banks.translate(addr)),
+      _ => MappedRead::None,
+    }
+  }
+
+  fn map_write(&mut self, addr: u16, data: u8) -> MappedWrite {
+
+    let block = addr & 0xF000;
+    let high = addr & self.high_line != 0;
+
+    match addr as usize {
+      CHR_START..=CHR_END => return MappedWrite::Chr(self.chr_banks.translate(addr), data),
+      PRG_RAM_START..=PRG_RAM_END => return MappedWrite::PrgRAM(addr as usize & 0x1FFF, data),
+      PRG_ROM_START..=PRG_ROM_END => match (block, high) {
+        (0x8000, false) => self.prg_regs[0] = data & 0x3F,
+        (0x8000, true) => self.prg_regs[1] = data & 0x3F,
+        (0x9000, false) => self.prg_regs[2] = data & 0x3F,
+        (0x9000, true) => {},
+        (0xA000..=0xD000, _) => self.chr_regs[((block - 0xA000) / 0x1000 * 2) as usize + high as usize] = data,
+        (0xE000, false) => {
+          self.mirroring = match data & 0b11 {
+            0 => ScreenMirroring::Vertical,
+            1 => ScreenMirroring::Horizontal,
+            2 => ScreenMirroring::SingleScreenLower,
+            _ => ScreenMirroring::SingleScreenUpper,
+          };
+        },
+        (0xE000, true) => self.irq.write_latch(data),
+        (0xF000, false) => self.irq.write_control(data),
+        (0xF000, true) => self.irq.acknowledge(),
+        _ => {},
+      },
+      _ => {},
+    }
+
+    self.update_banks();
+    MappedWrite::None
+
+  }
+
+  fn cpu_cycle(&mut self) {
+    self.irq.cpu_cycle();
+  }
+
+  fn irq_pending(&self) -> bool {
+    self.irq.pending()
+  }
+
+  fn reset(&mut self, _kind: ResetKind) {
+    self.prg_regs = [0; 3];
+    self.chr_regs = [0; 8];
+    self.irq = VrcIrq::new();
+    self.update_banks();
+  }
+
+  fn save_state(&self, state: &mut StateWriter) {
+    state.write_mirroring(self.mirroring);
+    state.write_bytes(&self.prg_regs);
+    state.write_bytes(&self.chr_regs);
+    self.irq.save_state(state);
+  }
+
+  fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.mirroring = state.read_mirroring()?;
+    state.read_bytes(&mut self.prg_regs)?;
+    state.read_bytes(&mut self.chr_regs)?;
+    self.irq.load_state(state)?;
+    self.update_banks();
+    Ok(())
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::mappers::tests::banked_rom;
+
+  #[test]
+  fn test_banks_and_irq() {
+    let mut mapper = banked_rom(85).mapper;
+    let bank = |mapper: &Mapper, addr| match mapper.map_peak(addr) {
+      MappedRead::PrgROM(index) => index / 0x2000,
+      MappedRead::Chr(index) => index / 0x400,
+      _ => panic!("not mapped"),
+    };
+
+    // Without a submapper both A3 and A4 select the second register
+    mapper.map_write(0x8008, 5);
+    mapper.map_write(0x9000, 6);
+    mapper.map_write(0xD010, 0x33);
+    assert_eq!([0x8000, 0xA000, 0xC000, 0xE000].map(|addr| bank(&mapper, addr)), [0, 5, 6, 15]);
+    assert_eq!(bank(&mapper, 0x1C00), 0x33);
+
+    // Cycle mode, one cycle from overflowing
+    mapper.map_write(0xE010, 0xFF);
+    mapper.map_write(0xF000, 0b110);
+    mapper.cpu_cycle();
+    assert!(mapper.irq_pending());
+    mapper.map_write(0xF008, 0);
+    assert!(!mapper.irq_pending());
+  }
+
+}
@@ -0,0 +1,164 @@
+use crate::savestate::{StateReader, StateWriter};
+
+/// CPU cycles per scanline are 113 2/3, so the prescaler counts in thirds
+const PRESCALER_PERIOD: i16 = 341;
+
+/// The IRQ counter Konami put in VRC4, VRC6 and VRC7. It counts up from a latch and
+/// fires when it overflows, either every CPU cycle or once per scanline. Scanlines are
+/// timed with a CPU cycle prescaler rather than by watching the PPU, so it keeps
+/// counting through vblank and with rendering off.
+#[derive(Debug, Clone)]
+pub(super) struct VrcIrq {
+  latch: u8,
+  counter: u8,
+  prescaler: i16,
+  enabled: bool,
+  /// Whether acknowledging the IRQ enables it again
+  enable_after_ack: bool,
+  cycle_mode: bool,
+  pending: bool,
+}
+
+impl VrcIrq {
+
+  pub(super) const fn new() -> Self {
+    Self {
+      latch: 0,
+      counter: 0,
+      prescaler: PRESCALER_PERIOD,
+      enabled: false,
+      enable_after_ack: false,
+      cycle_mode: false,
+      pending: false,
+    }
+  }
+
+  pub(super) fn pending(&self) -> bool {
+    self.pending
+  }
+
+  pub(super) fn write_latch(&mut self, data: u8) {
+    self.latch = data;
+  }
+
+  /// VRC4 takes the latch a nibble at a time
+  pub(super) fn write_latch_low(&mut self, data: u8) {
+    self.latch = self.latch & 0xF0 | data & 0x0F;
+  }
+
+  pub(super) fn write_latch_high(&mut self, data: u8) {
+    self.latch = self.latch & 0x0F | (data & 0x0F) << 4;
+  }
+
+  pub(super) fn write_control(&mut self, data: u8) {
+    self.enable_after_ack = data & 0b001 != 0;
+    self.enabled = data & 0b010 != 0;
+    self.cycle_mode = data & 0b100 != 0;
+    self.pending = false;
+    if self.enabled {
+      self.counter = self.latch;
+      self.prescaler = PRESCALER_PERIOD;
+    }
+  }
+
+  pub(super) fn acknowledge(&mut self) {
+    self.pending = false;
+    self.enabled = self.enable_after_ack;
+  }
+
+  pub(super) fn cpu_cycle(&mut self) {
+    if !self.enabled {
+      return;
+    }
+    if self.cycle_mode {
+      self.clock();
+      return;
+    }
+    self.prescaler -= 3;
+    if self.prescaler <= 0 {
+      self.prescaler += PRESCALER_PERIOD;
+      self.clock();
+    }
+  }
+
+  fn clock(&mut self) {
+    if self.counter == 0xFF {
+      self.counter = self.latch;
+      self.pending = true;
+    } else {
+      self.counter += 1;
+    }
+  }
+
+  pub(super) fn save_state(&self, state: &mut StateWriter) {
+    state.write_u8(self.latch);
+    state.write_u8(self.counter);
+    state.write_u16(self.prescaler as u16);
+    state.write_bool(self.enabled);
+    state.write_bool(self.enable_after_ack);
+    state.write_bool(self.cycle_mode);
+    state.write_bool(self.pending);
+  }
+
+  pub(super) fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.latch = state.read_u8()?;
+    self.counter = state.read_u8()?;
+    self.prescaler = state.read_u16()? as i16;
+    self.enabled = state.read_bool()?;
+    self.enable_after_ack = state.read_bool()?;
+    self.cycle_mode = state.read_bool()?;
+    self.pending = state.read_bool()?;
+    Ok(())
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_cycle_mode() {
+    let mut irq = VrcIrq::new();
+    irq.write_latch(0xFD);
+    irq.write_control(0b111);
+
+    // 0xFD, 0xFE, 0xFF, then the overflow
+    for _ in 0..2 {
+      irq.cpu_cycle();
+    }
+    assert!(!irq.pending());
+    irq.cpu_cycle();
+    assert!(irq.pending());
+
+    // Acknowledging keeps it running because bit 0 was set
+    irq.acknowledge();
+    for _ in 0..3 {
+      irq.cpu_cycle();
+    }
+    assert!(irq.pending());
+  }
+
+  #[test]
+  fn test_scanline_mode() {
+    let mut irq = VrcIrq::new();
+    irq.write_latch(0xFE);
+    irq.write_control(0b010);
+
+    // Two scanlines of 113 2/3 cycles
+    for _ in 0..227 {
+      irq.cpu_cycle();
+    }
+    assert!(!irq.pending());
+    irq.cpu_cycle();
+    assert!(irq.pending());
+
+    irq.acknowledge();
+    for _ in 0..1000 {
+      irq.cpu_cycle();
+    }
+    assert!(!irq.pending());
+  }
+
+}
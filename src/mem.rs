@@ -1,5 +1,7 @@
 use std::cmp::max;
 
+use crate::savestate::{StateReader, StateWriter};
+
 pub trait Mem {
 
   fn mem_read_u8(&mut self, addr: u16) -> u8;
@@ -12,6 +14,16 @@ pub trait Mem {
 
   fn mem_write_u8(&mut self, addr: u16, data: u8);
 
+  /// Reads a byte without any of the side effects a real read would have,
+  /// like clearing vblank or shifting the controller. For tracing and debugging.
+  fn mem_peek_u8(&self, addr: u16) -> u8;
+
+  fn mem_peek_u16(&self, addr: u16) -> u16 {
+      let lsb: u16 = self.mem_peek_u8(addr) as u16;
+      let msb = self.mem_peek_u8(addr.wrapping_add(1)) as u16;
+      (msb << 8) | lsb
+  }
+
   fn mem_write_u16(&mut self, addr: u16, data: u16) {
       let msb = (data >> 8) as u8;
       let lsb = (data & 0xff) as u8;
@@ -83,6 +95,19 @@ impl Membank {
     page | (addr as usize) & (self.window - 1)
   }
 
+  pub fn save_state(&self, state: &mut StateWriter) {
+    for bank in self.banks.iter() {
+      state.write_usize(*bank);
+    }
+  }
+
+  pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    for bank in self.banks.iter_mut() {
+      *bank = state.read_usize()?;
+    }
+    Ok(())
+  }
+
 }
 
 #[cfg(test)]
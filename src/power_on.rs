@@ -0,0 +1,135 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Palette RAM contents read back from a real console after power on
+const HARDWARE_PALETTE: [u8; 32] = [
+  0x09, 0x01, 0x00, 0x01, 0x00, 0x02, 0x02, 0x0D, 0x08, 0x10, 0x08, 0x24, 0x00, 0x00, 0x04, 0x2C,
+  0x09, 0x01, 0x34, 0x03, 0x00, 0x04, 0x00, 0x14, 0x08, 0x3A, 0x00, 0x02, 0x00, 0x20, 0x2C, 0x08,
+];
+
+/// Memories that power up with undefined contents
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+  CpuRam,
+  PrgRam,
+  ChrRam,
+  Oam,
+  Palette,
+}
+
+/// What RAM holds when the console is switched on. Real hardware leaves it semi-random,
+/// which some games rely on for seeding and others trip over when it isn't cleared.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum PowerOnState {
+  #[default]
+  Zero,
+  Ones,
+  /// Random bytes from the given seed, the same every run
+  Random(u64),
+  /// Runs of four 0x00 and four 0xFF bytes, common on front-loading consoles,
+  /// and the palette a real console was measured powering up with
+  Hardware,
+}
+
+impl std::str::FromStr for PowerOnState {
+  type Err = String;
+
+  /// `zero`, `ones`, `hardware`, `random` with a fresh seed, or `random:<seed>`
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_ascii_lowercase().as_str() {
+      "zero" => Ok(PowerOnState::Zero),
+      "ones" | "ff" => Ok(PowerOnState::Ones),
+      "hardware" => Ok(PowerOnState::Hardware),
+      "random" => Ok(PowerOnState::Random(rand::random())),
+      other => match other.strip_prefix("random:") {
+        Some(seed) => seed.parse().map(PowerOnState::Random).map_err(|_| format!("Invalid seed \"{}\"", seed)),
+        None => Err(format!("Unknown power on state \"{}\", expected zero, ones, hardware, random or random:<seed>", s)),
+      },
+    }
+  }
+}
+
+impl std::fmt::Display for PowerOnState {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    match self {
+      PowerOnState::Zero => write!(f, "zero"),
+      PowerOnState::Ones => write!(f, "ones"),
+      PowerOnState::Random(seed) => write!(f, "random:{}", seed),
+      PowerOnState::Hardware => write!(f, "hardware"),
+    }
+  }
+}
+
+impl PowerOnState {
+
+  /// Fills `memory` as `region` would power up. Each region gets its own random stream,
+  /// so resizing one doesn't change what the others contain.
+  pub fn fill(&self, region: Region, memory: &mut [u8]) {
+    match self {
+      PowerOnState::Zero => memory.fill(0),
+      PowerOnState::Ones => memory.fill(0xFF),
+      PowerOnState::Random(seed) => {
+        let mut rng = StdRng::seed_from_u64(seed.wrapping_add(region as u64));
+        rng.fill(memory);
+      },
+      PowerOnState::Hardware => match region {
+        Region::Palette => {
+          for (byte, value) in memory.iter_mut().zip(HARDWARE_PALETTE.iter().cycle()) {
+            *byte = *value;
+          }
+        },
+        _ => {
+          for (i, byte) in memory.iter_mut().enumerate() {
+            *byte = if i & 4 == 0 { 0x00 } else { 0xFF };
+          }
+        },
+      },
+    }
+
+    // Palette RAM is only 6 bits wide
+    if region == Region::Palette {
+      memory.iter_mut().for_each(|byte| *byte &= 0x3F);
+    }
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_seeded_random_is_reproducible() {
+    let mut first = [0; 64];
+    let mut second = [0; 64];
+
+    PowerOnState::Random(1234).fill(Region::CpuRam, &mut first);
+    PowerOnState::Random(1234).fill(Region::CpuRam, &mut second);
+    assert_eq!(first, second);
+
+    PowerOnState::Random(1234).fill(Region::Oam, &mut second);
+    assert_ne!(first, second);
+  }
+
+  #[test]
+  fn test_patterns() {
+    let mut ram = [0x12; 10];
+    PowerOnState::Hardware.fill(Region::CpuRam, &mut ram);
+    assert_eq!(ram, [0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0]);
+
+    let mut palette = [0; 32];
+    PowerOnState::Ones.fill(Region::Palette, &mut palette);
+    assert_eq!(palette, [0x3F; 32]);
+  }
+
+  #[test]
+  fn test_parse() {
+    assert_eq!("random:42".parse::<PowerOnState>().unwrap(), PowerOnState::Random(42));
+    assert_eq!("Hardware".parse::<PowerOnState>().unwrap(), PowerOnState::Hardware);
+    assert!(matches!("random".parse::<PowerOnState>().unwrap(), PowerOnState::Random(_)));
+    assert!("random:abc".parse::<PowerOnState>().is_err());
+    assert_eq!(PowerOnState::Random(7).to_string(), "random:7");
+  }
+
+}
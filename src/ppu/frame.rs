@@ -1,27 +1,101 @@
+use super::palette::Palette;
+
+/// Byte layouts `Frame::convert` can produce, for whatever the frontend's texture expects
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PixelFormat {
+  RGB24,
+  RGBA32,
+  BGRA32,
+}
+
+impl PixelFormat {
+
+  pub fn bytes_per_pixel(&self) -> usize {
+    match self {
+      PixelFormat::RGB24 => 3,
+      PixelFormat::RGBA32 | PixelFormat::BGRA32 => 4,
+    }
+  }
+
+}
+
+/// A picture as the PPU outputs it, before any palette is applied. Each pixel
+/// is `emphasis << 6 | color`: the palette RAM value plus the PPUMASK emphasis bits.
 #[derive(Default)]
 pub struct Frame {
-  pub data: Vec<u8>
+  pub pixels: Vec<u16>,
 }
 
 impl Frame {
 
-  const FRAME_WIDTH: usize = 256;
-  const FRAME_HEIGHT: usize = 240;
+  pub const FRAME_WIDTH: usize = 256;
+  pub const FRAME_HEIGHT: usize = 240;
 
   pub fn new() -> Self {
     Frame {
-      data: vec![0; (Frame::FRAME_WIDTH) * (Frame::FRAME_HEIGHT) * 3],
+      pixels: vec![0; (Frame::FRAME_WIDTH) * (Frame::FRAME_HEIGHT)],
     }
   }
 
-  pub fn set_pixel(&mut self, x: usize, y: usize, rgb: (u8, u8, u8)) {
+  pub fn set_pixel(&mut self, x: usize, y: usize, index: u16) {
+    if x < Frame::FRAME_WIDTH {
+      if let Some(pixel) = self.pixels.get_mut(y * Frame::FRAME_WIDTH + x) {
+        *pixel = index;
+      }
+    }
+  }
+
+  pub fn pixel(&self, x: usize, y: usize) -> u16 {
+    self.pixels[y * Frame::FRAME_WIDTH + x]
+  }
+
+  /// Looks every pixel up in `palette` and writes it to `out` in `format`,
+  /// which must hold `FRAME_WIDTH * FRAME_HEIGHT * format.bytes_per_pixel()` bytes
+  pub fn convert(&self, palette: &Palette, format: PixelFormat, out: &mut [u8]) {
 
-    let base = y * 3 * Frame::FRAME_WIDTH + x * 3;
+    for (pixel, bytes) in self.pixels.iter().zip(out.chunks_exact_mut(format.bytes_per_pixel())) {
 
-    if base+2 < self.data.len() {
-      self.data[base] = rgb.0;
-      self.data[base+1] = rgb.1;
-      self.data[base+2] = rgb.2;
+      let (r, g, b) = palette.color(*pixel as u8 & 0x3F, (*pixel >> 6) as u8);
+
+      match format {
+        PixelFormat::RGB24 => bytes.copy_from_slice(&[r, g, b]),
+        PixelFormat::RGBA32 => bytes.copy_from_slice(&[r, g, b, 0xFF]),
+        PixelFormat::BGRA32 => bytes.copy_from_slice(&[b, g, r, 0xFF]),
+      }
     }
   }
-}
\ No newline at end of file
+
+  /// `convert` into a newly allocated buffer
+  pub fn to_bytes(&self, palette: &Palette, format: PixelFormat) -> Vec<u8> {
+    let mut out = vec![0; self.pixels.len() * format.bytes_per_pixel()];
+    self.convert(palette, format, &mut out);
+    out
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_convert_formats() {
+    let palette = Palette::default();
+    let mut frame = Frame::new();
+    frame.set_pixel(1, 0, 0b001 << 6 | 0x21);
+
+    let (r, g, b) = palette.color(0x21, 0b001);
+
+    let rgb = frame.to_bytes(&palette, PixelFormat::RGB24);
+    assert_eq!(rgb.len(), Frame::FRAME_WIDTH * Frame::FRAME_HEIGHT * 3);
+    assert_eq!(rgb[3..6], [r, g, b]);
+
+    let rgba = frame.to_bytes(&palette, PixelFormat::RGBA32);
+    assert_eq!(rgba[4..8], [r, g, b, 0xFF]);
+
+    let bgra = frame.to_bytes(&palette, PixelFormat::BGRA32);
+    assert_eq!(bgra[4..8], [b, g, r, 0xFF]);
+    assert_eq!(bgra[0..4], [palette.color(0, 0).2, palette.color(0, 0).1, palette.color(0, 0).0, 0xFF]);
+  }
+
+}
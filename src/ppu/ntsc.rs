@@ -0,0 +1,208 @@
+use super::frame::Frame;
+use super::palette::{self, NtscParams};
+
+/// The PPU outputs 8 signal samples per pixel, at 12 samples per color cycle
+const SAMPLES_PER_PIXEL: usize = 8;
+const SAMPLES_PER_LINE: usize = Frame::FRAME_WIDTH * SAMPLES_PER_PIXEL;
+const SAMPLES_PER_CYCLE: usize = 12;
+
+/// A scanline is 341 dots, so each one starts 341 * 8 % 12 = 4 samples further into the color cycle
+const LINE_PHASE_STEP: usize = 4;
+
+/// Frames alternate between 89342 and 89341 dots with rendering on,
+/// which moves the color cycle by 4 samples and back again: the dot crawl
+const FRAME_PHASE_STEP: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NtscPreset {
+  /// Luma and chroma share one signal. The decoder can't fully separate them,
+  /// so sharp edges get color fringes that crawl from frame to frame.
+  Composite,
+  /// Luma and chroma are carried separately. No crosstalk, but chroma still bleeds.
+  SVideo,
+}
+
+impl std::str::FromStr for NtscPreset {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.to_ascii_lowercase().as_str() {
+      "composite" => Ok(NtscPreset::Composite),
+      "svideo" | "s-video" => Ok(NtscPreset::SVideo),
+      _ => Err(format!("Unknown NTSC filter \"{}\", expected composite or svideo", s)),
+    }
+  }
+}
+
+/// Re-creates the picture a TV would decode from the PPU's video signal, from the
+/// indexed pixels of a `Frame`. Output is RGB24 at twice the horizontal resolution.
+pub struct NtscFilter {
+  preset: NtscPreset,
+  params: NtscParams,
+  /// Samples averaged for luma. A full color cycle cancels out the chroma of flat areas.
+  luma_window: usize,
+  /// Samples averaged for chroma. Wider than a pixel, which is where the color bleed comes from.
+  chroma_window: usize,
+  frame_phase: usize,
+  /// Per-color average signal level, the luma an S-Video cable carries on its own wire
+  luma: Vec<f32>,
+}
+
+impl NtscFilter {
+
+  pub const WIDTH: usize = Frame::FRAME_WIDTH * 2;
+  pub const HEIGHT: usize = Frame::FRAME_HEIGHT;
+
+  pub fn new(preset: NtscPreset, params: NtscParams) -> Self {
+
+    let luma = (0..512)
+      .map(|pixel| (0..SAMPLES_PER_CYCLE).map(|phase| palette::signal_level(pixel, phase)).sum::<f32>() / SAMPLES_PER_CYCLE as f32)
+      .collect();
+
+    NtscFilter {
+      preset,
+      params,
+      luma_window: SAMPLES_PER_CYCLE,
+      chroma_window: match preset {
+        NtscPreset::Composite => SAMPLES_PER_CYCLE * 3,
+        NtscPreset::SVideo => SAMPLES_PER_CYCLE * 2,
+      },
+      frame_phase: 0,
+      luma,
+    }
+  }
+
+  pub fn preset(&self) -> NtscPreset { self.preset }
+
+  /// Filters one frame into `out`, `WIDTH * HEIGHT * 3` bytes of RGB24.
+  /// Call once per emulated frame, the color phase advances with every call.
+  pub fn apply(&mut self, frame: &Frame, out: &mut [u8]) {
+
+    let mut luma = vec![0.0; SAMPLES_PER_LINE];
+    let mut chroma = vec![0.0; SAMPLES_PER_LINE];
+
+    for y in 0..Frame::FRAME_HEIGHT {
+
+      let line_phase = (self.frame_phase + y * LINE_PHASE_STEP) % SAMPLES_PER_CYCLE;
+
+      for t in 0..SAMPLES_PER_LINE {
+        let pixel = frame.pixel(t / SAMPLES_PER_PIXEL, y) as usize & 0x1FF;
+        let signal = palette::signal_level(pixel, (line_phase + t) % SAMPLES_PER_CYCLE);
+        match self.preset {
+          NtscPreset::Composite => {
+            luma[t] = signal;
+            chroma[t] = signal;
+          },
+          NtscPreset::SVideo => {
+            luma[t] = self.luma[pixel];
+            chroma[t] = signal - self.luma[pixel];
+          },
+        }
+      }
+
+      self.decode_line(&luma, &chroma, line_phase, &mut out[y * NtscFilter::WIDTH * 3..(y + 1) * NtscFilter::WIDTH * 3]);
+    }
+
+    if self.preset == NtscPreset::Composite {
+      self.frame_phase = (self.frame_phase + FRAME_PHASE_STEP) % (FRAME_PHASE_STEP * 2);
+    }
+  }
+
+  fn decode_line(&self, luma: &[f32], chroma: &[f32], line_phase: usize, out: &mut [u8]) {
+
+    // Running sums, so every window average is a single subtraction
+    let mut y_sum = vec![0.0; SAMPLES_PER_LINE + 1];
+    let mut i_sum = vec![0.0; SAMPLES_PER_LINE + 1];
+    let mut q_sum = vec![0.0; SAMPLES_PER_LINE + 1];
+
+    for t in 0..SAMPLES_PER_LINE {
+      let angle = palette::chroma_angle((line_phase + t) % SAMPLES_PER_CYCLE, &self.params);
+      y_sum[t + 1] = y_sum[t] + luma[t];
+      i_sum[t + 1] = i_sum[t] + chroma[t] * angle.cos();
+      q_sum[t + 1] = q_sum[t] + chroma[t] * angle.sin();
+    }
+
+    // Past the edges of the picture the signal is at blanking level, which is black
+    let average = |sums: &[f32], center: usize, window: usize| {
+      let start = (center as isize - window as isize / 2).clamp(0, SAMPLES_PER_LINE as isize) as usize;
+      let end = (center + window / 2).min(SAMPLES_PER_LINE);
+      (sums[end] - sums[start]) / window as f32
+    };
+
+    let samples_per_output = SAMPLES_PER_LINE / NtscFilter::WIDTH;
+
+    for (x, rgb) in out.chunks_exact_mut(3).enumerate() {
+      let center = x * samples_per_output + samples_per_output / 2;
+      let y = average(&y_sum, center, self.luma_window);
+      let i = average(&i_sum, center, self.chroma_window);
+      let q = average(&q_sum, center, self.chroma_window);
+      let (r, g, b) = palette::yiq_to_rgb(y, i, q, &self.params);
+      rgb.copy_from_slice(&[r, g, b]);
+    }
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::ppu::palette::Palette;
+
+  fn frame_with(pixel: impl Fn(usize, usize) -> u16) -> Frame {
+    let mut frame = Frame::new();
+    for y in 0..Frame::FRAME_HEIGHT {
+      for x in 0..Frame::FRAME_WIDTH {
+        frame.set_pixel(x, y, pixel(x, y));
+      }
+    }
+    frame
+  }
+
+  fn output_pixel(out: &[u8], x: usize, y: usize) -> (u8, u8, u8) {
+    let base = (y * NtscFilter::WIDTH + x) * 3;
+    (out[base], out[base + 1], out[base + 2])
+  }
+
+  #[test]
+  fn test_flat_color_matches_palette() {
+    let palette = Palette::ntsc(&NtscParams::default());
+    let frame = frame_with(|_, _| 0x16);
+    let mut out = vec![0; NtscFilter::WIDTH * NtscFilter::HEIGHT * 3];
+
+    for preset in [NtscPreset::Composite, NtscPreset::SVideo] {
+      NtscFilter::new(preset, NtscParams::default()).apply(&frame, &mut out);
+      let (r, g, b) = output_pixel(&out, 256, 120);
+      let (pr, pg, pb) = palette.color(0x16, 0);
+      assert!(r.abs_diff(pr) <= 1 && g.abs_diff(pg) <= 1 && b.abs_diff(pb) <= 1, "{:?}", preset);
+    }
+  }
+
+  #[test]
+  fn test_composite_dot_crawl() {
+    // Thin white and black stripes: composite turns them into colored fringes that move every frame
+    let frame = frame_with(|x, _| if x % 2 == 0 { 0x30 } else { 0x0F });
+    let mut first = vec![0; NtscFilter::WIDTH * NtscFilter::HEIGHT * 3];
+    let mut second = first.clone();
+
+    let mut filter = NtscFilter::new(NtscPreset::Composite, NtscParams::default());
+    filter.apply(&frame, &mut first);
+    filter.apply(&frame, &mut second);
+    assert_ne!(first, second);
+
+    let mut filter = NtscFilter::new(NtscPreset::SVideo, NtscParams::default());
+    filter.apply(&frame, &mut first);
+    filter.apply(&frame, &mut second);
+    assert_eq!(first, second);
+    let (r, g, b) = output_pixel(&first, 256, 120);
+    assert!(r == g && g == b, "S-Video has no luma to chroma crosstalk");
+  }
+
+  #[test]
+  fn test_parse_preset() {
+    assert_eq!("Composite".parse::<NtscPreset>().unwrap(), NtscPreset::Composite);
+    assert_eq!("s-video".parse::<NtscPreset>().unwrap(), NtscPreset::SVideo);
+    assert!("rgb".parse::<NtscPreset>().is_err());
+  }
+
+}
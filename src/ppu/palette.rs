@@ -1,3 +1,10 @@
+use std::fs;
+use std::path::Path;
+
+/// Each emphasis bit darkens the two other channels by this much.
+/// Measured on an NTSC PPU, see <https://www.nesdev.org/wiki/NTSC_video>
+const EMPHASIS_ATTENUATION: f32 = 0.816328;
+
 pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
     (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96), (0xA1, 0x00, 0x5E), 
     (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00), (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00), 
@@ -12,4 +19,231 @@ pub static SYSTEM_PALLETE: [(u8,u8,u8); 64] = [
     (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB), (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0), 
     (0xFF, 0xEF, 0xA6), (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA), 
     (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11)
-];
\ No newline at end of file
+];
+
+/// Expands a 64-color palette to all 8 emphasis combinations.
+/// Bit 0 of the emphasis is red, bit 1 green and bit 2 blue, as in PPUMASK.
+pub fn emphasis_palette(palette: &[(u8, u8, u8); 64]) -> [(u8, u8, u8); 512] {
+
+  let mut result = [(0, 0, 0); 512];
+
+  for (i, rgb) in result.iter_mut().enumerate() {
+
+    let (r, g, b) = palette[i & 0x3F];
+    let emphasis = i >> 6;
+
+    // Columns $xE and $xF are forced black and unaffected by emphasis
+    if emphasis == 0 || i & 0x0E == 0x0E {
+      *rgb = (r, g, b);
+      continue;
+    }
+
+    // Emphasizing a channel darkens the other two. With all three bits set, everything is darkened
+    let channel = |value: u8, bit: usize| {
+      if emphasis & bit == 0 || emphasis == 0b111 {
+        (value as f32 * EMPHASIS_ATTENUATION).round() as u8
+      } else {
+        value
+      }
+    };
+
+    *rgb = (channel(r, 0b001), channel(g, 0b010), channel(b, 0b100));
+  }
+
+  result
+}
+
+/// The RGB value of every palette RAM color under every emphasis combination,
+/// indexed by `emphasis << 6 | color`
+pub struct Palette {
+  colors: Vec<(u8, u8, u8)>,
+}
+
+impl Default for Palette {
+  fn default() -> Self {
+    Palette { colors: emphasis_palette(&SYSTEM_PALLETE).to_vec() }
+  }
+}
+
+impl Palette {
+
+  /// Reads a `.pal` file: 64 RGB triplets (192 bytes), or 512 (1536 bytes)
+  /// when it includes the emphasized colors, like the ones FCEUX and Nestopia export
+  pub fn from_bytes(bytes: &[u8]) -> Result<Palette, String> {
+
+    let triplets = bytes.chunks_exact(3).map(|rgb| (rgb[0], rgb[1], rgb[2]));
+
+    match bytes.len() {
+      192 => {
+        let mut colors = [(0, 0, 0); 64];
+        for (color, rgb) in colors.iter_mut().zip(triplets) {
+          *color = rgb;
+        }
+        Ok(Palette { colors: emphasis_palette(&colors).to_vec() })
+      },
+      1536 => Ok(Palette { colors: triplets.collect() }),
+      len => Err(format!("Palette must be 192 or 1536 bytes, found {}", len)),
+    }
+  }
+
+  pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Palette, String> {
+    let path = path.as_ref();
+    let Ok(bytes) = fs::read(path) else {
+      return Err(format!("Unable to read palette \"{}\"", path.to_string_lossy()));
+    };
+    Palette::from_bytes(&bytes)
+  }
+
+  /// Computes every color by decoding the composite signal the PPU would output for it
+  pub fn ntsc(params: &NtscParams) -> Palette {
+    Palette { colors: (0..512).map(|pixel| ntsc_color(pixel, params)).collect() }
+  }
+
+  /// RGB for a palette RAM value under the given PPUMASK emphasis bits
+  pub fn color(&self, index: u8, emphasis: u8) -> (u8, u8, u8) {
+    self.colors[((emphasis as usize & 0b111) << 6) | (index as usize & 0x3F)]
+  }
+
+}
+
+/// Decoder settings for `Palette::ntsc`. The defaults decode the signal as measured,
+/// hue is in degrees and the rest are multipliers, except brightness which is an offset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NtscParams {
+  pub hue: f32,
+  pub saturation: f32,
+  pub contrast: f32,
+  pub brightness: f32,
+}
+
+impl Default for NtscParams {
+  fn default() -> Self {
+    NtscParams { hue: 0.0, saturation: 1.0, contrast: 1.0, brightness: 0.0 }
+  }
+}
+
+/// Composite voltages for the low and high half of the square wave, by luma level
+/// <https://www.nesdev.org/wiki/NTSC_video>
+const SIGNAL_LOW: [f32; 4] =  [0.228, 0.312, 0.552, 0.880];
+const SIGNAL_HIGH: [f32; 4] = [0.616, 0.840, 1.100, 1.100];
+const SIGNAL_BLACK: f32 = 0.312;
+const SIGNAL_WHITE: f32 = 1.100;
+const SIGNAL_EMPHASIS_ATTENUATION: f32 = 0.746;
+
+/// Chroma phase of color $x8 relative to the color burst, in twelfths of a cycle
+const BURST_PHASE: f32 = 4.0;
+
+/// Normalized level (0.0 black, 1.0 white) of the composite signal the PPU outputs
+/// for `pixel` (`emphasis << 6 | color`) at `phase`, in twelfths of a color cycle.
+/// The PPU generates a square wave whose phase is the hue and whose levels are the brightness.
+pub(crate) fn signal_level(pixel: usize, phase: usize) -> f32 {
+
+  let color = pixel & 0x0F;
+  let emphasis = pixel >> 6;
+  // Colors $xE and $xF always output the level of $1D
+  let level = if color > 13 { 1 } else { (pixel >> 4) & 3 };
+
+  let low = if color == 0 { SIGNAL_HIGH[level] } else { SIGNAL_LOW[level] };
+  let high = if color > 12 { SIGNAL_LOW[level] } else { SIGNAL_HIGH[level] };
+
+  let in_phase = |color: usize| (color + phase) % 12 < 6;
+
+  let mut signal = if in_phase(color) { high } else { low };
+  if (emphasis & 1 != 0 && in_phase(0)) || (emphasis & 2 != 0 && in_phase(4)) || (emphasis & 4 != 0 && in_phase(8)) {
+    signal *= SIGNAL_EMPHASIS_ATTENUATION;
+  }
+
+  (signal - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK)
+}
+
+/// Angle of the I axis at `phase`, for demodulating the chroma
+pub(crate) fn chroma_angle(phase: usize, params: &NtscParams) -> f32 {
+  std::f32::consts::PI * (phase as f32 + BURST_PHASE + params.hue / 30.0) / 6.0
+}
+
+/// Applies the decoder knobs to a demodulated YIQ color and converts it to RGB
+pub(crate) fn yiq_to_rgb(y: f32, i: f32, q: f32, params: &NtscParams) -> (u8, u8, u8) {
+
+  let y = y * params.contrast + params.brightness;
+  let i = i * params.saturation * params.contrast;
+  let q = q * params.saturation * params.contrast;
+
+  let to_u8 = |value: f32| (value * 255.0).round().clamp(0.0, 255.0) as u8;
+  (
+    to_u8(y + 0.946882 * i + 0.623557 * q),
+    to_u8(y - 0.274788 * i - 0.635691 * q),
+    to_u8(y - 1.108545 * i + 1.709007 * q),
+  )
+}
+
+fn ntsc_color(pixel: usize, params: &NtscParams) -> (u8, u8, u8) {
+
+  // Average one full color cycle of 12 samples
+  let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+  for phase in 0..12 {
+    let signal = signal_level(pixel, phase);
+    let angle = chroma_angle(phase, params);
+    y += signal;
+    i += signal * angle.cos();
+    q += signal * angle.sin();
+  }
+
+  yiq_to_rgb(y / 12.0, i / 12.0, q / 12.0, params)
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_pal_files() {
+    let mut bytes = vec![0; 192];
+    bytes[0x16 * 3..0x16 * 3 + 3].copy_from_slice(&[0xFF, 0x00, 0x00]);
+    let palette = Palette::from_bytes(&bytes).unwrap();
+    assert_eq!(palette.color(0x16, 0), (0xFF, 0x00, 0x00));
+    assert_eq!(palette.color(0x16, 0b001), (0xFF, 0x00, 0x00));
+    assert_eq!(palette.color(0x16, 0b010), (0xD0, 0x00, 0x00));
+
+    let mut bytes = vec![0; 1536];
+    bytes[(0b010 << 6 | 0x16) * 3] = 0x12;
+    let palette = Palette::from_bytes(&bytes).unwrap();
+    assert_eq!(palette.color(0x16, 0b010), (0x12, 0x00, 0x00));
+
+    assert!(Palette::from_bytes(&[0; 100]).is_err());
+  }
+
+  #[test]
+  fn test_ntsc_palette() {
+    let palette = Palette::ntsc(&NtscParams::default());
+
+    assert_eq!(palette.color(0x20, 0), (0xFF, 0xFF, 0xFF));
+    assert_eq!(palette.color(0x0F, 0), (0x00, 0x00, 0x00));
+
+    // Hue lands where the usual palettes have it
+    let (r, g, b) = palette.color(0x16, 0);
+    assert!(r > g && r > b, "0x16 should be red");
+    let (r, g, b) = palette.color(0x1A, 0);
+    assert!(g > r && g > b, "0x1A should be green");
+    let (r, g, b) = palette.color(0x12, 0);
+    assert!(b > r && b > g, "0x12 should be blue");
+
+    let gray = Palette::ntsc(&NtscParams { saturation: 0.0, ..NtscParams::default() });
+    let (r, g, b) = gray.color(0x16, 0);
+    assert!(r == g && g == b);
+
+    let brighter = Palette::ntsc(&NtscParams { brightness: 0.1, ..NtscParams::default() });
+    assert!(brighter.color(0x00, 0).0 > palette.color(0x00, 0).0);
+  }
+
+  #[test]
+  fn test_emphasis_palette() {
+    let palette = emphasis_palette(&SYSTEM_PALLETE);
+
+    assert_eq!(palette[0x21], SYSTEM_PALLETE[0x21]);
+    assert_eq!(palette[0b100 << 6 | 0x20], (0xD0, 0xD0, 0xFF));
+    assert_eq!(palette[0b111 << 6 | 0x20], (0xD0, 0xD0, 0xD0));
+    assert_eq!(palette[0b001 << 6 | 0x0F], SYSTEM_PALLETE[0x0F]);
+  }
+
+}
@@ -1,3 +1,5 @@
+use crate::savestate::{StateReader, StateWriter};
+
 #[derive(Default)]
 pub struct AddressRegister {
   value: (u8, u8),
@@ -55,6 +57,17 @@ impl AddressRegister {
     self.hi_ptr = true;
   }
 
+  pub fn save_state(&self, state: &mut StateWriter) {
+    state.write_u16(self.get());
+    state.write_bool(self.hi_ptr);
+  }
+
+  pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.set(state.read_u16()?);
+    self.hi_ptr = state.read_bool()?;
+    Ok(())
+  }
+
 }
 
 #[cfg(test)]
@@ -27,6 +27,11 @@ impl ControlRegister {
     self.insert(ControlRegister::from_bits_truncate(data));
   }
 
+  /// Base address of the nametable scrolling starts from
+  pub fn nametable_address(&self) -> u16 {
+    0x2000 + 0x400 * (self.bits() & 0b11) as u16
+  }
+
   pub fn get_vram_addr_increment(&self) -> u8 {
     if self.contains(ControlRegister::VRAM_ADD_INCREMENT) {
       32
@@ -55,6 +60,14 @@ impl ControlRegister {
     }
   }
 
+  pub fn sprite_size(&self) -> u8 {
+    if self.contains(ControlRegister::SPRITE_SIZE) {
+      16
+    } else {
+      8
+    }
+  }
+
 }
 
 #[cfg(test)]
@@ -110,4 +123,15 @@ mod tests{
 
   }
 
+  #[test]
+  fn test_get_sprite_size() {
+
+    let mut reg = ControlRegister::default();
+    assert_eq!(reg.sprite_size(), 8);
+
+    reg.insert(ControlRegister::SPRITE_SIZE);
+    assert_eq!(reg.sprite_size(), 16);
+
+  }
+
 }
\ No newline at end of file
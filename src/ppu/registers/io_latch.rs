@@ -0,0 +1,85 @@
+use crate::savestate::{StateReader, StateWriter};
+
+/// Frames a latch bit holds its charge without being driven, about 600 ms
+const DECAY_FRAMES: u8 = 36;
+
+/// The PPU's I/O data bus. Every register write and most reads drive it, and reading
+/// a write-only register returns whatever it last held. Each bit decays to 0 on its
+/// own when it isn't refreshed, so partial reads like PPUSTATUS keep stale low bits alive.
+#[derive(Default)]
+pub struct IoLatch {
+  value: u8,
+  /// Frames since each bit was last driven
+  age: [u8; 8],
+}
+
+impl IoLatch {
+
+  pub fn new() -> Self {
+    IoLatch { value: 0, age: [0; 8] }
+  }
+
+  pub fn get(&self) -> u8 {
+    self.value
+  }
+
+  /// Drives the bits in `mask` with `data`, leaving the others as they were
+  pub fn set(&mut self, data: u8, mask: u8) {
+    self.value = self.value & !mask | data & mask;
+    for (bit, age) in self.age.iter_mut().enumerate() {
+      if mask >> bit & 1 == 1 {
+        *age = 0;
+      }
+    }
+  }
+
+  /// Called once per frame
+  pub fn decay(&mut self) {
+    for (bit, age) in self.age.iter_mut().enumerate() {
+      *age = age.saturating_add(1);
+      if *age >= DECAY_FRAMES {
+        self.value &= !(1 << bit);
+      }
+    }
+  }
+
+  pub fn save_state(&self, state: &mut StateWriter) {
+    state.write_u8(self.value);
+    state.write_bytes(&self.age);
+  }
+
+  pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.value = state.read_u8()?;
+    state.read_bytes(&mut self.age)
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_bits_decay_separately() {
+
+    let mut latch = IoLatch::new();
+    latch.set(0xFF, 0xFF);
+
+    for _ in 0..DECAY_FRAMES - 1 {
+      latch.decay();
+    }
+    latch.set(0x0F, 0x0F);
+    assert_eq!(latch.get(), 0xFF);
+
+    latch.decay();
+    assert_eq!(latch.get(), 0x0F);
+
+    for _ in 0..DECAY_FRAMES {
+      latch.decay();
+    }
+    assert_eq!(latch.get(), 0);
+
+  }
+
+}
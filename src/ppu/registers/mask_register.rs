@@ -29,4 +29,17 @@ impl MaskRegister {
 
   pub fn grayscale(&self) -> bool { self.contains(MaskRegister::GRAYSCALE) }
 
+  pub fn show_background(&self) -> bool { self.contains(MaskRegister::SHOW_BG) }
+
+  pub fn show_sprites(&self) -> bool { self.contains(MaskRegister::SHOW_SPR) }
+
+  pub fn show_left_background(&self) -> bool { self.contains(MaskRegister::SHOW_LEFT_BG) }
+
+  pub fn show_left_sprites(&self) -> bool { self.contains(MaskRegister::SHOW_LEFT_SPR) }
+
+  pub fn rendering_enabled(&self) -> bool { self.intersects(MaskRegister::SHOW_BG | MaskRegister::SHOW_SPR) }
+
+  /// The red, green and blue emphasis bits, in that order from bit 0
+  pub fn emphasis(&self) -> u8 { self.bits() >> 5 }
+
 }
\ No newline at end of file
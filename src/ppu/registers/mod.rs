@@ -1,4 +1,6 @@
 pub mod address_register;
 pub mod control_register;
 pub mod status_register;
-pub mod mask_register;
\ No newline at end of file
+pub mod mask_register;
+pub mod scroll_register;
+pub mod io_latch;
\ No newline at end of file
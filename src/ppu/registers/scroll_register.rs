@@ -0,0 +1,69 @@
+use crate::savestate::{StateReader, StateWriter};
+
+/// PPUSCROLL (0x2005): two writes, X then Y, into the nametable selected by PPUCTRL
+#[derive(Default)]
+pub struct ScrollRegister {
+  pub scroll_x: u8,
+  pub scroll_y: u8,
+  latch: bool,
+}
+
+impl ScrollRegister {
+
+  pub fn new() -> Self {
+    ScrollRegister { scroll_x: 0, scroll_y: 0, latch: false }
+  }
+
+  pub fn write(&mut self, data: u8) {
+
+    if !self.latch {
+      self.scroll_x = data;
+    } else {
+      self.scroll_y = data;
+    }
+
+    self.latch = !self.latch;
+
+  }
+
+  pub fn reset_latch(&mut self) {
+    self.latch = false;
+  }
+
+  pub fn save_state(&self, state: &mut StateWriter) {
+    state.write_u8(self.scroll_x);
+    state.write_u8(self.scroll_y);
+    state.write_bool(self.latch);
+  }
+
+  pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), String> {
+    self.scroll_x = state.read_u8()?;
+    self.scroll_y = state.read_u8()?;
+    self.latch = state.read_bool()?;
+    Ok(())
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_write_x_then_y() {
+
+    let mut reg = ScrollRegister::new();
+
+    reg.write(0x12);
+    reg.write(0x34);
+    assert_eq!((reg.scroll_x, reg.scroll_y), (0x12, 0x34));
+
+    reg.write(0x56);
+    reg.reset_latch();
+    reg.write(0x78);
+    assert_eq!((reg.scroll_x, reg.scroll_y), (0x78, 0x34));
+
+  }
+
+}
@@ -28,6 +28,10 @@ impl StatusRegister {
     self.remove(StatusRegister::VBLANK_STARTED);
   }
 
+  pub fn set_sprite_overflow(&mut self, value: bool) {
+    self.set(StatusRegister::SPRITE_OVERFLOW, value);
+  }
+
   pub fn is_in_vblank(&self) -> bool {
     self.contains(StatusRegister::VBLANK_STARTED)
   }
@@ -1,11 +1,12 @@
-use crate::palette;
-use crate::Frame;
+use crate::mappers::{Map, RenderEvent};
+
+use super::frame::Frame;
 
 use super::PPU;
 
-fn bg_pallette(ppu: &PPU, tile_column: usize, tile_row : usize) -> [u8;4] {
+fn bg_pallette(ppu: &mut PPU, tile_column: usize, tile_row : usize) -> [u8;4] {
   let attr_table_idx = tile_row / 4 * 8 +  tile_column / 4;
-  let attr_byte = ppu.vram[0x3c0 + attr_table_idx];  // note: still using hardcoded first nametable
+  let attr_byte = ppu.read_nametable(0x23C0 + attr_table_idx as u16);  // note: still using hardcoded first nametable
 
   let pallet_idx = match (tile_column %4 / 2, tile_row % 4 / 2) {
       (0,0) => attr_byte & 0b11,
@@ -29,150 +30,137 @@ fn sprite_palette(ppu: &PPU, pallete_idx: u8) -> [u8; 4] {
   ]
 }
 
-pub fn render(ppu: &PPU, frame: &mut Frame) {
+/// One row of a tile as eight 2-bit color values, leftmost pixel first
+fn fetch_tile_row(ppu: &mut PPU, bank: u16, tile_idx: u16, row: u16) -> [u8; 8] {
+  let start = bank + tile_idx * 16 + row;
+  let lower = ppu.read_chr(start);
+  let upper = ppu.read_chr(start + 8);
+
+  let mut pixels = [0; 8];
+  for (x, pixel) in pixels.iter_mut().enumerate() {
+    let shift = 7 - x;
+    *pixel = ((upper >> shift) & 1) << 1 | ((lower >> shift) & 1);
+  }
+  pixels
+}
+
+/// Hardware only fetches this many sprites per scanline
+const SPRITES_PER_LINE: usize = 8;
+
+/// Draws scanline `y` into `ppu.frame`. Called by the PPU as each visible scanline
+/// ends, so pattern fetches see whatever CHR banks the mapper has selected at that point.
+pub fn render_scanline(ppu: &mut PPU, y: usize) {
 
   let bank = ppu.control.background_pattern_address();
+  let tile_y = y / 8;
+  let row = (y % 8) as u16;
 
-  for i in 0..0x3C0 {
+  // Palette RAM value of every pixel, starting out as the backdrop color
+  let mut colors = [ppu.palette_table[0]; Frame::FRAME_WIDTH];
+  // Sprites with the priority bit set only show through color 0 of the background
+  let mut bg_opaque = [false; Frame::FRAME_WIDTH];
+  // The PPU fetches both layers whenever either is on, so mappers watching the
+  // fetches see them even when one layer is hidden
+  let rendering = ppu.mask.rendering_enabled();
 
-    let tile = ppu.vram[i] as u16;
-    let tile_x = i % 32;
-    let tile_y = i / 32;
-    let tile = &ppu.chr_rom[(bank+tile*16) as usize..=(bank+tile*16+15) as usize];
-    let palette = bg_pallette(ppu, tile_x, tile_y);
+  for tile_x in 0..32 {
 
-    for y in 0..=7 {
-
-      let mut upper = tile[y];
-      let mut lower = tile[y+8];
-  
-      for x in (0..=7).rev() {
-  
-        let value = (1&lower) << 1 | (1&upper);
-  
-        upper >>= 1;
-        lower >>= 1;
-  
-        let rgb = match value {
-          0 => palette::SYSTEM_PALLETE[palette[0] as usize],
-          1 => palette::SYSTEM_PALLETE[palette[1] as usize],
-          2 => palette::SYSTEM_PALLETE[palette[2] as usize],
-          3 => palette::SYSTEM_PALLETE[palette[3] as usize],
-          _ => panic!(""),
-        };
-  
-        frame.set_pixel(tile_x * 8 + x, tile_y * 8 + y, rgb)
-      }
+    if !rendering {
+      break;
     }
-  }
 
-  // Sprites
-  for i in (0..ppu.oam_data.len()).step_by(4).rev() {
-    let tile_idx = ppu.oam_data[i + 1] as u16;
-    let tile_x = ppu.oam_data[i + 3] as usize;
-    let tile_y = ppu.oam_data[i] as usize;
+    ppu.mapper.render_event(RenderEvent::BackgroundTile(tile_x as u8));
+    let tile = ppu.read_nametable(0x2000 + (tile_y * 32 + tile_x) as u16) as u16;  // note: still using hardcoded first nametable
+    let palette = bg_pallette(ppu, tile_x, tile_y);
+    let pixels = fetch_tile_row(ppu, bank, tile, row);
 
-    let flip_vertical = ppu.oam_data[i + 2] >> 7 & 1 == 1;
-    let flip_horizontal = ppu.oam_data[i + 2] >> 6 & 1 == 1;
-    let pallette_idx = ppu.oam_data[i + 2] & 0b11;
-    let sprite_palette = sprite_palette(ppu, pallette_idx);
-   
-    let bank: u16 = ppu.control.sprite_pattern_address();
-
-    let tile = &ppu.chr_rom[(bank + tile_idx * 16) as usize..=(bank + tile_idx * 16 + 15) as usize];
-
-
-    for y in 0..=7 {
-      let mut upper = tile[y];
-      let mut lower = tile[y + 8];
-      'ololo: for x in (0..=7).rev() {
-        let value = (1 & lower) << 1 | (1 & upper);
-        upper >>= 1;
-        lower >>= 1;
-        let rgb = match value {
-          0 => continue 'ololo, // skip coloring the pixel
-          1 => palette::SYSTEM_PALLETE[sprite_palette[1] as usize],
-          2 => palette::SYSTEM_PALLETE[sprite_palette[2] as usize],
-          3 => palette::SYSTEM_PALLETE[sprite_palette[3] as usize],
-          _ => panic!("can't be"),
-        };
-        match (flip_horizontal, flip_vertical) {
-          (false, false) => frame.set_pixel(tile_x + x, tile_y + y, rgb),
-          (true, false) => frame.set_pixel(tile_x + 7 - x, tile_y + y, rgb),
-          (false, true) => frame.set_pixel(tile_x + x, tile_y + 7 - y, rgb),
-          (true, true) => frame.set_pixel(tile_x + 7 - x, tile_y + 7 - y, rgb),
-        }
+    for (x, value) in pixels.iter().enumerate() {
+      let x = tile_x * 8 + x;
+      if *value != 0 && ppu.mask.show_background() && (x >= 8 || ppu.mask.show_left_background()) {
+        bg_opaque[x] = true;
+        colors[x] = palette[*value as usize];
       }
     }
   }
-}
-
-pub fn show_tile(chr_rom: &[u8], bank: usize, tile_num: usize) -> Frame {
 
-  let mut frame = Frame::new();
-  let bank = bank * 0x1000;
-  
-  let tile = &chr_rom[(bank+tile_num*16)..=(bank+tile_num*16+15)];
-
-  for y in 0..=7 {
+  // For every pixel, the first sprite in OAM order with an opaque pixel there
+  let mut sprite_pixels: [Option<(u8, bool)>; Frame::FRAME_WIDTH] = [None; Frame::FRAME_WIDTH];
+  let height = ppu.control.sprite_size() as usize;
+  let mut found = 0;
+  if rendering {
+    ppu.mapper.render_event(RenderEvent::Sprites { tall: height == 16 });
+  }
 
-    let mut upper = tile[y];
-    let mut lower = tile[y+8];
+  for i in (0..ppu.oam_data.len()).step_by(4) {
 
-    for x in (0..=7).rev() {
+    if !rendering {
+      break;
+    }
 
-      let value = (1&upper) << 1 | (1&lower);
+    let tile_y = ppu.oam_data[i] as usize;
+    if y < tile_y || y >= tile_y + height {
+      continue;
+    }
 
-      upper >>= 1;
-      lower >>= 1;
+    found += 1;
+    if found > SPRITES_PER_LINE {
+      ppu.status.set_sprite_overflow(true);
+      if ppu.sprite_limit {
+        break;
+      }
+    }
 
-      let rgb = match value {
-        0 => palette::SYSTEM_PALLETE[0x01],
-        1 => palette::SYSTEM_PALLETE[0x23],
-        2 => palette::SYSTEM_PALLETE[0x27],
-        3 => palette::SYSTEM_PALLETE[0x30],
-        _ => panic!(""),
-      };
+    let tile_idx = ppu.oam_data[i + 1] as u16;
+    let attributes = ppu.oam_data[i + 2];
+    let tile_x = ppu.oam_data[i + 3] as usize;
 
-      frame.set_pixel(x, y, rgb);
+    let flip_vertical = attributes >> 7 & 1 == 1;
+    let flip_horizontal = attributes >> 6 & 1 == 1;
+    let behind_background = attributes >> 5 & 1 == 1;
+    let pallette_idx = attributes & 0b11;
+
+    let row = y - tile_y;
+    let row = if flip_vertical { height - 1 - row } else { row };
+
+    // 8x16 sprites take their pattern table from bit 0 of the tile index,
+    // and the bottom half is the tile after the top one
+    let (bank, tile_idx) = match height {
+      16 => ((tile_idx & 1) * 0x1000, (tile_idx & 0xFE) + (row / 8) as u16),
+      _ => (ppu.control.sprite_pattern_address(), tile_idx),
+    };
+    let pixels = fetch_tile_row(ppu, bank, tile_idx, (row % 8) as u16);
+    let sprite_palette = sprite_palette(ppu, pallette_idx);
 
+    for (x, value) in pixels.iter().enumerate() {
+      // Color 0 is transparent
+      if *value == 0 {
+        continue;
+      }
+      let x = if flip_horizontal { tile_x + 7 - x } else { tile_x + x };
+      if x < 8 && !ppu.mask.show_left_sprites() {
+        continue;
+      }
+      if x < Frame::FRAME_WIDTH && sprite_pixels[x].is_none() {
+        sprite_pixels[x] = Some((sprite_palette[*value as usize], behind_background));
+      }
     }
   }
-  frame
-}
 
-pub fn show_tile_bank(chr_rom: &[u8], bank: usize) -> Frame {
-  let mut frame = Frame::new();
-    let mut tile_y = 0;
-    let mut tile_x = 0;
-    let bank = bank * 0x1000;
-
-    for tile_n in 0..255 {
-        if tile_n != 0 && tile_n % 20 == 0 {
-            tile_y += 10;
-            tile_x = 0;
-        }
-        let tile = &chr_rom[(bank + tile_n * 16)..=(bank + tile_n * 16 + 15)];
-
-        for y in 0..=7 {
-            let mut upper = tile[y];
-            let mut lower = tile[y + 8];
-
-            for x in (0..=7).rev() {
-                let value = (1 & upper) << 1 | (1 & lower);
-                upper >>= 1;
-                lower >>= 1;
-                let rgb = match value {
-                    0 => palette::SYSTEM_PALLETE[0x01],
-                    1 => palette::SYSTEM_PALLETE[0x23],
-                    2 => palette::SYSTEM_PALLETE[0x27],
-                    3 => palette::SYSTEM_PALLETE[0x30],
-                    _ => unreachable!("You shouldn't be here!"),
-                };
-                frame.set_pixel(tile_x + x, tile_y + y, rgb)
-            }
-        }
-        tile_x += 10;
+  if ppu.mask.show_sprites() {
+    for (x, pixel) in sprite_pixels.iter().enumerate() {
+      match pixel {
+        Some((color, behind)) if !(*behind && bg_opaque[x]) => colors[x] = *color,
+        _ => {},
+      }
     }
-    frame
-}
\ No newline at end of file
+  }
+
+  // Grayscale keeps only the brightness column of the palette
+  let color_mask = if ppu.mask.grayscale() { 0x30 } else { 0x3F };
+  let emphasis = ppu.mask.emphasis();
+
+  for (x, color) in colors.iter().enumerate() {
+    let color = color & color_mask;
+    ppu.frame.set_pixel(x, y, (emphasis as u16) << 6 | color as u16);
+  }
+}
@@ -0,0 +1,337 @@
+use std::fmt;
+use std::path::Path;
+
+use crate::capture;
+
+use super::palette::Palette;
+use super::{palette_index, PPU};
+
+/// Color of the scroll viewport outline in `nametables`
+const VIEWPORT_COLOR: (u8, u8, u8) = (0xFF, 0x00, 0xFF);
+
+/// Pixels between neighbouring sprites in `sprites`
+const SPRITE_SPACING: usize = 2;
+
+/// An RGB24 picture of some part of the PPU's state, for debugging
+pub struct View {
+  pub width: usize,
+  pub height: usize,
+  pub rgb: Vec<u8>,
+}
+
+impl View {
+
+  fn new(width: usize, height: usize) -> Self {
+    View { width, height, rgb: vec![0; width * height * 3] }
+  }
+
+  fn set_pixel(&mut self, x: usize, y: usize, (r, g, b): (u8, u8, u8)) {
+    if x < self.width && y < self.height {
+      let base = (y * self.width + x) * 3;
+      self.rgb[base..base + 3].copy_from_slice(&[r, g, b]);
+    }
+  }
+
+  pub fn pixel(&self, x: usize, y: usize) -> (u8, u8, u8) {
+    let base = (y * self.width + x) * 3;
+    (self.rgb[base], self.rgb[base + 1], self.rgb[base + 2])
+  }
+
+  pub fn save_png<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+    capture::write_png(path, self.width, self.height, &self.rgb)
+  }
+
+}
+
+/// One OAM entry, decoded
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Sprite {
+  pub index: usize,
+  pub x: u8,
+  pub y: u8,
+  pub tile: u8,
+  /// Sprite palette 0-3
+  pub palette: u8,
+  pub behind_background: bool,
+  pub flip_horizontal: bool,
+  pub flip_vertical: bool,
+}
+
+impl fmt::Display for Sprite {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "#{:02} X:{:3} Y:{:3} Tile:{:02X} Pal:{} {} {}{}",
+      self.index,
+      self.x,
+      self.y,
+      self.tile,
+      self.palette,
+      if self.behind_background { "Back " } else { "Front" },
+      if self.flip_horizontal { "H" } else { "-" },
+      if self.flip_vertical { "V" } else { "-" },
+    )
+  }
+}
+
+/// All 64 sprites in OAM order
+pub fn oam_sprites(ppu: &PPU) -> Vec<Sprite> {
+  ppu.oam_data
+    .chunks_exact(4)
+    .enumerate()
+    .map(|(index, entry)| Sprite {
+      index,
+      y: entry[0],
+      tile: entry[1],
+      palette: entry[2] & 0b11,
+      behind_background: entry[2] >> 5 & 1 == 1,
+      flip_horizontal: entry[2] >> 6 & 1 == 1,
+      flip_vertical: entry[2] >> 7 & 1 == 1,
+      x: entry[3],
+    })
+    .collect()
+}
+
+/// One row of a tile as eight 2-bit color values. Peeks, so viewing
+/// pattern data doesn't trip mappers that watch the PPU's fetches.
+fn peek_tile_row(ppu: &PPU, bank: u16, tile_idx: u16, row: u16) -> [u8; 8] {
+  let start = bank + tile_idx * 16 + row;
+  let lower = ppu.peek_chr(start);
+  let upper = ppu.peek_chr(start + 8);
+
+  let mut pixels = [0; 8];
+  for (x, pixel) in pixels.iter_mut().enumerate() {
+    let shift = 7 - x;
+    *pixel = ((upper >> shift) & 1) << 1 | ((lower >> shift) & 1);
+  }
+  pixels
+}
+
+/// The four colors of palette 0-3 (background) or 4-7 (sprites), as stored in palette RAM.
+/// PPUMASK grayscale and emphasis are left out so the viewers show what the game wrote.
+fn palette_colors(ppu: &PPU, palette: &Palette, palette_idx: u8) -> [(u8, u8, u8); 4] {
+  let mut colors = [(0, 0, 0); 4];
+  for (value, color) in colors.iter_mut().enumerate() {
+    let entry = ppu.palette_table[palette_index(0x3F00 + (palette_idx as u16 & 0b111) * 4 + value as u16)];
+    *color = palette.color(entry & 0x3F, 0);
+  }
+  colors
+}
+
+/// Draws a tile with its top left corner at `(x, y)`, color 0 included
+fn draw_tile(view: &mut View, ppu: &PPU, bank: u16, tile_idx: u16, colors: &[(u8, u8, u8); 4], x: usize, y: usize) {
+  for row in 0..8 {
+    for (column, value) in peek_tile_row(ppu, bank, tile_idx, row).iter().enumerate() {
+      view.set_pixel(x + column, y + row as usize, colors[*value as usize]);
+    }
+  }
+}
+
+/// Both pattern tables side by side, 256x128, colored with palette 0-7
+pub fn pattern_tables(ppu: &PPU, palette: &Palette, palette_idx: u8) -> View {
+
+  let mut view = View::new(256, 128);
+  let colors = palette_colors(ppu, palette, palette_idx);
+
+  for table in 0..2 {
+    for tile in 0..256 {
+      let x = table * 128 + tile % 16 * 8;
+      let y = tile / 16 * 8;
+      draw_tile(&mut view, ppu, table as u16 * 0x1000, tile as u16, &colors, x, y);
+    }
+  }
+  view
+}
+
+/// All four nametables as laid out in 0x2000-0x2FFF, 512x480, after mirroring.
+/// The part on screen, going by PPUCTRL and PPUSCROLL, is outlined and wraps around the edges.
+pub fn nametables(ppu: &PPU, palette: &Palette) -> View {
+
+  let mut view = View::new(512, 480);
+  let bank = ppu.control.background_pattern_address();
+  let nametable_byte = |addr: u16| ppu.peek_nametable(addr);
+
+  for nametable in 0..4u16 {
+
+    let base = 0x2000 + nametable * 0x400;
+    let origin_x = (nametable & 1) as usize * 256;
+    let origin_y = (nametable >> 1) as usize * 240;
+
+    for tile_y in 0..30 {
+      for tile_x in 0..32 {
+        let tile = nametable_byte(base + tile_y * 32 + tile_x);
+        let attribute = nametable_byte(base + 0x3C0 + tile_y / 4 * 8 + tile_x / 4);
+        let shift = (tile_y % 4 / 2) * 4 + (tile_x % 4 / 2) * 2;
+        let colors = palette_colors(ppu, palette, attribute >> shift & 0b11);
+        draw_tile(&mut view, ppu, bank, tile as u16, &colors, origin_x + tile_x as usize * 8, origin_y + tile_y as usize * 8);
+      }
+    }
+  }
+
+  let (scroll_x, scroll_y) = ppu.scroll_origin();
+  for offset in 0..256 {
+    view.set_pixel((scroll_x + offset) % 512, scroll_y, VIEWPORT_COLOR);
+    view.set_pixel((scroll_x + offset) % 512, (scroll_y + 239) % 480, VIEWPORT_COLOR);
+  }
+  for offset in 0..240 {
+    view.set_pixel(scroll_x, (scroll_y + offset) % 480, VIEWPORT_COLOR);
+    view.set_pixel((scroll_x + 255) % 512, (scroll_y + offset) % 480, VIEWPORT_COLOR);
+  }
+  view
+}
+
+/// The 64 OAM sprites in an 8x8 grid, in OAM order, drawn with their own palette and flips.
+/// 8x16 sprites are shown whole when PPUCTRL selects them.
+pub fn sprites(ppu: &PPU, palette: &Palette) -> View {
+
+  let height = ppu.control.sprite_size() as usize;
+  let (cell_width, cell_height) = (8 + SPRITE_SPACING, height + SPRITE_SPACING);
+  let mut view = View::new(cell_width * 8, cell_height * 8);
+
+  for sprite in oam_sprites(ppu) {
+
+    let colors = palette_colors(ppu, palette, 4 + sprite.palette);
+    let cell_x = sprite.index % 8 * cell_width;
+    let cell_y = sprite.index / 8 * cell_height;
+
+    for row in 0..height {
+      let source_row = if sprite.flip_vertical { height - 1 - row } else { row };
+      let (bank, tile) = match height {
+        16 => ((sprite.tile as u16 & 1) * 0x1000, (sprite.tile as u16 & 0xFE) + (source_row / 8) as u16),
+        _ => (ppu.control.sprite_pattern_address(), sprite.tile as u16),
+      };
+      for (column, value) in peek_tile_row(ppu, bank, tile, (source_row % 8) as u16).iter().enumerate() {
+        let column = if sprite.flip_horizontal { 7 - column } else { column };
+        view.set_pixel(cell_x + column, cell_y + row, colors[*value as usize]);
+      }
+    }
+  }
+  view
+}
+
+/// The 32 palette RAM entries as 16x16 swatches, background palettes on the top row
+pub fn palette_ram(ppu: &PPU, palette: &Palette) -> View {
+
+  let mut view = View::new(256, 32);
+
+  for entry in 0..32 {
+    let color = palette.color(ppu.palette_table[palette_index(0x3F00 + entry as u16)] & 0x3F, 0);
+    for y in 0..16 {
+      for x in 0..16 {
+        view.set_pixel(entry % 16 * 16 + x, entry / 16 * 16 + y, color);
+      }
+    }
+  }
+  view
+}
+
+/// Writes every view to `dir` as `{prefix}-patterns.png`, `-nametables.png`,
+/// `-sprites.png` and `-palettes.png`, plus the sprite attributes as `{prefix}-oam.txt`
+pub fn save_views<P: AsRef<Path>>(ppu: &PPU, palette: &Palette, palette_idx: u8, dir: P, prefix: &str) -> Result<(), String> {
+
+  let dir = dir.as_ref();
+  pattern_tables(ppu, palette, palette_idx).save_png(dir.join(format!("{}-patterns.png", prefix)))?;
+  nametables(ppu, palette).save_png(dir.join(format!("{}-nametables.png", prefix)))?;
+  sprites(ppu, palette).save_png(dir.join(format!("{}-sprites.png", prefix)))?;
+  palette_ram(ppu, palette).save_png(dir.join(format!("{}-palettes.png", prefix)))?;
+
+  let oam: String = oam_sprites(ppu).iter().map(|sprite| format!("{}\n", sprite)).collect();
+  let path = dir.join(format!("{}-oam.txt", prefix));
+  std::fs::write(&path, oam).map_err(|e| format!("Unable to write \"{}\": {}", path.to_string_lossy(), e))
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::rom::ROM;
+  use crate::rom::tests::test_chr_ram_rom;
+
+  /// A PPU with CHR-RAM, where tile 1 of the first pattern table is solid color 3,
+  /// and palette RAM holds `0x0F` (backdrop) and `0x10 + n` for every other entry
+  fn viewer_ppu() -> PPU {
+    let rom: ROM = test_chr_ram_rom();
+    let mut ppu = PPU::new();
+    ppu.load_mapper(rom.mapper);
+    ppu.load_chr_ram(rom.chr_ram);
+    ppu.load_chr_rom(rom.chr_rom);
+
+    ppu.chr_ram[16..32].fill(0xFF);
+    for entry in 0..32u16 {
+      ppu.palette_table[palette_index(0x3F00 + entry)] = if entry % 4 == 0 { 0x0F } else { 0x10 + entry as u8 };
+    }
+    ppu
+  }
+
+  #[test]
+  fn test_pattern_tables_use_selected_palette() {
+    let ppu = viewer_ppu();
+    let palette = Palette::default();
+
+    let view = pattern_tables(&ppu, &palette, 2);
+    assert_eq!((view.width, view.height), (256, 128));
+    assert_eq!(view.pixel(0, 0), palette.color(0x0F, 0));
+    assert_eq!(view.pixel(8, 0), palette.color(0x10 + 2 * 4 + 3, 0));
+
+    let view = pattern_tables(&ppu, &palette, 5);
+    assert_eq!(view.pixel(15, 7), palette.color(0x10 + 5 * 4 + 3, 0));
+  }
+
+  #[test]
+  fn test_nametables_outline_viewport() {
+    let mut ppu = viewer_ppu();
+    let palette = Palette::default();
+
+    // Second nametable, scrolled by (16, 8)
+    ppu.update_ctrl_register(0b01);
+    ppu.write_to_scroll_register(16);
+    ppu.write_to_scroll_register(8);
+    assert_eq!(ppu.scroll_origin(), (272, 8));
+
+    let view = nametables(&ppu, &palette);
+    assert_eq!(view.pixel(272, 8), VIEWPORT_COLOR);
+    assert_eq!(view.pixel(272 + 255, 100), VIEWPORT_COLOR);
+    // The right edge wraps around into the first nametable
+    assert_eq!(view.pixel((272 + 250) % 512, 8 + 239), VIEWPORT_COLOR);
+    assert_eq!(view.pixel(100, 100), palette.color(0x0F, 0));
+  }
+
+  #[test]
+  fn test_oam_sprites() {
+    let mut ppu = viewer_ppu();
+    let palette = Palette::default();
+    ppu.write_oam_addr(4);
+    for byte in [0x20, 0x01, 0b0110_0010, 0x30] {
+      ppu.write_oam_data(byte);
+    }
+
+    let sprite = oam_sprites(&ppu)[1];
+    assert_eq!(sprite, Sprite {
+      index: 1,
+      x: 0x30,
+      y: 0x20,
+      tile: 0x01,
+      palette: 2,
+      behind_background: true,
+      flip_horizontal: true,
+      flip_vertical: false,
+    });
+    assert_eq!(sprite.to_string(), "#01 X: 48 Y: 32 Tile:01 Pal:2 Back  H-");
+
+    let view = sprites(&ppu, &palette);
+    assert_eq!(view.pixel(8 + SPRITE_SPACING, 0), palette.color(0x10 + 6 * 4 + 3, 0));
+  }
+
+  #[test]
+  fn test_palette_ram_mirrors_backdrop() {
+    let ppu = viewer_ppu();
+    let palette = Palette::default();
+
+    let view = palette_ram(&ppu, &palette);
+    assert_eq!(view.pixel(16 + 8, 8), palette.color(0x11, 0));
+    // 0x3F10 is the same byte as 0x3F00
+    assert_eq!(view.pixel(8, 16 + 8), palette.color(0x0F, 0));
+    assert_eq!(view.pixel(15 * 16, 31), palette.color(0x2F, 0));
+  }
+
+}
@@ -0,0 +1,374 @@
+use std::collections::VecDeque;
+
+use crate::cpu::CPU;
+use crate::gamepad::gamepad_register::JoypadButton;
+use crate::savestate::{StateReader, StateWriter};
+
+/// An older snapshot, stored as the difference between it and the snapshot taken after it
+struct Snapshot {
+  frame: usize,
+  delta: Vec<u8>,
+}
+
+/// Ring of machine snapshots used to rewind gameplay.
+///
+/// A full snapshot is taken every `interval` frames. Only the newest one is kept
+/// whole; every older one is XORed against its successor and run-length encoded,
+/// so walking backwards just means undoing one delta at a time. The controller
+/// state of every frame is recorded alongside, which lets `rewind` land on any
+/// frame by replaying forward from the nearest snapshot.
+/// The oldest snapshots are dropped once the buffer grows past `budget` bytes.
+pub struct RewindBuffer {
+  interval: usize,
+  budget: usize,
+  latest: Vec<u8>,
+  latest_frame: usize,
+  history: VecDeque<Snapshot>,
+  history_size: usize,
+  inputs: VecDeque<JoypadButton>,
+  inputs_start: usize,
+}
+
+impl RewindBuffer {
+
+  pub fn new(interval: usize, budget: usize) -> Self {
+    RewindBuffer {
+      interval: interval.max(1),
+      budget,
+      latest: vec![],
+      latest_frame: 0,
+      history: VecDeque::new(),
+      history_size: 0,
+      inputs: VecDeque::new(),
+      inputs_start: 0,
+    }
+  }
+
+  /// Bytes currently held, compared against the budget
+  pub fn memory_used(&self) -> usize {
+    self.latest.len() + self.history_size + self.inputs.len()
+  }
+
+  /// Oldest frame that can still be rewound to, if anything has been recorded
+  pub fn oldest_frame(&self) -> Option<usize> {
+    if self.latest.is_empty() {
+      return None;
+    }
+    Some(self.history.front().map_or(self.latest_frame, |snapshot| snapshot.frame))
+  }
+
+  /// Call once per completed frame, at an instruction boundary.
+  /// Records the controller state and takes a snapshot every `interval` frames.
+  pub fn record(&mut self, cpu: &CPU) {
+
+    let frame = cpu.bus.frame_count();
+    self.record_input(frame, cpu.bus.gamepad_state());
+
+    if frame.is_multiple_of(self.interval) && (self.latest.is_empty() || frame > self.latest_frame) {
+      let mut state = StateWriter::new();
+      cpu.save_state(&mut state);
+      self.push(frame, state.into_bytes());
+    }
+
+  }
+
+  /// Moves the machine back by `frames` frames, or as far as the buffer reaches.
+  /// Everything recorded after the frame that is landed on is discarded.
+  /// Returns `false` if there was nothing to rewind to.
+  pub fn rewind(&mut self, cpu: &mut CPU, frames: usize) -> bool {
+
+    let Some(oldest) = self.oldest_frame() else {
+      return false;
+    };
+
+    let target = cpu.bus.frame_count().saturating_sub(frames).max(oldest);
+
+    while self.latest_frame > target {
+      let Some(snapshot) = self.history.pop_back() else {
+        break;
+      };
+      self.history_size -= snapshot.delta.len();
+      apply_delta(&mut self.latest, &snapshot.delta);
+      self.latest_frame = snapshot.frame;
+    }
+
+    if cpu.load_state(&mut StateReader::new(&self.latest)).is_err() {
+      self.clear();
+      return false;
+    }
+
+    cpu.bus.set_callback_enabled(false);
+    for frame in self.latest_frame..target {
+      cpu.bus.gamepad().set_button_status(self.input_at(frame));
+      run_frame(cpu);
+    }
+    cpu.bus.set_callback_enabled(true);
+
+    cpu.bus.gamepad().set_button_status(self.input_at(target));
+    self.inputs.truncate(target + 1 - self.inputs_start);
+    true
+
+  }
+
+  pub fn clear(&mut self) {
+    self.latest.clear();
+    self.history.clear();
+    self.history_size = 0;
+    self.inputs.clear();
+  }
+
+  fn record_input(&mut self, frame: usize, buttons: JoypadButton) {
+
+    if self.inputs.is_empty() {
+      self.inputs_start = frame;
+    }
+
+    if frame < self.inputs_start {
+      return;
+    }
+
+    let index = frame - self.inputs_start;
+    self.inputs.truncate(index);
+    while self.inputs.len() <= index {
+      self.inputs.push_back(buttons);
+    }
+
+  }
+
+  fn input_at(&self, frame: usize) -> JoypadButton {
+    frame
+      .checked_sub(self.inputs_start)
+      .and_then(|index| self.inputs.get(index).copied())
+      .unwrap_or_default()
+  }
+
+  fn push(&mut self, frame: usize, state: Vec<u8>) {
+
+    if !self.latest.is_empty() {
+      if self.latest.len() == state.len() {
+        let delta = encode_delta(&self.latest, &state);
+        self.history_size += delta.len();
+        self.history.push_back(Snapshot { frame: self.latest_frame, delta });
+      } else {
+        self.history.clear();
+        self.history_size = 0;
+      }
+    }
+
+    self.latest = state;
+    self.latest_frame = frame;
+
+    while self.memory_used() > self.budget {
+      let Some(snapshot) = self.history.pop_front() else {
+        break;
+      };
+      self.history_size -= snapshot.delta.len();
+    }
+
+    // Inputs from before the oldest snapshot can never be replayed
+    let oldest = self.oldest_frame().unwrap_or(frame);
+    while self.inputs_start < oldest && !self.inputs.is_empty() {
+      self.inputs.pop_front();
+      self.inputs_start += 1;
+    }
+
+  }
+
+}
+
+/// Runs the CPU until the PPU completes the frame in progress. Expects to be called
+/// where `run_with_callback` would call its callback, and leaves the CPU at that point again.
+fn run_frame(cpu: &mut CPU) {
+  let frame = cpu.bus.frame_count();
+  while cpu.bus.frame_count() == frame {
+    if !cpu.step() {
+      break;
+    }
+    cpu.service_interrupts();
+  }
+}
+
+/// XORs two equally sized states and encodes the result as alternating runs:
+/// a varint count of unchanged bytes, then a varint count of changed bytes followed by those bytes.
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+
+  let mut out = vec![];
+  let mut i = 0;
+
+  while i < base.len() {
+
+    let start = i;
+    while i < base.len() && base[i] == target[i] {
+      i += 1;
+    }
+    write_varint(&mut out, i - start);
+
+    let start = i;
+    while i < base.len() && base[i] != target[i] {
+      i += 1;
+    }
+    write_varint(&mut out, i - start);
+    out.extend(base[start..i].iter().zip(&target[start..i]).map(|(a, b)| a ^ b));
+
+  }
+
+  out
+}
+
+/// Applies a delta from `encode_delta` in place. Since the delta is an XOR,
+/// this turns either of the two original states into the other one.
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+
+  let mut pos = 0;
+  let mut i = 0;
+
+  while pos < delta.len() {
+    i += read_varint(delta, &mut pos);
+    let changed = read_varint(delta, &mut pos);
+    for byte in &delta[pos..pos + changed] {
+      state[i] ^= byte;
+      i += 1;
+    }
+    pos += changed;
+  }
+
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+  while value >= 0x80 {
+    out.push((value as u8) | 0x80);
+    value >>= 7;
+  }
+  out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+  let mut value = 0;
+  let mut shift = 0;
+  loop {
+    let byte = data[*pos];
+    *pos += 1;
+    value |= ((byte & 0x7F) as usize) << shift;
+    if byte & 0x80 == 0 {
+      return value;
+    }
+    shift += 7;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+  use crate::bus::Bus;
+  use crate::mem::Mem;
+  use crate::rom::tests::test_rom;
+
+  // INC $10, JMP $0600
+  fn counting_cpu<'a>() -> CPU<'a> {
+    let mut cpu = CPU::new(Bus::new(test_rom(), |_, _| {}));
+    cpu.load(vec![0xE6, 0x10, 0x4C, 0x00, 0x06]);
+    cpu
+  }
+
+  #[test]
+  fn test_delta_round_trip() {
+
+    let old = vec![0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+    let mut new = old.clone();
+    new[0] = 0xFF;
+    new[4] = 0x44;
+    new[5] = 0x55;
+
+    let delta = encode_delta(&old, &new);
+
+    let mut state = new.clone();
+    apply_delta(&mut state, &delta);
+    assert_eq!(state, old);
+
+    apply_delta(&mut state, &delta);
+    assert_eq!(state, new);
+
+  }
+
+  #[test]
+  fn test_delta_is_compressed() {
+    let old = vec![0xAA; 4096];
+    let mut new = old.clone();
+    new[2048] = 0;
+    assert!(encode_delta(&old, &new).len() < 16);
+  }
+
+  #[test]
+  fn test_varint() {
+    let mut out = vec![];
+    write_varint(&mut out, 300);
+    let mut pos = 0;
+    assert_eq!(read_varint(&out, &mut pos), 300);
+    assert_eq!(pos, 2);
+  }
+
+  #[test]
+  fn test_rewind_to_exact_frame() {
+
+    let mut cpu = counting_cpu();
+    let mut rewind = RewindBuffer::new(10, usize::MAX);
+    let mut counters = vec![];
+
+    for _ in 0..30 {
+      run_frame(&mut cpu);
+      rewind.record(&cpu);
+      counters.push((cpu.bus.frame_count(), cpu.mem_read_u8(0x10)));
+    }
+
+    assert_eq!(cpu.bus.frame_count(), 30);
+    assert_eq!(rewind.oldest_frame(), Some(10));
+
+    assert!(rewind.rewind(&mut cpu, 7));
+    assert_eq!(cpu.bus.frame_count(), 23);
+    assert_eq!(cpu.mem_read_u8(0x10), counters[22].1);
+
+    assert!(rewind.rewind(&mut cpu, 100));
+    assert_eq!(cpu.bus.frame_count(), 10);
+    assert_eq!(cpu.mem_read_u8(0x10), counters[9].1);
+
+  }
+
+  #[test]
+  fn test_replays_recorded_input() {
+
+    let mut cpu = counting_cpu();
+    let mut rewind = RewindBuffer::new(4, usize::MAX);
+
+    for frame in 0..8 {
+      let buttons = if frame == 5 { JoypadButton::START } else { JoypadButton::empty() };
+      cpu.bus.gamepad().set_button_status(buttons);
+      run_frame(&mut cpu);
+      rewind.record(&cpu);
+    }
+
+    assert!(rewind.rewind(&mut cpu, 2));
+    assert_eq!(cpu.bus.frame_count(), 6);
+    assert_eq!(cpu.bus.gamepad_state(), JoypadButton::START);
+
+  }
+
+  #[test]
+  fn test_budget_drops_oldest_snapshots() {
+
+    let mut cpu = counting_cpu();
+    let mut rewind = RewindBuffer::new(1, 0);
+
+    for _ in 0..5 {
+      run_frame(&mut cpu);
+      rewind.record(&cpu);
+    }
+
+    // Only the newest full snapshot survives a zero budget
+    assert_eq!(rewind.oldest_frame(), Some(5));
+    assert!(rewind.rewind(&mut cpu, 3));
+    assert_eq!(cpu.bus.frame_count(), 5);
+
+  }
+
+}
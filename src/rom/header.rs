@@ -10,6 +10,8 @@ pub struct iNESHeader {
   pub prg_rom_banks: u16,
   pub chr_rom_banks: u16,
   pub mapper_id: u16,
+  /// Which variant of the mapper's board, from NES 2.0 headers. 0 when unknown.
+  pub submapper: u8,
   pub has_trainer: bool,
   pub has_battery_backed_ram: bool,
 }
@@ -34,6 +36,7 @@ impl iNESHeader {
     let ines_version = iNESHeader::get_ines_version(header);
     let mirroring = iNESHeader::get_screen_mirroring(header);
     let mut mapper_id = (header[7] & 0b1111_0000 | header[6] >> 4) as u16;
+    let mut submapper = 0;
     let has_trainer = header[6] & 0b100 != 0;
     let mut prg_rom_banks = header[4] as u16;
     let mut chr_rom_banks = header[5] as u16;
@@ -41,6 +44,7 @@ impl iNESHeader {
     // Have to do some things differently with the iNES_2 header
     if ines_version == iNESVersion::iNES_2 {
       mapper_id |= ((header[8] & 0x0F) as u16) << 8;
+      submapper = header[8] >> 4;
       prg_rom_banks |= ((header[9] & 0x0F) as u16) << 8;
       chr_rom_banks |= ((header[9] & 0xF0) as u16) << 8;
     }
@@ -53,6 +57,7 @@ impl iNESHeader {
         prg_rom_banks,
         chr_rom_banks,
         mapper_id,
+        submapper,
         has_trainer,
         has_battery_backed_ram
       }
@@ -164,4 +169,19 @@ mod tests {
 
   }
 
-}
\ No newline at end of file
+  #[test]
+  fn test_nes2_submapper() {
+
+    // Mapper 23, submapper 2 (VRC4e)
+    let mut header = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x70, 0x18, 0x20, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    let parsed = iNESHeader::from_bytes(&header).unwrap();
+    assert_eq!((parsed.mapper_id, parsed.submapper), (23, 2));
+
+    // iNES 1 headers don't have one
+    header[7] = 0x10;
+    let parsed = iNESHeader::from_bytes(&header).unwrap();
+    assert_eq!((parsed.mapper_id, parsed.submapper), (23, 0));
+
+  }
+
+}
@@ -4,9 +4,8 @@ use std::path::Path;
 
 pub mod header;
 
-use crate::mappers::nrom::NROM;
-use crate::mappers::txrom::TXROM;
 use crate::mappers::Mapper;
+use crate::mappers::registry::find_board;
 use crate::rom::header::HEADER_SIZE;
 
 use self::header::iNESHeader;
@@ -14,6 +13,7 @@ use self::header::iNESHeader;
 const TRAINER_SIZE: usize = 512;
 const PRG_ROM_PAGE_SIZE: usize = 16384;
 const CHR_ROM_PAGE_SIZE: usize = 8192;
+const FOUR_SCREEN_RAM_SIZE: usize = 4096;
 const _PRG_RAM_PAGE_SIZE: usize = 8192;
 const _CHR_RAM_PAGE_SIZE: usize = 4096;
 
@@ -37,6 +37,10 @@ pub enum ScreenMirroring {
     Vertical,
     FourScreen,
     Default,
+    /// Every nametable shows the first 1 KB of the console's VRAM
+    SingleScreenLower,
+    /// Every nametable shows the second 1 KB of the console's VRAM
+    SingleScreenUpper,
 }
 
 pub enum Region {
@@ -77,7 +81,7 @@ impl ROM {
         let chr_rom_size = header.chr_rom_banks as usize * CHR_ROM_PAGE_SIZE;
 
         if header.chr_rom_banks == 0 {
-            warn!("ROM has no CHR_ROM, uses CHR_RAM instead, which is unsupported");
+            debug!("ROM has no CHR_ROM, uses CHR_RAM instead");
         }
 
         debug!("iNES Version: {:?}", header.ines_version);
@@ -91,6 +95,9 @@ impl ROM {
 
         debug!("Mapper 0x{:0X}", header.mapper_id);
 
+        let board = find_board(header.mapper_id, header.submapper)?;
+        debug!("Board: {}", board.name);
+
         if header.has_trainer {
             warn!("ROM contains a 512 trainer, this will not be used and has no planned support.");
         }
@@ -98,6 +105,10 @@ impl ROM {
         debug!("PRG ROM is 0x{:0X} bytes", prg_rom_size);
         debug!("CHR ROM is 0x{:0X} bytes", chr_rom_size);
 
+        if prg_rom_size > board.max_prg_rom || chr_rom_size > board.max_chr_rom {
+            warn!("ROM is bigger than {} boards can address, the rest will be ignored", board.name);
+        }
+
         let prg_rom_offset = HEADER_SIZE + if header.has_trainer { TRAINER_SIZE } else { 0 };
         let chr_rom_offset = prg_rom_offset + prg_rom_size;
 
@@ -108,24 +119,31 @@ impl ROM {
             header,
             mapper: Mapper::none(),
             prg_rom: byte_code[prg_rom_offset..(prg_rom_offset + prg_rom_size)].to_vec(),
-            prg_ram: vec![],
+            prg_ram: vec![0; board.prg_ram],
             chr_rom: byte_code[chr_rom_offset..(chr_rom_offset + chr_rom_size)].to_vec(),
             chr_ram: vec![],
             ex_ram: vec![],
         };
 
-        let mapper = match rom.header.mapper_id {
-            0 => NROM::load(&mut rom),
-            4 => TXROM::load(&mut rom),
-            _ => return Err(format!("Mapper {} not supported", rom.header.mapper_id)),
-        };
+        if !rom.has_chr_rom() {
+            rom.chr_ram = vec![0; board.chr_ram];
+        }
 
-        rom.mapper = mapper;
+        if let Some(mirroring) = board.mirroring {
+            rom.header.mirroring = mirroring;
+        }
+
+        // Four-screen carts bring the extra nametable RAM themselves
+        if rom.header.mirroring == ScreenMirroring::FourScreen {
+            rom.ex_ram = vec![0; FOUR_SCREEN_RAM_SIZE];
+        }
+
+        rom.mapper = (board.new)(&rom);
         Ok(rom)
     }
 
     pub fn has_chr_rom(&self) -> bool {
-        self.chr_rom.is_empty()
+        !self.chr_rom.is_empty()
     }
 }
 
@@ -171,4 +189,29 @@ pub mod tests {
 
         ROM::from_bytes("".to_string(), &test_rom).unwrap()
     }
+
+    /// Like `test_rom`, but without CHR-ROM so the mapper provides CHR-RAM
+    pub fn test_chr_ram_rom() -> ROM {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x00, 0x01, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            prg_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+
+        ROM::from_bytes("".to_string(), &test_rom).unwrap()
+    }
+
+    #[test]
+    fn test_has_chr_rom() {
+        let rom = test_rom();
+        assert!(rom.has_chr_rom());
+        assert!(rom.chr_ram.is_empty());
+
+        let rom = test_chr_ram_rom();
+        assert!(!rom.has_chr_rom());
+        assert_eq!(rom.chr_ram.len(), 0x2000);
+    }
 }
@@ -0,0 +1,181 @@
+use crate::rom::ScreenMirroring;
+
+/// Flat little-endian byte stream that each component appends its state to.
+/// The layout has no tags or versioning: a state can only be loaded back into
+/// a machine built from the same ROM, in the same order it was written.
+#[derive(Default)]
+pub struct StateWriter {
+  data: Vec<u8>,
+}
+
+impl StateWriter {
+
+  pub fn new() -> Self {
+    StateWriter { data: vec![] }
+  }
+
+  pub fn write_u8(&mut self, value: u8) {
+    self.data.push(value);
+  }
+
+  pub fn write_bool(&mut self, value: bool) {
+    self.data.push(value as u8);
+  }
+
+  pub fn write_u16(&mut self, value: u16) {
+    self.data.extend_from_slice(&value.to_le_bytes());
+  }
+
+  pub fn write_usize(&mut self, value: usize) {
+    self.data.extend_from_slice(&(value as u64).to_le_bytes());
+  }
+
+  /// Writes a fixed-size block. The reader must know the length up front.
+  pub fn write_bytes(&mut self, bytes: &[u8]) {
+    self.data.extend_from_slice(bytes);
+  }
+
+  /// Writes a length-prefixed block, for buffers whose size depends on the ROM.
+  pub fn write_vec(&mut self, bytes: &[u8]) {
+    self.write_usize(bytes.len());
+    self.write_bytes(bytes);
+  }
+
+  pub fn write_mirroring(&mut self, mirroring: ScreenMirroring) {
+    self.write_u8(mirroring as u8);
+  }
+
+  pub fn into_bytes(self) -> Vec<u8> {
+    self.data
+  }
+
+}
+
+pub struct StateReader<'a> {
+  data: &'a [u8],
+  pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+
+  pub fn new(data: &'a [u8]) -> Self {
+    StateReader { data, pos: 0 }
+  }
+
+  fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+    match self.data.get(self.pos..self.pos + len) {
+      Some(bytes) => {
+        self.pos += len;
+        Ok(bytes)
+      },
+      None => Err(format!("Save state is truncated, expected {} more bytes at offset {}", len, self.pos)),
+    }
+  }
+
+  pub fn read_u8(&mut self) -> Result<u8, String> {
+    Ok(self.take(1)?[0])
+  }
+
+  pub fn read_bool(&mut self) -> Result<bool, String> {
+    Ok(self.read_u8()? != 0)
+  }
+
+  pub fn read_u16(&mut self) -> Result<u16, String> {
+    let bytes = self.take(2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+  }
+
+  pub fn read_usize(&mut self) -> Result<usize, String> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(self.take(8)?);
+    Ok(u64::from_le_bytes(bytes) as usize)
+  }
+
+  /// Fills `out` completely, the counterpart of `StateWriter::write_bytes`
+  pub fn read_bytes(&mut self, out: &mut [u8]) -> Result<(), String> {
+    out.copy_from_slice(self.take(out.len())?);
+    Ok(())
+  }
+
+  /// Reads a length-prefixed block into `out`, which must already be the saved size
+  pub fn read_vec(&mut self, out: &mut [u8]) -> Result<(), String> {
+    let len = self.read_usize()?;
+    if len != out.len() {
+      return Err(format!("Save state buffer is 0x{:0X} bytes, expected 0x{:0X}", len, out.len()));
+    }
+    self.read_bytes(out)
+  }
+
+  pub fn read_mirroring(&mut self) -> Result<ScreenMirroring, String> {
+    match self.read_u8()? {
+      0 => Ok(ScreenMirroring::Horizontal),
+      1 => Ok(ScreenMirroring::Vertical),
+      2 => Ok(ScreenMirroring::FourScreen),
+      3 => Ok(ScreenMirroring::Default),
+      4 => Ok(ScreenMirroring::SingleScreenLower),
+      5 => Ok(ScreenMirroring::SingleScreenUpper),
+      value => Err(format!("Invalid screen mirroring {} in save state", value)),
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.pos >= self.data.len()
+  }
+
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  #[test]
+  fn test_round_trip() {
+
+    let mut writer = StateWriter::new();
+    writer.write_u8(0xAB);
+    writer.write_bool(true);
+    writer.write_u16(0x1234);
+    writer.write_usize(0xDEAD_BEEF);
+    writer.write_bytes(&[1, 2, 3]);
+    writer.write_vec(&[4, 5]);
+    writer.write_mirroring(ScreenMirroring::Vertical);
+
+    let bytes = writer.into_bytes();
+    let mut reader = StateReader::new(&bytes);
+
+    assert_eq!(reader.read_u8().unwrap(), 0xAB);
+    assert!(reader.read_bool().unwrap());
+    assert_eq!(reader.read_u16().unwrap(), 0x1234);
+    assert_eq!(reader.read_usize().unwrap(), 0xDEAD_BEEF);
+
+    let mut fixed = [0; 3];
+    reader.read_bytes(&mut fixed).unwrap();
+    assert_eq!(fixed, [1, 2, 3]);
+
+    let mut sized = vec![0; 2];
+    reader.read_vec(&mut sized).unwrap();
+    assert_eq!(sized, vec![4, 5]);
+
+    assert_eq!(reader.read_mirroring().unwrap(), ScreenMirroring::Vertical);
+    assert!(reader.is_empty());
+
+  }
+
+  #[test]
+  fn test_truncated_state() {
+
+    let bytes = [0x01];
+    let mut reader = StateReader::new(&bytes);
+
+    assert!(reader.read_u16().is_err());
+
+    let mut wrong_size = vec![0; 4];
+    let mut writer = StateWriter::new();
+    writer.write_vec(&[1, 2]);
+    let bytes = writer.into_bytes();
+    assert!(StateReader::new(&bytes).read_vec(&mut wrong_size).is_err());
+
+  }
+
+}
@@ -0,0 +1,229 @@
+use std::fmt;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+
+use log::info;
+
+use crate::bus::Bus;
+use crate::cpu::{ResetKind, CPU};
+use crate::mem::Mem;
+use crate::power_on::PowerOnState;
+use crate::rom::ROM;
+
+/// blargg's test ROMs report through PRG-RAM: a status byte at 0x6000,
+/// a signature at 0x6001-0x6003 once the status is valid, and a
+/// null-terminated log of the test output from 0x6004.
+/// <https://github.com/christopherpow/nes-test-roms/blob/master/README.md>
+const STATUS_ADDRESS: u16 =       0x6000;
+const SIGNATURE_ADDRESS: u16 =    0x6001;
+const TEXT_ADDRESS: u16 =         0x6004;
+const TEXT_END: u16 =             0x7FFF;
+
+const SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+
+const STATUS_RUNNING: u8 =        0x80;
+const STATUS_NEEDS_RESET: u8 =    0x81;
+const STATUS_PASSED: u8 =         0x00;
+
+/// Tests that need a reset want it held off for at least 100ms
+const RESET_DELAY_FRAMES: usize = 6;
+
+#[derive(Debug, PartialEq)]
+pub enum TestOutcome {
+  Passed(String),
+  Failed(u8, String),
+  TimedOut(String),
+  Crashed(String),
+}
+
+impl TestOutcome {
+
+  pub fn passed(&self) -> bool {
+    matches!(self, TestOutcome::Passed(_))
+  }
+
+}
+
+impl fmt::Display for TestOutcome {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TestOutcome::Passed(text) => write!(f, "PASSED\n{}", text.trim_end()),
+      TestOutcome::Failed(code, text) => write!(f, "FAILED with code {}\n{}", code, text.trim_end()),
+      TestOutcome::TimedOut(text) => write!(f, "TIMED OUT\n{}", text.trim_end()),
+      TestOutcome::Crashed(msg) => write!(f, "CRASHED: {}", msg),
+    }
+  }
+}
+
+/// Loads and runs a single test ROM, see `run`
+pub fn run_path<P: AsRef<Path>>(path: P, max_frames: usize) -> Result<TestOutcome, String> {
+  let rom = ROM::from_path(path)?;
+  Ok(run(rom, max_frames))
+}
+
+/// Runs a test ROM without any video output until it reports a result
+/// through PRG-RAM or `max_frames` frames have gone by.
+/// Emulator panics (e.g. an unimplemented opcode) are reported as a crash instead of unwinding.
+pub fn run(rom: ROM, max_frames: usize) -> TestOutcome {
+
+  info!("Running test ROM {} for at most {} frames", rom.name, max_frames);
+  let mut bus = Bus::new(rom, |_, _| {});
+  bus.power_on(PowerOnState::Zero);
+  let mut cpu = CPU::new(bus);
+
+  let result = panic::catch_unwind(AssertUnwindSafe(|| {
+
+    let mut last_frame = 0;
+    let mut reset_at = None;
+
+    loop {
+
+      cpu.service_interrupts();
+      if !cpu.step() {
+        return TestOutcome::Crashed(format!("Hit BRK at 0x{:04X}", cpu.pc.wrapping_sub(1)));
+      }
+
+      let frame = cpu.bus.frame_count();
+      if frame == last_frame {
+        continue;
+      }
+      last_frame = frame;
+
+      if has_signature(&cpu) {
+        match cpu.mem_peek_u8(STATUS_ADDRESS) {
+          STATUS_RUNNING => {},
+          STATUS_NEEDS_RESET => match reset_at {
+            None => reset_at = Some(frame + RESET_DELAY_FRAMES),
+            Some(at) if frame >= at => {
+              cpu.bus.ppu.request_reset(ResetKind::Soft);
+              reset_at = None;
+            },
+            Some(_) => {},
+          },
+          STATUS_PASSED => return TestOutcome::Passed(read_text(&cpu)),
+          code => return TestOutcome::Failed(code, read_text(&cpu)),
+        }
+      }
+
+      if frame >= max_frames {
+        return TestOutcome::TimedOut(read_text(&cpu));
+      }
+    }
+
+  }));
+
+  result.unwrap_or_else(|payload| {
+    let msg = payload
+      .downcast_ref::<&str>()
+      .map(|msg| msg.to_string())
+      .or_else(|| payload.downcast_ref::<String>().cloned())
+      .unwrap_or_else(|| "Emulator panicked".to_string());
+    TestOutcome::Crashed(msg)
+  })
+
+}
+
+/// All `.nes` files under `dir`, recursively, in a stable order
+pub fn find_test_roms<P: AsRef<Path>>(dir: P) -> Vec<PathBuf> {
+
+  let mut roms = vec![];
+  let Ok(entries) = fs::read_dir(dir) else {
+    return roms;
+  };
+
+  for path in entries.flatten().map(|entry| entry.path()) {
+    if path.is_dir() {
+      roms.extend(find_test_roms(&path));
+    } else if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("nes")) {
+      roms.push(path);
+    }
+  }
+
+  roms.sort();
+  roms
+}
+
+fn has_signature(cpu: &CPU) -> bool {
+  (0..3).all(|i| cpu.mem_peek_u8(SIGNATURE_ADDRESS + i) == SIGNATURE[i as usize])
+}
+
+fn read_text(cpu: &CPU) -> String {
+  let mut text = vec![];
+  for addr in TEXT_ADDRESS..=TEXT_END {
+    match cpu.mem_peek_u8(addr) {
+      0 => break,
+      byte => text.push(byte),
+    }
+  }
+  String::from_utf8_lossy(&text).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+
+  use super::*;
+
+  fn test_rom_with_program(program: &[u8]) -> ROM {
+
+    let mut prg_rom = vec![0xEA; 0x4000];
+    prg_rom[..program.len()].copy_from_slice(program);
+
+    // Reset vector -> 0x8000
+    prg_rom[0x3FFC] = 0x00;
+    prg_rom[0x3FFD] = 0x80;
+
+    let mut bytes = vec![0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00];
+    bytes.extend(prg_rom);
+    bytes.extend(vec![0; 0x2000]);
+
+    ROM::from_bytes("test", &bytes).unwrap()
+  }
+
+  /// Writes the signature, `text` and then `status`, and spins forever
+  fn reporting_program(status: u8, text: &str) -> Vec<u8> {
+
+    let mut program = vec![];
+    let mut store = |addr: u16, value: u8| {
+      program.extend([0xA9, value, 0x8D, addr as u8, (addr >> 8) as u8]);
+    };
+
+    store(STATUS_ADDRESS, STATUS_RUNNING);
+    for (i, byte) in SIGNATURE.iter().enumerate() {
+      store(SIGNATURE_ADDRESS + i as u16, *byte);
+    }
+    for (i, byte) in text.bytes().chain([0]).enumerate() {
+      store(TEXT_ADDRESS + i as u16, byte);
+    }
+    store(STATUS_ADDRESS, status);
+
+    let spin = 0x8000 + program.len() as u16;
+    program.extend([0x4C, spin as u8, (spin >> 8) as u8]);
+    program
+  }
+
+  #[test]
+  fn test_passing_rom() {
+    let rom = test_rom_with_program(&reporting_program(0, "All tests passed"));
+    assert_eq!(run(rom, 10), TestOutcome::Passed("All tests passed".to_string()));
+  }
+
+  #[test]
+  fn test_failing_rom() {
+    let rom = test_rom_with_program(&reporting_program(3, "Failed #3"));
+    assert_eq!(run(rom, 10), TestOutcome::Failed(3, "Failed #3".to_string()));
+  }
+
+  #[test]
+  fn test_timeout() {
+    let rom = test_rom_with_program(&reporting_program(STATUS_RUNNING, "Running"));
+    assert_eq!(run(rom, 10), TestOutcome::TimedOut("Running".to_string()));
+  }
+
+  #[test]
+  fn test_brk_crashes() {
+    let rom = test_rom_with_program(&[0x00]);
+    assert!(matches!(run(rom, 10), TestOutcome::Crashed(_)));
+  }
+
+}
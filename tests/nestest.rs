@@ -0,0 +1,114 @@
+//! Compares the CPU trace of `nestest.nes` against a known good log, line by line.
+//!
+//! Neither file is distributed with ferricom. The ROM is read from `FERRICOM_NESTEST_ROM`
+//! (default `roms/nestest.nes`) and the log from `FERRICOM_NESTEST_LOG` (default `logs/nestest.log`).
+//! The test is skipped if either one is missing.
+
+use std::env;
+use std::fs;
+
+use ferricom::bus::Bus;
+use ferricom::cpu::cpu_status_flags::CPUFlags;
+use ferricom::cpu::cpu_trace::trace;
+use ferricom::cpu::CPU;
+use ferricom::rom::ROM;
+
+/// How many matching lines to print before the first one that diverges
+const CONTEXT_LINES: usize = 5;
+
+/// The reset sequence takes 7 cycles before the first instruction is fetched
+const RESET_CYCLES: u8 = 7;
+
+fn path_from_env(var: &str, default: &str) -> String {
+  env::var(var).unwrap_or_else(|_| default.to_string())
+}
+
+/// Splits the `A:00 X:00 ...` columns off a trace line, so a mismatch can say which ones differ
+fn columns(line: &str) -> Vec<(String, String)> {
+  let registers = line.find("A:").map_or("", |start| &line[start..]);
+  let mut columns: Vec<(String, String)> = vec![];
+
+  for token in registers.split_whitespace() {
+    match (token.split_once(':'), columns.last_mut()) {
+      (Some((name, value)), _) => columns.push((name.to_string(), value.to_string())),
+      (None, Some((_, value))) => {
+        value.push(' ');
+        value.push_str(token);
+      },
+      (None, None) => {},
+    }
+  }
+
+  columns
+}
+
+fn describe_mismatch(expected: &str, actual: &str) -> String {
+  let expected_columns = columns(expected);
+  let actual_columns = columns(actual);
+
+  let differing: Vec<String> = expected_columns
+    .iter()
+    .zip(actual_columns.iter())
+    .filter(|(e, a)| e != a)
+    .map(|((name, e), (_, a))| format!("{} expected {} got {}", name, e.trim(), a.trim()))
+    .collect();
+
+  if differing.is_empty() {
+    "Disassembly differs".to_string()
+  } else {
+    differing.join(", ")
+  }
+}
+
+#[test]
+fn nestest_matches_golden_log() {
+
+  let rom_path = path_from_env("FERRICOM_NESTEST_ROM", "roms/nestest.nes");
+  let log_path = path_from_env("FERRICOM_NESTEST_LOG", "logs/nestest.log");
+
+  let (Ok(rom), Ok(golden)) = (ROM::from_path(&rom_path), fs::read_to_string(&log_path)) else {
+    eprintln!("Skipping nestest, {} or {} not found", rom_path, log_path);
+    return;
+  };
+
+  let mut bus = Bus::new(rom, |_, _| {});
+  bus.tick_cycles(RESET_CYCLES);
+
+  let mut cpu = CPU::new(bus);
+  cpu.pc = 0xC000;
+  cpu.status = CPUFlags::from_bits_truncate(0x24);
+
+  let golden: Vec<&str> = golden.lines().map(str::trim_end).collect();
+
+  for (i, expected) in golden.iter().enumerate() {
+
+    cpu.service_interrupts();
+    let actual = trace(&cpu);
+
+    if actual != *expected {
+      let context = golden[i.saturating_sub(CONTEXT_LINES)..i].join("\n");
+      panic!(
+        "Trace diverges from {} at line {}: {}\n\n{}\n\nexpected: {}\nactual:   {}",
+        log_path,
+        i + 1,
+        describe_mismatch(expected, &actual),
+        context,
+        expected,
+        actual
+      );
+    }
+
+    if !cpu.step() {
+      assert_eq!(i + 1, golden.len(), "CPU hit BRK at line {} of {}", i + 1, golden.len());
+      break;
+    }
+  }
+
+}
+
+#[test]
+fn mismatch_names_differing_columns() {
+  let expected = "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7";
+  let actual = "C000  4C F5 C5  JMP $C5F5                       A:01 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:8";
+  assert_eq!(describe_mismatch(expected, actual), "A expected 00 got 01, CYC expected 7 got 8");
+}
@@ -0,0 +1,62 @@
+//! Runs suites of test ROMs that report their results through 0x6000 (blargg's protocol).
+//!
+//! The ROMs aren't distributed with ferricom. Point `FERRICOM_TEST_ROMS` at a directory
+//! with `cpu`, `ppu` and `mappers` subdirectories, or place them under `roms/tests`.
+//! Suites without a directory are skipped.
+
+use std::env;
+use std::path::PathBuf;
+
+use ferricom::test_rom::{find_test_roms, run_path};
+
+const MAX_FRAMES: usize = 3600;
+
+fn suite_dir(suite: &str) -> PathBuf {
+  let root = env::var("FERRICOM_TEST_ROMS").unwrap_or_else(|_| "roms/tests".to_string());
+  PathBuf::from(root).join(suite)
+}
+
+fn run_suite(suite: &str) {
+
+  let dir = suite_dir(suite);
+  let roms = find_test_roms(&dir);
+
+  if roms.is_empty() {
+    eprintln!("Skipping {} test ROMs, none found in {}", suite, dir.display());
+    return;
+  }
+
+  let failures: Vec<String> = roms
+    .iter()
+    .filter_map(|rom| match run_path(rom, MAX_FRAMES) {
+      Ok(outcome) if outcome.passed() => None,
+      Ok(outcome) => Some(format!("{}: {}", rom.display(), outcome)),
+      Err(msg) => Some(format!("{}: {}", rom.display(), msg)),
+    })
+    .collect();
+
+  assert!(
+    failures.is_empty(),
+    "{} of {} {} test ROMs failed\n\n{}",
+    failures.len(),
+    roms.len(),
+    suite,
+    failures.join("\n\n")
+  );
+
+}
+
+#[test]
+fn cpu_test_roms() {
+  run_suite("cpu");
+}
+
+#[test]
+fn ppu_test_roms() {
+  run_suite("ppu");
+}
+
+#[test]
+fn mapper_test_roms() {
+  run_suite("mappers");
+}